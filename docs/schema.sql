@@ -11,11 +11,30 @@ create table report (
     unique (timestamp, latitude, longitude),
     
     user_agent text,
+    token_id integer,
+    -- which upload the report came in, unset for ingested reports
+    upload_id text,
     raw bytea not null
 );
 
 create index report_todo on report (id) where processed_at is null;
 create index report_error on report (id) where processing_error is not null;
+create index report_token on report (token_id) where token_id is not null;
+
+-- reports flagged as test data, never processed or counted in stats
+create table test_report (
+    id serial not null primary key,
+    submitted_at timestamp with time zone not null default now(),
+
+    timestamp timestamp with time zone not null,
+    latitude double precision not null,
+    longitude double precision not null,
+    unique (timestamp, latitude, longitude),
+
+    user_agent text,
+    token_id integer,
+    raw bytea not null
+);
 
 create table cell (
     radio smallint not null,
@@ -29,7 +48,12 @@ create table cell (
     min_lat double precision not null,
     min_lon double precision not null,
     max_lat double precision not null,
-    max_lon double precision not null
+    max_lon double precision not null,
+
+    first_seen timestamp with time zone,
+    last_seen timestamp with time zone,
+    days_seen integer,
+    accuracy double precision
 );
 
 create table wifi (
@@ -38,7 +62,23 @@ create table wifi (
     min_lat double precision not null,
     min_lon double precision not null,
     max_lat double precision not null,
-    max_lon double precision not null
+    max_lon double precision not null,
+
+    pinned boolean not null default false,
+    capabilities text,
+
+    first_seen timestamp with time zone,
+    last_seen timestamp with time zone,
+    days_seen integer,
+    accuracy double precision
+);
+
+create index wifi_centre on wifi (((min_lat + max_lat) / 2), ((min_lon + max_lon) / 2));
+
+create table wifi_h3 (
+    mac macaddr not null,
+    h3 bytea not null,
+    primary key (mac, h3)
 );
 
 create table bluetooth (
@@ -47,7 +87,29 @@ create table bluetooth (
     min_lat double precision not null,
     min_lon double precision not null,
     max_lat double precision not null,
-    max_lon double precision not null
+    max_lon double precision not null,
+
+    first_seen timestamp with time zone,
+    last_seen timestamp with time zone,
+    days_seen integer,
+    accuracy double precision
+);
+
+-- beacons by their advertised identity: protocol 1 is iBeacon, 2 Eddystone
+create table beacon (
+    protocol smallint not null,
+    id bytea not null,
+    primary key (protocol, id),
+
+    min_lat double precision not null,
+    min_lon double precision not null,
+    max_lat double precision not null,
+    max_lon double precision not null,
+
+    first_seen timestamp with time zone,
+    last_seen timestamp with time zone,
+    days_seen integer,
+    accuracy double precision
 );
 
 create table mls_cell (
@@ -70,14 +132,29 @@ create table geoip (
     range_end inet not null,
     country char(2) not null,
     latitude double precision not null,
-    longitude double precision not null
+    longitude double precision not null,
+    accuracy integer not null default 25000,
+    continent text,
+    state text,
+    city text
 );
 
 create index geoip_range on geoip using gist (cidr inet_ops);
 
+-- imports are loaded here, then swapped with geoip
+create table geoip_staging (like geoip including all);
+
 create table map (
     h3 bytea not null primary key,
     new boolean not null default true
 );
 
 create index on map (h3) where new;
+
+-- each retention run, checked by purge
+create table archive (
+    id serial not null primary key,
+    archived_at timestamp with time zone not null default now(),
+    path text not null,
+    reports integer not null
+);
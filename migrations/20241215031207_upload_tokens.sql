@@ -0,0 +1,4 @@
+-- id of the upload token used to submit a report, if tokens are required
+alter table report add column token_id integer;
+
+create index report_token on report (token_id) where token_id is not null;
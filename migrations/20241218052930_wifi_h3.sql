@@ -0,0 +1,7 @@
+-- h3 cells that an access point has been seen in, used instead of the wifi
+-- table when configured with wifi_storage = "h3"
+create table wifi_h3 (
+    mac macaddr not null,
+    h3 bytea not null,
+    primary key (mac, h3)
+);
@@ -0,0 +1,3 @@
+-- access points with a known position submitted through /v2/correct. these
+-- are left alone by processing so that later reports can't move them
+alter table wifi add column pinned boolean not null default false;
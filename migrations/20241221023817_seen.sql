@@ -0,0 +1,9 @@
+-- when each transmitter was first and last observed, based on report
+-- timestamps. transmitters processed before this was added have no value
+-- until they are seen again
+alter table cell add column first_seen timestamp with time zone;
+alter table cell add column last_seen timestamp with time zone;
+alter table wifi add column first_seen timestamp with time zone;
+alter table wifi add column last_seen timestamp with time zone;
+alter table bluetooth add column first_seen timestamp with time zone;
+alter table bluetooth add column last_seen timestamp with time zone;
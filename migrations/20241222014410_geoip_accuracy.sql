@@ -0,0 +1,4 @@
+-- estimated accuracy in metres based on how specific each record is. rows
+-- imported before this was added keep the previous fixed estimate until the
+-- database is imported again
+alter table geoip add column accuracy integer not null default 25000;
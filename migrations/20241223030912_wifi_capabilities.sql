@@ -0,0 +1,2 @@
+-- security flags reported alongside each access point, such as "[WPA2-PSK-CCMP][ESS]"
+alter table wifi add column capabilities text;
@@ -0,0 +1,3 @@
+-- the remaining fields of each record, so that the table can be exported
+-- again in the format it was imported from
+alter table geoip add column continent text, add column state text, add column city text;
@@ -0,0 +1,2 @@
+-- lets geolocate count the access points centred near a fix
+create index wifi_centre on wifi (((min_lat + max_lat) / 2), ((min_lon + max_lon) / 2));
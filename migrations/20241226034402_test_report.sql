@@ -0,0 +1,15 @@
+-- reports flagged as test data, kept apart so they're never processed or
+-- counted in stats
+create table test_report (
+    id serial not null primary key,
+    submitted_at timestamp with time zone not null default now(),
+
+    timestamp timestamp with time zone not null,
+    latitude double precision not null,
+    longitude double precision not null,
+    unique (timestamp, latitude, longitude),
+
+    user_agent text,
+    token_id integer,
+    raw bytea not null
+);
@@ -0,0 +1,3 @@
+-- imports are loaded here and then swapped with geoip, so that lookups keep
+-- using the previous data until an import has fully succeeded
+create table geoip_staging (like geoip including all);
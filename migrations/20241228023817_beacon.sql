@@ -0,0 +1,16 @@
+-- bluetooth beacons that advertise an iBeacon or Eddystone identity, stored by
+-- that rather than by their mac address, which many beacons rotate
+create table beacon (
+    -- 1 for iBeacon (uuid, major, minor), 2 for Eddystone (namespace, instance)
+    protocol smallint not null,
+    id bytea not null,
+    primary key (protocol, id),
+
+    min_lat double precision not null,
+    min_lon double precision not null,
+    max_lat double precision not null,
+    max_lon double precision not null,
+
+    first_seen timestamp with time zone,
+    last_seen timestamp with time zone
+);
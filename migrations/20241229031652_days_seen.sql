@@ -0,0 +1,16 @@
+-- how many different days each transmitter was seen on, based on report
+-- timestamps. rows seen before this was added start from the fewest days
+-- their first and last seen times allow
+alter table cell add column days_seen integer;
+alter table wifi add column days_seen integer;
+alter table bluetooth add column days_seen integer;
+alter table beacon add column days_seen integer;
+
+update cell set days_seen = case when (first_seen at time zone 'UTC')::date = (last_seen at time zone 'UTC')::date then 1 else 2 end
+    where first_seen is not null and last_seen is not null;
+update wifi set days_seen = case when (first_seen at time zone 'UTC')::date = (last_seen at time zone 'UTC')::date then 1 else 2 end
+    where first_seen is not null and last_seen is not null;
+update bluetooth set days_seen = case when (first_seen at time zone 'UTC')::date = (last_seen at time zone 'UTC')::date then 1 else 2 end
+    where first_seen is not null and last_seen is not null;
+update beacon set days_seen = case when (first_seen at time zone 'UTC')::date = (last_seen at time zone 'UTC')::date then 1 else 2 end
+    where first_seen is not null and last_seen is not null;
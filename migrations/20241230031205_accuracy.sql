@@ -0,0 +1,7 @@
+-- the accuracy in metres of the least precise position each transmitter was
+-- seen from, as a floor on how precisely it can be placed. it used to be
+-- folded into the bounds instead, so older rows have none
+alter table cell add column accuracy double precision;
+alter table wifi add column accuracy double precision;
+alter table bluetooth add column accuracy double precision;
+alter table beacon add column accuracy double precision;
@@ -0,0 +1,8 @@
+-- each retention run, so purge can tell that reports it can no longer see have
+-- contributed to the transmitters it rebuilds
+create table archive (
+    id serial not null primary key,
+    archived_at timestamp with time zone not null default now(),
+    path text not null,
+    reports integer not null
+);
@@ -0,0 +1,4 @@
+-- which upload a report came in, as reports from one upload can be stored at
+-- different times when the submission buffer is used. null for reports from
+-- before this was recorded and ones loaded with the ingest command
+alter table report add column upload_id text;
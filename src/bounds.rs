@@ -1,8 +1,9 @@
 use std::ops::Add;
 
-use geo::Point;
+use geo::{Destination, Distance, Haversine, Point};
+use h3o::CellIndex;
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy)]
 pub struct Bounds {
     pub min_lat: f64,
     pub min_lon: f64,
@@ -20,6 +21,87 @@ impl Bounds {
         }
     }
 
+    /// A square around a point, reaching `radius` metres out in each direction.
+    /// Squares reaching a pole or the antimeridian can't be represented
+    /// exactly, so they're widened to cover every longitude.
+    pub fn around(lat: f64, lon: f64, radius: f64) -> Self {
+        let center = Point::new(lon, lat);
+        let north = Haversine::destination(center, 0.0, radius);
+        let east = Haversine::destination(center, 90.0, radius);
+        let south = Haversine::destination(center, 180.0, radius);
+        let west = Haversine::destination(center, 270.0, radius);
+
+        // past a pole, the destination comes back down the other side of it
+        let past_north = radius >= Haversine::distance(center, Point::new(lon, 90.0));
+        let past_south = radius >= Haversine::distance(center, Point::new(lon, -90.0));
+        let (min_lon, max_lon) = (wrap_lon(west.x()), wrap_lon(east.x()));
+        let (min_lon, max_lon) = if past_north || past_south || min_lon > max_lon {
+            (-180.0, 180.0)
+        } else {
+            (min_lon, max_lon)
+        };
+
+        Self {
+            min_lat: if past_south {
+                -90.0
+            } else {
+                south.y().max(-90.0)
+            },
+            min_lon,
+            max_lat: if past_north {
+                90.0
+            } else {
+                north.y().min(90.0)
+            },
+            max_lon,
+        }
+    }
+
+    /// The smallest bounds enclosing all of the given cells, centred on
+    /// roughly the middle of them.
+    pub fn from_cells(cells: impl IntoIterator<Item = CellIndex>) -> Option<Self> {
+        let mut bounds: Option<Self> = None;
+        for cell in cells {
+            for vertex in cell.boundary().iter() {
+                let (lat, lon) = (vertex.lat(), vertex.lng());
+                bounds = Some(match bounds {
+                    Some(b) => b + (lat, lon),
+                    None => Bounds::new(lat, lon),
+                });
+            }
+        }
+        bounds
+    }
+
+    /// Add a point, unless that would stretch the bounds further than
+    /// `max_span` metres corner to corner.
+    pub fn add_capped(self, other: impl Into<Bounds>, max_span: Option<f64>) -> Option<Self> {
+        let other = other.into();
+        let b = self + (other.min_lat, other.min_lon) + (other.max_lat, other.max_lon);
+        match max_span {
+            // bounds that are already too large can still take points inside them
+            Some(max) if b.span() > max && b.span() > self.span() => None,
+            _ => Some(b),
+        }
+    }
+
+    /// The distance between opposite corners, in metres.
+    pub fn span(&self) -> f64 {
+        let (min, max) = self.points();
+        Haversine::distance(min, max)
+    }
+
+    /// The same area with its corners in the right order, for bounds that were
+    /// stored with the minimum and maximum swapped.
+    pub fn normalized(self) -> Self {
+        Self {
+            min_lat: self.min_lat.min(self.max_lat),
+            min_lon: self.min_lon.min(self.max_lon),
+            max_lat: self.min_lat.max(self.max_lat),
+            max_lon: self.min_lon.max(self.max_lon),
+        }
+    }
+
     pub fn points(&self) -> (Point, Point) {
         let min = Point::new(self.min_lon, self.min_lat);
         let max = Point::new(self.max_lon, self.max_lat);
@@ -27,6 +109,21 @@ impl Bounds {
     }
 }
 
+/// A longitude in the range -180 to 180.
+fn wrap_lon(lon: f64) -> f64 {
+    if (-180.0..=180.0).contains(&lon) {
+        lon
+    } else {
+        (lon + 180.0).rem_euclid(360.0) - 180.0
+    }
+}
+
+impl From<(f64, f64)> for Bounds {
+    fn from((lat, lon): (f64, f64)) -> Self {
+        Self::new(lat, lon)
+    }
+}
+
 impl Add<(f64, f64)> for Bounds {
     type Output = Self;
 
@@ -67,4 +164,138 @@ mod tests {
         assert!(b.min_lat < 0.0);
         assert!(b.min_lon < 0.0);
     }
+
+    #[test]
+    fn around() {
+        let b = Bounds::around(-27.4698, 153.0251, 100.0);
+        assert!(b.min_lat < -27.4698 && b.max_lat > -27.4698);
+        assert!(b.min_lon < 153.0251 && b.max_lon > 153.0251);
+        // the diagonal of a 200m square
+        assert!((b.span() - 200.0 * 2f64.sqrt()).abs() < 1.0);
+    }
+
+    #[test]
+    fn around_poles() {
+        let valid = |b: &Bounds| {
+            (-90.0..=90.0).contains(&b.min_lat)
+                && (-90.0..=90.0).contains(&b.max_lat)
+                && (-180.0..=180.0).contains(&b.min_lon)
+                && (-180.0..=180.0).contains(&b.max_lon)
+                && b.min_lat <= b.max_lat
+                && b.min_lon <= b.max_lon
+        };
+
+        // ~111km from the pole, so a 1km square is still a square, centred
+        // where it started
+        let b = Bounds::around(89.0, 10.0, 1000.0);
+        assert!(valid(&b));
+        assert!(((b.min_lat + b.max_lat) / 2.0 - 89.0).abs() < 1e-6);
+        assert!(((b.min_lon + b.max_lon) / 2.0 - 10.0).abs() < 1e-6);
+        assert!((b.max_lat - b.min_lat - 0.018).abs() < 1e-3);
+
+        // reaching past the pole covers it, and every longitude around it
+        let b = Bounds::around(89.0, 10.0, 200_000.0);
+        assert!(valid(&b));
+        assert_eq!((b.max_lat, b.min_lon, b.max_lon), (90.0, -180.0, 180.0));
+        assert!((b.min_lat - 87.2).abs() < 0.01);
+        let b = Bounds::around(-89.5, -60.0, 100_000.0);
+        assert!(valid(&b));
+        assert_eq!((b.min_lat, b.min_lon, b.max_lon), (-90.0, -180.0, 180.0));
+
+        // across the antimeridian
+        let b = Bounds::around(65.0, 179.99, 10_000.0);
+        assert!(valid(&b));
+        assert_eq!((b.min_lon, b.max_lon), (-180.0, 180.0));
+
+        assert_eq!(wrap_lon(190.0), -170.0);
+        assert_eq!(wrap_lon(-190.0), 170.0);
+        assert_eq!(wrap_lon(180.0), 180.0);
+    }
+
+    #[test]
+    fn capped() {
+        // ~1.1km apart
+        let b = Bounds::new(-27.47, 153.02) + (-27.46, 153.02);
+
+        // no limit
+        let far = (-27.0, 153.0);
+        assert!(b.add_capped(far, None).is_some());
+
+        // too far away, so it's ignored
+        assert!(b.add_capped(far, Some(2000.0)).is_none());
+
+        // close enough to stay under the cap
+        let near = b.add_capped((-27.465, 153.025), Some(2000.0)).unwrap();
+        assert_eq!(near.max_lon, 153.025);
+        assert!(near.span() < 2000.0);
+
+        // already larger than the cap, but the point doesn't grow it
+        let inside = b.add_capped((-27.465, 153.02), Some(500.0)).unwrap();
+        assert_eq!(inside.span(), b.span());
+        assert!(b.add_capped((-27.48, 153.02), Some(500.0)).is_none());
+    }
+
+    #[test]
+    fn normalized() {
+        let swapped = Bounds {
+            min_lat: -27.46,
+            min_lon: 153.03,
+            max_lat: -27.47,
+            max_lon: 153.02,
+        };
+        let b = swapped.normalized();
+        assert_eq!(
+            (b.min_lat, b.min_lon, b.max_lat, b.max_lon),
+            (-27.47, 153.02, -27.46, 153.03)
+        );
+
+        // already in order
+        let b = Bounds::around(-27.46, 153.02, 100.0);
+        let n = b.normalized();
+        assert_eq!(
+            (b.min_lat, b.min_lon, b.max_lat, b.max_lon),
+            (n.min_lat, n.min_lon, n.max_lat, n.max_lon)
+        );
+    }
+
+    #[test]
+    fn points_vs_cells() {
+        use h3o::LatLng;
+
+        use crate::model::WIFI_RESOLUTION;
+
+        let observations = [
+            (-27.4698, 153.0251),
+            (-27.4701, 153.0255),
+            (-27.4695, 153.0249),
+        ];
+
+        let mut points = Bounds::new(observations[0].0, observations[0].1);
+        for x in &observations[1..] {
+            points = points + *x;
+        }
+        let cells = Bounds::from_cells(
+            observations
+                .iter()
+                .map(|(lat, lon)| LatLng::new(*lat, *lon).unwrap().to_cell(WIFI_RESOLUTION)),
+        )
+        .unwrap();
+
+        let center = |b: &Bounds| {
+            let (min, max) = b.points();
+            (min + max) / 2.0
+        };
+        let (min, _) = cells.points();
+        let radius = Haversine::distance(min, center(&cells));
+
+        // both estimates are close to each other, but the cells are larger
+        // than the spread of the observations so no exact position is kept
+        assert!(Haversine::distance(center(&points), center(&cells)) < radius);
+        assert!(cells.min_lat < points.min_lat && cells.max_lat > points.max_lat);
+        assert!(cells.min_lon < points.min_lon && cells.max_lon > points.max_lon);
+        for (lat, lon) in observations {
+            assert!(![cells.min_lat, cells.max_lat].contains(&lat));
+            assert!(![cells.min_lon, cells.max_lon].contains(&lon));
+        }
+    }
 }
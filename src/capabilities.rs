@@ -0,0 +1,111 @@
+use actix_web::{get, web, HttpResponse};
+use serde_json::{json, Value};
+
+use crate::{
+    config::{Config, WifiStorage},
+    geoip::LICENSE,
+    model::CellRadio,
+};
+
+const RADIOS: [CellRadio; 4] = [
+    CellRadio::Gsm,
+    CellRadio::Wcdma,
+    CellRadio::Lte,
+    CellRadio::Nr,
+];
+
+/// What this instance supports, so that clients don't have to guess.
+#[get("/v1")]
+pub async fn service(config: web::Data<Config>) -> HttpResponse {
+    HttpResponse::Ok().json(describe(&config))
+}
+
+fn describe(config: &Config) -> Value {
+    let geolocate = &config.geolocate;
+    let mls_radios: Vec<_> = RADIOS
+        .into_iter()
+        .filter(|x| geolocate.mls_radios.as_ref().is_none_or(|r| r.contains(x)))
+        .collect();
+
+    let prefix = config.prefix();
+    let endpoints: Vec<_> = [
+        "/v1/country",
+        "/v1/geolocate",
+        "/v2/geosubmit",
+        "/v2/geosubmit/validate",
+        "/v2/correct",
+    ]
+    .into_iter()
+    .map(|x| format!("{prefix}{x}"))
+    .collect();
+
+    json!({
+        "endpoints": endpoints,
+        "radioTypes": RADIOS,
+        "geolocate": {
+            "modes": ["thorough", "fast"],
+            "params": [
+                "mode",
+                "confidence",
+                "points",
+                "max_accuracy",
+                "max_age",
+                "accuracy_model",
+                "estimator",
+                "source_count",
+                "density",
+                "crs",
+                "units",
+                "shape",
+            ],
+            "ipFallback": true,
+            "mlsRadioTypes": mls_radios,
+            "h3Fallback": geolocate.wifi_h3_fallback && config.wifi_storage == WifiStorage::Bounds,
+            "cellAreaFallback": geolocate.cell_area_fallback,
+            "upstream": geolocate.upstream_url.is_some(),
+            "maxCells": geolocate.max_cells,
+        },
+        "geosubmit": {
+            "tokenRequired": config.upload_tokens.is_some(),
+            "buffered": config.submission_buffer.is_some(),
+        },
+        "license": LICENSE,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reflects_config() {
+        let config: Config = toml::from_str("database_url = ''\nhttp_port = 0").unwrap();
+        let res = describe(&config);
+        assert_eq!(res["radioTypes"], json!(["gsm", "wcdma", "lte", "nr"]));
+        assert_eq!(res["geolocate"]["mlsRadioTypes"], res["radioTypes"]);
+        assert_eq!(res["geolocate"]["upstream"], false);
+        assert_eq!(res["geolocate"]["maxCells"], Value::Null);
+        assert_eq!(res["geosubmit"]["tokenRequired"], false);
+
+        let config: Config = toml::from_str(
+            r#"
+            database_url = ''
+            http_port = 0
+            upload_tokens = [{ id = 1, token = "secret" }]
+
+            [geolocate]
+            upstream_url = "https://example.com/v1/geolocate"
+            mls_radios = ["gsm"]
+            max_cells = 10
+            "#,
+        )
+        .unwrap();
+        let res = describe(&config);
+        assert_eq!(res["geolocate"]["mlsRadioTypes"], json!(["gsm"]));
+        assert_eq!(res["geolocate"]["upstream"], true);
+        assert_eq!(res["geolocate"]["maxCells"], 10);
+        assert_eq!(res["geosubmit"]["tokenRequired"], true);
+        // tokens themselves are never shown
+        assert!(!res.to_string().contains("secret"));
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use futures::TryStreamExt;
+use geo::{Distance, Haversine, Point};
+use sqlx::{query, PgPool};
+
+use crate::model::CellRadio;
+
+// a mast often carries cells of several radios, such as gsm, umts and lte
+// cells added as the site was upgraded. their ids are unrelated, but they
+// share an operator and usually a location area, so cells of an area whose
+// centres are close together are grouped into a site. this is greedy rather
+// than a proper clustering: each cell joins the nearest site whose centre is
+// within SITE_RADIUS, or starts a new one.
+
+/// Metres. Cell bounds come from where a cell was heard rather than where the
+/// mast is, so the centres of co-located cells rarely agree exactly
+pub const SITE_RADIUS: f64 = 300.0;
+
+/// The centre of a known cell.
+#[derive(Debug, Clone, Copy)]
+pub struct CellCentre {
+    pub radio: CellRadio,
+    pub lat: f64,
+    pub lon: f64,
+}
+
+/// Cells of an area that are likely on the same mast.
+#[derive(Debug, PartialEq)]
+pub struct Site {
+    pub lat: f64,
+    pub lon: f64,
+    /// Metres from the centre to the furthest cell
+    pub radius: f64,
+    pub radios: BTreeSet<CellRadio>,
+    pub cells: usize,
+}
+
+/// Group the cells of a single area into sites.
+pub fn sites(cells: &[CellCentre]) -> Vec<Site> {
+    let mut groups: Vec<(Point, Vec<CellCentre>)> = Vec::new();
+    for cell in cells {
+        let p = Point::new(cell.lon, cell.lat);
+        let nearest = groups
+            .iter_mut()
+            .map(|(centre, members)| (Haversine::distance(*centre, p), centre, members))
+            .filter(|(distance, _, _)| *distance <= SITE_RADIUS)
+            .min_by(|a, b| a.0.total_cmp(&b.0));
+        match nearest {
+            Some((_, centre, members)) => {
+                members.push(*cell);
+                let n = members.len() as f64;
+                *centre = Point::new(
+                    members.iter().map(|x| x.lon).sum::<f64>() / n,
+                    members.iter().map(|x| x.lat).sum::<f64>() / n,
+                );
+            }
+            None => groups.push((p, vec![*cell])),
+        }
+    }
+
+    groups
+        .into_iter()
+        .map(|(centre, members)| Site {
+            lat: centre.y(),
+            lon: centre.x(),
+            radius: members
+                .iter()
+                .map(|x| Haversine::distance(centre, Point::new(x.lon, x.lat)))
+                .fold(0.0, f64::max),
+            radios: members.iter().map(|x| x.radio).collect(),
+            cells: members.len(),
+        })
+        .collect()
+}
+
+/// Print every site with cells of more than one radio.
+pub async fn run(pool: PgPool) -> Result<()> {
+    let mut rows = query!(
+        r#"select radio as "radio: CellRadio", country, network, area,
+                  (min_lat + max_lat) / 2 as "lat!", (min_lon + max_lon) / 2 as "lon!"
+           from cell order by country, network, area"#
+    )
+    .fetch(&pool);
+
+    let mut area = None;
+    let mut cells = Vec::new();
+    let mut total = 0;
+    let mut print = |area: Option<(i16, i16, i32)>, cells: &[CellCentre]| {
+        let Some((country, network, area)) = area else {
+            return;
+        };
+        for site in sites(cells).into_iter().filter(|x| x.radios.len() > 1) {
+            total += 1;
+            println!(
+                "{country} {network} {area}: {:.5},{:.5} within {:.0}m, {} cells {:?}",
+                site.lat, site.lon, site.radius, site.cells, site.radios
+            );
+        }
+    };
+    while let Some(row) = rows.try_next().await? {
+        let key = Some((row.country, row.network, row.area));
+        if key != area {
+            print(area, &cells);
+            area = key;
+            cells.clear();
+        }
+        cells.push(CellCentre {
+            radio: row.radio,
+            lat: row.lat,
+            lon: row.lon,
+        });
+    }
+    print(area, &cells);
+    eprintln!("{total} sites with cells of more than one radio");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colocated_radios() {
+        let cell = |radio, lat, lon| CellCentre { radio, lat, lon };
+        let cells = [
+            cell(CellRadio::Gsm, -27.4698, 153.0251),
+            // ~900m away
+            cell(CellRadio::Lte, -27.4698, 153.0342),
+            // ~50m from the gsm cell either way
+            cell(CellRadio::Wcdma, -27.4702, 153.0251),
+            cell(CellRadio::Lte, -27.4698, 153.0246),
+        ];
+
+        let sites = sites(&cells);
+        assert_eq!(sites.len(), 2);
+        assert_eq!(
+            sites[0].radios,
+            BTreeSet::from([CellRadio::Gsm, CellRadio::Wcdma, CellRadio::Lte])
+        );
+        assert_eq!(sites[0].cells, 3);
+        assert!((sites[0].lat - -27.46993).abs() < 1e-4);
+        assert!((sites[0].lon - 153.02493).abs() < 1e-4);
+        assert!(sites[0].radius < 50.0);
+
+        assert_eq!(sites[1].radios, BTreeSet::from([CellRadio::Lte]));
+        assert_eq!(sites[1].radius, 0.0);
+    }
+}
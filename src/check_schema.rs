@@ -0,0 +1,190 @@
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::{Column, Executor, PgPool, TypeInfo};
+
+// builds without a database check the query! macros against the offline data
+// in .sqlx, which can fall out of step with the migrations. this prepares
+// every saved query against a database the migrations were just applied to,
+// and reports any whose result columns differ from what was saved.
+
+#[derive(Deserialize)]
+struct Saved {
+    query: String,
+    describe: SavedDescribe,
+}
+
+#[derive(Deserialize)]
+struct SavedDescribe {
+    columns: Vec<SavedColumn>,
+    nullable: Vec<Option<bool>>,
+}
+
+#[derive(Deserialize)]
+struct SavedColumn {
+    name: String,
+    type_info: Value,
+}
+
+/// A result column as described by a database or saved offline.
+#[derive(Debug, PartialEq)]
+struct Described {
+    name: String,
+    /// As given by postgres, such as `INT4` or `TEXT[]`
+    type_name: String,
+    nullable: Option<bool>,
+}
+
+pub async fn run(pool: PgPool, dir: &Path) -> Result<()> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|x| x == "json") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut failed = 0;
+    for path in &paths {
+        let saved: Saved = serde_json::from_slice(&fs::read(path)?)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        let problems = match pool.describe(&saved.query).await {
+            Ok(x) => {
+                let live: Vec<_> = x
+                    .columns()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, c)| Described {
+                        name: c.name().to_owned(),
+                        type_name: c.type_info().name().to_owned(),
+                        nullable: x.nullable(i),
+                    })
+                    .collect();
+                compare(&saved.describe, &live)
+            }
+            Err(e) => vec![format!("failed to prepare: {e}")],
+        };
+
+        if !problems.is_empty() {
+            failed += 1;
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            println!("{name}: {}", saved.query);
+            for problem in problems {
+                println!("  {problem}");
+            }
+        }
+    }
+
+    eprintln!("checked {} queries - {failed} don't match", paths.len());
+    if failed > 0 {
+        bail!("the schema doesn't match the saved queries");
+    }
+    Ok(())
+}
+
+fn compare(saved: &SavedDescribe, live: &[Described]) -> Vec<String> {
+    let saved: Vec<_> = saved
+        .columns
+        .iter()
+        .zip(&saved.nullable)
+        .map(|(c, nullable)| Described {
+            name: c.name.clone(),
+            type_name: saved_type_name(&c.type_info),
+            nullable: *nullable,
+        })
+        .collect();
+
+    let mut problems = Vec::new();
+    if saved.len() != live.len() {
+        problems.push(format!(
+            "saved with {} columns, but returns {}",
+            saved.len(),
+            live.len()
+        ));
+    }
+    for (saved, live) in saved.iter().zip(live) {
+        if saved != live {
+            problems.push(format!("saved as {saved:?}, but is {live:?}"));
+        }
+    }
+    problems
+}
+
+/// The name postgres gives a type saved in the offline data. Built in types
+/// are saved by their sqlx variant name, such as `Int4` or `TextArray`.
+fn saved_type_name(x: &Value) -> String {
+    let name = match x {
+        Value::String(x) => x.as_str(),
+        Value::Object(x) => x
+            .get("Custom")
+            .and_then(|x| x["name"].as_str())
+            .unwrap_or_default(),
+        _ => "",
+    };
+    let (name, array) = match name.strip_suffix("Array") {
+        Some(x) => (x, "[]"),
+        None => (name, ""),
+    };
+    let name = match name {
+        // blank padded char
+        "Bpchar" => "CHAR".to_owned(),
+        x => x.to_uppercase(),
+    };
+    format!("{name}{array}")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn saved_types() {
+        assert_eq!(saved_type_name(&json!("Int4")), "INT4");
+        assert_eq!(saved_type_name(&json!("Timestamptz")), "TIMESTAMPTZ");
+        assert_eq!(saved_type_name(&json!("Bpchar")), "CHAR");
+        assert_eq!(saved_type_name(&json!("TextArray")), "TEXT[]");
+        assert_eq!(
+            saved_type_name(&json!({ "Custom": { "name": "geometry", "kind": "Simple" } })),
+            "GEOMETRY"
+        );
+    }
+
+    #[test]
+    fn mismatched_columns() {
+        let saved: SavedDescribe = serde_json::from_value(json!({
+            "columns": [
+                { "ordinal": 0, "name": "mac", "type_info": "Macaddr" },
+                { "ordinal": 1, "name": "accuracy", "type_info": "Int4" },
+            ],
+            "nullable": [false, true],
+        }))
+        .unwrap();
+        let column = |name: &str, type_name: &str, nullable| Described {
+            name: name.to_owned(),
+            type_name: type_name.to_owned(),
+            nullable: Some(nullable),
+        };
+
+        let live = [
+            column("mac", "MACADDR", false),
+            column("accuracy", "INT4", true),
+        ];
+        assert_eq!(compare(&saved, &live), Vec::<String>::new());
+
+        // a column changed type and another was added
+        let live = [
+            column("mac", "MACADDR", false),
+            column("accuracy", "FLOAT8", true),
+            column("extra", "TEXT", true),
+        ];
+        let problems = compare(&saved, &live);
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[0], "saved with 2 columns, but returns 3");
+        assert!(problems[1].contains("FLOAT8"), "{}", problems[1]);
+    }
+}
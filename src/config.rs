@@ -1,17 +1,111 @@
 use std::{
-    fs,
+    collections::{BTreeMap, BTreeSet},
+    env, fs,
     path::{Path, PathBuf},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, ensure, Context, Result};
 use serde::Deserialize;
+use toml::{Table, Value};
+
+use crate::{
+    geoip::Country,
+    model::{CellRadio, Transmitter},
+};
 
 #[derive(Deserialize)]
 pub struct Config {
     pub database_url: String,
     pub http_port: u16,
 
+    // listen on this unix socket instead of http_port, such as for a reverse
+    // proxy on the same host
+    pub unix_socket: Option<PathBuf>,
+
+    // serve every route under this path, such as "/beacondb" behind a proxy
+    // that routes by path. routes are served from the root when empty
+    #[serde(default)]
+    pub path_prefix: String,
+
+    // a separate, possibly read-only, database holding the mls_cell table.
+    // defaults to the main database
+    pub mls_database_url: Option<String>,
+
+    // the terms this instance's own data is available under, such as a link
+    // to them. sent with every geolocate and country response in an
+    // X-Data-License header, along with DB-IP's license when IP geolocation
+    // was used
+    pub data_license: Option<String>,
+
+    // answer lookups that find nothing with a bare 404, rather than one with
+    // an Ichnaea style error body, for clients that can't handle the body
+    #[serde(default)]
+    pub bare_not_found: bool,
+
+    // never store the ssids of submitted access points. hidden and opted out
+    // networks are dropped when a report is submitted rather than during
+    // processing, and only the remaining access points are kept
+    #[serde(default)]
+    pub strip_ssids: bool,
+
+    // seconds that a report's timestamp may be ahead of the server's clock,
+    // as some devices' clocks run a little fast. reports further ahead are
+    // dropped. an hour when unset
+    pub future_tolerance_secs: Option<u32>,
+
     pub stats: Option<StatsConfig>,
+
+    // how long processed reports are kept before the retention command moves
+    // them out of the database
+    pub retention: Option<RetentionConfig>,
+    #[serde(default)]
+    pub geolocate: GeolocateConfig,
+
+    #[serde(default)]
+    pub wifi_storage: WifiStorage,
+
+    #[serde(default)]
+    pub max_span: MaxSpanConfig,
+
+    #[serde(default)]
+    pub max_accuracy: MaxAccuracyConfig,
+
+    #[serde(default)]
+    pub cell_unit: CellUnit,
+
+    // when set, cells are only stored if their mobile country code belongs to
+    // one of these countries. others are skipped while processing
+    pub cell_countries: Option<BTreeSet<Country>>,
+
+    // when set, only cells of these radio types are stored. others are
+    // skipped while processing
+    pub cell_radios: Option<BTreeSet<CellRadio>>,
+
+    // most transmitters written per statement while processing. larger
+    // batches mean fewer round trips to the database. 1000 when unset
+    pub upsert_batch_size: Option<usize>,
+
+    // most submitted reports written per transaction. each commits on its
+    // own, so a large batch doesn't hold a transaction open for long. 1000
+    // when unset
+    pub insert_batch_size: Option<usize>,
+
+    // when set, submissions are only accepted from uploaders presenting one
+    // of these tokens. geolocate stays open either way.
+    pub upload_tokens: Option<Vec<UploadToken>>,
+
+    // tokens that may pin access points to a known position through
+    // /v2/correct, which is disabled when unset. these are kept apart from
+    // upload_tokens, as any uploader could otherwise overwrite the map
+    pub correction_tokens: Option<Vec<UploadToken>>,
+
+    // when set, submissions are buffered in memory and written in batches
+    pub submission_buffer: Option<BufferConfig>,
+
+    // status codes returned for successful submissions from these exact user
+    // agents, for clients that don't handle the usual one
+    #[serde(default)]
+    pub submission_status: BTreeMap<String, u16>,
 }
 
 #[derive(Deserialize)]
@@ -21,10 +115,415 @@ pub struct StatsConfig {
     // amount of reports that aren't stored in the database but should still
     // be added to the total count
     pub archived_reports: i64,
+
+    // counting every table is slow once they get large, so when set the stats
+    // are only recounted if the file is at least this old
+    pub refresh_interval_secs: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct RetentionConfig {
+    // processed reports submitted more than this many days ago are archived
+    pub days: i64,
+    // directory the archives are written to, a newline delimited file per run
+    pub path: PathBuf,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WifiStorage {
+    /// The bounding box of every position an access point was seen from
+    #[default]
+    Bounds,
+    /// Only the h3 cells an access point was seen in, so that the exact
+    /// positions it was seen from are never stored
+    H3,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CellUnit {
+    /// Cells reported without a psc/pci are stored apart from the same cell
+    /// reported with one
+    #[default]
+    Separate,
+    /// Cells reported without a psc/pci are stored as the psc-specific cell,
+    /// when exactly one is known
+    Merge,
+}
+
+// the largest a transmitter's bounds may grow to, in metres corner to corner.
+// observations that would stretch them further are ignored. unlimited when
+// unset
+#[derive(Deserialize, Default)]
+pub struct MaxSpanConfig {
+    pub wifi: Option<f64>,
+    pub cell: Option<f64>,
+    pub bluetooth: Option<f64>,
+}
+
+// the least accurate position, in metres, that a transmitter may be placed
+// from. observations from positions less accurate than this are skipped, as
+// they would only widen the transmitter's accuracy. unlimited when unset
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct MaxAccuracyConfig {
+    pub wifi: Option<f64>,
+    pub cell: Option<f64>,
+    pub bluetooth: Option<f64>,
+}
+
+impl Config {
+    /// `path_prefix` with a leading slash and without a trailing one, or empty
+    /// when routes are served from the root.
+    pub fn prefix(&self) -> String {
+        let prefix = self.path_prefix.trim_matches('/');
+        if prefix.is_empty() {
+            String::new()
+        } else {
+            format!("/{prefix}")
+        }
+    }
+
+    /// Reject values that parse fine but can't work, rather than failing
+    /// later on, possibly inside a background task.
+    fn validate(&self) -> Result<()> {
+        if let Some(x) = &self.submission_buffer {
+            ensure!(
+                x.capacity > 0,
+                "submission_buffer.capacity must be at least 1"
+            );
+            ensure!(
+                x.flush_interval_ms > 0,
+                "submission_buffer.flush_interval_ms must be at least 1"
+            );
+        }
+        if let Some(x) = self.geolocate.max_signal_weight {
+            // the weakest signal weighs 1, so anything lower would cap every
+            // weight, and zero or less would leave nothing to average
+            ensure!(x >= 1.0, "geolocate.max_signal_weight must be at least 1");
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for MaxAccuracyConfig {
+    fn default() -> Self {
+        Self {
+            wifi: Some(200.0),
+            cell: None,
+            bluetooth: Some(100.0),
+        }
+    }
+}
+
+impl MaxAccuracyConfig {
+    pub fn get(&self, x: &Transmitter) -> Option<f64> {
+        match x {
+            Transmitter::Wifi { .. } => self.wifi,
+            Transmitter::Cell { .. } => self.cell,
+            Transmitter::Bluetooth { .. } | Transmitter::Beacon { .. } => self.bluetooth,
+        }
+    }
+}
+
+impl MaxSpanConfig {
+    pub fn get(&self, x: &Transmitter) -> Option<f64> {
+        match x {
+            Transmitter::Wifi { .. } => self.wifi,
+            Transmitter::Cell { .. } => self.cell,
+            Transmitter::Bluetooth { .. } | Transmitter::Beacon { .. } => self.bluetooth,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct GeolocateConfig {
+    // decimal places that returned coordinates are rounded to. coarser output
+    // is a cheap way to reduce how precisely a client can be located
+    pub output_decimals: i32,
+
+    // ignore transmitters seen on fewer than this many different days, as
+    // short lived ones (like phone hotspots) tend to move around. each report
+    // only counts towards the day of its own timestamp
+    pub min_days_seen: u32,
+
+    // cells whose bounds are larger than this, in metres corner to corner,
+    // aren't used for fixes. unlimited when unset
+    pub max_cell_span: Option<f64>,
+
+    // access points seen on fewer different days than this aren't used for
+    // fixes, unless pinned by a correction. ones without a count, such as
+    // those stored as h3 cells or processed before days were counted, are
+    // skipped while this is set
+    pub min_wifi_days: u32,
+
+    // accuracy in metres of a fix from a transmitter only seen from a single
+    // position, whose bounds say nothing about how far away it was heard
+    pub single_observation_accuracy: f64,
+
+    // the largest accuracy in metres that is returned, for clients that break
+    // on larger values. less accurate fixes are returned with this accuracy,
+    // or treated as not found when reject_inaccurate_fixes is set. unlimited
+    // when unset
+    pub max_returned_accuracy: Option<u32>,
+    pub reject_inaccurate_fixes: bool,
+
+    // radios whose cells are looked up in the MLS data when they aren't known
+    // locally, as its quality varies between radio generations. all when unset
+    pub mls_radios: Option<BTreeSet<CellRadio>>,
+
+    // most cells looked up per request, as each can take two queries. any
+    // after the first this many are ignored, or the request is rejected with
+    // a 400 when reject_excess_cells is set. unlimited when unset
+    pub max_cells: Option<usize>,
+    pub reject_excess_cells: bool,
+
+    // reduce the weight of access points clustered together when averaging,
+    // so that isolated ones count for relatively more
+    pub density_weighting: bool,
+
+    // most an access point's signal strength may weigh when averaging, where
+    // the weakest usable signal (-80 dBm) weighs 1 and the strongest (-50 dBm
+    // and above) ~4.2, so that one strong signal can't outweigh several
+    // consistent weaker ones. must be at least 1, uncapped when unset
+    pub max_signal_weight: Option<f64>,
+
+    // requests that take longer than this to answer, fallbacks included, fail
+    // with 503 instead of holding a worker. unlimited when unset
+    pub timeout_ms: Option<u64>,
+
+    // another geolocation api, such as a larger beaconDB instance, that is
+    // asked to locate clients when no local data is available, unless they
+    // opt out of fallbacks
+    pub upstream_url: Option<String>,
+
+    // with bounds storage, also keep the h3 cells each access point was seen
+    // in, and use those for a coarse fix when none of the requested access
+    // points have bounds. clients can opt out with `fallbacks: {"h3": false}`
+    pub wifi_h3_fallback: bool,
+
+    // when a cell isn't known, but every known cell of its area (of any radio)
+    // is centred on a single site, use that site for a coarse fix
+    pub cell_area_fallback: bool,
+
+    // log the transmitters and outcome of one in this many requests. none are
+    // logged when unset
+    pub log_every: Option<u64>,
+
+    // remember fixes for identical sets of transmitters for a short while
+    pub cache: Option<GeolocateCacheConfig>,
+}
+
+#[derive(Deserialize)]
+pub struct GeolocateCacheConfig {
+    // maximum amount of fixes kept, least recently used are evicted first
+    pub capacity: usize,
+    pub ttl_secs: u64,
+}
+
+impl Default for GeolocateConfig {
+    fn default() -> Self {
+        Self {
+            // ~0.1m
+            output_decimals: 6,
+            min_days_seen: 0,
+            max_cell_span: None,
+            min_wifi_days: 0,
+            single_observation_accuracy: 50.0,
+            max_returned_accuracy: None,
+            reject_inaccurate_fixes: false,
+            mls_radios: None,
+            max_cells: None,
+            reject_excess_cells: false,
+            density_weighting: false,
+            max_signal_weight: None,
+            timeout_ms: None,
+            upstream_url: None,
+            wifi_h3_fallback: false,
+            cell_area_fallback: false,
+            log_every: None,
+            cache: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct BufferConfig {
+    // maximum amount of reports waiting to be written. submissions wait for
+    // space once this is full
+    pub capacity: usize,
+    // reports are written once this many are waiting...
+    pub flush_size: usize,
+    // ...or after this long, whichever comes first
+    pub flush_interval_ms: u64,
+}
+
+#[derive(Deserialize)]
+pub struct UploadToken {
+    // stored alongside each report so that a contributor's data can be
+    // identified later without keeping the token itself in the database
+    pub id: i32,
+    pub token: String,
+}
+
+// top level values can be overridden by environment variables, such as
+// BEACONDB_DATABASE_URL or BEACONDB_HTTP_PORT
+const ENV_PREFIX: &str = "BEACONDB_";
+
+/// Where to look for a config file when none is given, in order of priority.
+pub fn search_paths(xdg_config_home: Option<PathBuf>, home: Option<PathBuf>) -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from("config.toml")];
+    // https://specifications.freedesktop.org/basedir-spec/latest/
+    if let Some(dir) = xdg_config_home
+        .filter(|x| x.is_absolute())
+        .or_else(|| home.map(|x| x.join(".config")))
+    {
+        paths.push(dir.join("beacondb/config.toml"));
+    }
+    paths.push(PathBuf::from("/etc/beacondb/config.toml"));
+    paths
+}
+
+pub fn find(paths: &[PathBuf]) -> Result<&Path> {
+    match paths.iter().find(|x| x.is_file()) {
+        Some(x) => Ok(x),
+        None => {
+            let searched: Vec<_> = paths.iter().map(|x| x.display().to_string()).collect();
+            bail!("No config file found, searched: {}", searched.join(", "))
+        }
+    }
 }
 
 pub fn load(path: &Path) -> Result<Config> {
     let data = fs::read_to_string(path).context("Failed to read config")?;
-    let config = toml::from_str(&data).context("Failed to parse config")?;
+    let mut table: Table = toml::from_str(&data).context("Failed to parse config")?;
+    apply_env(&mut table, env::vars());
+    let config: Config = table.try_into().context("Failed to parse config")?;
+    config.validate()?;
     Ok(config)
 }
+
+fn apply_env(table: &mut Table, vars: impl Iterator<Item = (String, String)>) {
+    for (key, value) in vars {
+        let Some(key) = key.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+
+        let value = if let Ok(x) = value.parse() {
+            Value::Integer(x)
+        } else if let Ok(x) = value.parse() {
+            Value::Boolean(x)
+        } else {
+            Value::String(value)
+        };
+        table.insert(key.to_lowercase(), value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xdg_search() {
+        let xdg = env::temp_dir().join(format!("beacondb-xdg-{}", std::process::id()));
+        let paths = search_paths(Some(xdg.clone()), Some(PathBuf::from("/home/user")));
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from("config.toml"),
+                xdg.join("beacondb/config.toml"),
+                PathBuf::from("/etc/beacondb/config.toml"),
+            ]
+        );
+
+        // relative paths are invalid, so the default under $HOME is used
+        let paths = search_paths(Some(PathBuf::from("relative")), Some("/home/user".into()));
+        assert_eq!(
+            paths[1],
+            Path::new("/home/user/.config/beacondb/config.toml")
+        );
+
+        let paths = [PathBuf::from("/nonexistent/config.toml"), paths[1].clone()];
+        let err = find(&paths).unwrap_err().to_string();
+        assert!(err.contains("/nonexistent/config.toml"));
+        assert!(err.contains("/home/user/.config/beacondb/config.toml"));
+
+        let paths = [
+            PathBuf::from("/nonexistent/config.toml"),
+            xdg.join("beacondb/config.toml"),
+        ];
+        fs::create_dir_all(xdg.join("beacondb")).unwrap();
+        fs::write(&paths[1], "").unwrap();
+        let found = find(&paths).map(Path::to_owned);
+        fs::remove_dir_all(&xdg).unwrap();
+        assert_eq!(found.unwrap(), paths[1]);
+    }
+
+    #[test]
+    fn env_precedence() {
+        let mut table: Table = toml::from_str(
+            r#"
+            database_url = "postgres:///file"
+            http_port = 8099
+            "#,
+        )
+        .unwrap();
+        let vars = [
+            ("BEACONDB_DATABASE_URL", "postgres:///env"),
+            ("BEACONDB_HTTP_PORT", "9000"),
+            ("HTTP_PORT", "1234"),
+        ];
+        apply_env(
+            &mut table,
+            vars.into_iter().map(|(k, v)| (k.to_owned(), v.to_owned())),
+        );
+
+        let config: Config = table.try_into().unwrap();
+        assert_eq!(config.database_url, "postgres:///env");
+        assert_eq!(config.http_port, 9000);
+    }
+
+    #[test]
+    fn env_fills_missing() {
+        let mut table: Table = toml::from_str("http_port = 8099").unwrap();
+        apply_env(
+            &mut table,
+            [(
+                "BEACONDB_DATABASE_URL".to_owned(),
+                "postgres:///env".to_owned(),
+            )]
+            .into_iter(),
+        );
+
+        let config: Config = table.try_into().unwrap();
+        assert_eq!(config.database_url, "postgres:///env");
+        assert_eq!(config.http_port, 8099);
+    }
+
+    #[test]
+    fn rejects_unusable_values() {
+        let validate = |extra: &str| {
+            let config: Config =
+                toml::from_str(&format!("database_url = ''\nhttp_port = 0\n{extra}")).unwrap();
+            config.validate()
+        };
+
+        let buffer = |capacity, flush_interval_ms| {
+            format!("[submission_buffer]\ncapacity = {capacity}\nflush_size = 10\nflush_interval_ms = {flush_interval_ms}")
+        };
+        assert!(validate(&buffer(100, 1000)).is_ok());
+        assert!(validate(&buffer(0, 1000)).is_err());
+        assert!(validate(&buffer(100, 0)).is_err());
+
+        let cap = |x| format!("[geolocate]\nmax_signal_weight = {x}");
+        assert!(validate(&cap("2.0")).is_ok());
+        assert!(validate(&cap("1.0")).is_ok());
+        assert!(validate(&cap("0.5")).is_err());
+        assert!(validate(&cap("0.0")).is_err());
+        assert!(validate(&cap("nan")).is_err());
+    }
+}
@@ -0,0 +1,42 @@
+use anyhow::Result;
+use sqlx::{query, Executor, PgPool};
+
+const TABLES: &[&str] = &[
+    "report",
+    "wifi",
+    "wifi_h3",
+    "cell",
+    "bluetooth",
+    "beacon",
+    "map",
+    "geoip",
+    "mls_cell",
+];
+
+pub async fn run(pool: PgPool, vacuum: bool) -> Result<()> {
+    if vacuum {
+        eprintln!("vacuuming...");
+        // vacuum can't run in a transaction, so the simple query protocol is
+        // used instead of a prepared statement
+        pool.execute("vacuum (analyze)").await?;
+    }
+
+    let tables: Vec<String> = TABLES.iter().map(|x| x.to_string()).collect();
+    // row counts are postgres' estimates, as counting large tables is slow
+    let rows = query!(
+        r#"select relname as "name!", n_live_tup as "rows!",
+             pg_size_pretty(pg_total_relation_size(relid)) as "size!"
+           from pg_stat_user_tables where relname = any($1)
+           order by pg_total_relation_size(relid) desc"#,
+        &tables
+    )
+    .fetch_all(&pool)
+    .await?;
+
+    println!("{:<12} {:>14} {:>10}", "table", "rows", "size");
+    for row in rows {
+        println!("{:<12} {:>14} {:>10}", row.name, row.rows, row.size);
+    }
+
+    Ok(())
+}
@@ -1,9 +1,21 @@
+use serde::Deserialize;
 use strum::{AsRefStr, Display, EnumString, IntoStaticStr};
 
 // https://github.com/annexare/Countries/blob/main/dist/countries.min.json
 
 #[derive(
-    Debug, Clone, Copy, Display, EnumString, AsRefStr, IntoStaticStr, PartialEq, Eq, PartialOrd, Ord,
+    Debug,
+    Clone,
+    Copy,
+    Display,
+    EnumString,
+    AsRefStr,
+    IntoStaticStr,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Deserialize,
 )]
 pub enum Country {
     AD,
@@ -0,0 +1,69 @@
+use std::io::{self, Write};
+
+use anyhow::Result;
+use futures::TryStreamExt;
+use sqlx::{query, PgPool};
+
+use super::import::RawRecord;
+
+// rows imported before the continent, state and city were stored are written
+// with those left empty, so importing them again estimates a coarser accuracy
+
+/// Write the geoip table to stdout in the same CSV format that is imported.
+pub async fn run(pool: PgPool) -> Result<()> {
+    let mut writer = writer(io::stdout().lock());
+    let mut q = query!(
+        r#"select range_start, range_end, continent, country as "country!", state, city, latitude, longitude
+           from geoip order by range_start"#
+    )
+    .fetch(&pool);
+    while let Some(x) = q.try_next().await? {
+        writer.serialize(RawRecord {
+            start: x.range_start.ip(),
+            end: x.range_end.ip(),
+            continent: x.continent.unwrap_or_default(),
+            country: x.country,
+            state: x.state.unwrap_or_default(),
+            city: x.city.unwrap_or_default(),
+            latitude: x.latitude,
+            longitude: x.longitude,
+        })?;
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn writer<W: Write>(output: W) -> csv::Writer<W> {
+    csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::geoip::import::reader;
+
+    #[test]
+    fn round_trip() {
+        let csv = "\
+1.0.0.0,1.0.0.255,OC,AU,Queensland,\"Brisbane, City\",-27.4698,153.0251
+1.0.1.0,1.0.3.255,AS,CN,,,35.8617,104.1954
+2001:200::,2001:200:ffff:ffff:ffff:ffff:ffff:ffff,AS,JP,Tokyo,Tokyo,35.6895,139.6917
+";
+        let records: Vec<RawRecord> = reader(csv.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0].city, "Brisbane, City");
+
+        let mut writer = writer(Vec::new());
+        for x in records {
+            writer.serialize(x).unwrap();
+        }
+        let exported = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        assert_eq!(exported, csv);
+    }
+}
@@ -1,61 +1,188 @@
-use std::{io, net::IpAddr, str::FromStr};
+use std::{
+    io::{self, Read},
+    net::IpAddr,
+    str::FromStr,
+};
 
 use anyhow::{bail, Result};
 use ipnetwork::IpNetwork;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use sqlx::{query, PgPool};
 
 use super::Country;
 
-#[derive(Debug, Deserialize)]
-struct RawRecord {
-    start: IpNetwork,
-    end: IpNetwork,
-    continent: String,
-    country: String,
-    state: String,
-    city: String,
-    latitude: f64,
-    longitude: f64,
+/// A row of DB-IP's IP to City Lite CSV, which has no header.
+#[derive(Debug, Deserialize, Serialize, PartialEq)]
+pub(super) struct RawRecord {
+    pub start: IpAddr,
+    pub end: IpAddr,
+    pub continent: String,
+    pub country: String,
+    pub state: String,
+    pub city: String,
+    pub latitude: f64,
+    pub longitude: f64,
 }
 
-pub async fn run(pool: PgPool) -> Result<()> {
-    let mut reader = csv::ReaderBuilder::new()
+pub(super) fn reader(input: impl Read) -> csv::Reader<impl Read> {
+    csv::ReaderBuilder::new()
         .has_headers(false)
-        .from_reader(io::stdin());
+        .from_reader(input)
+}
+
+// the whole import is loaded into geoip_staging and swapped with geoip by
+// renaming both tables in the same transaction, so lookups see either the
+// previous data or the new data, and never an empty or partial table. geoip is
+// only locked for the swap itself. the previous data is emptied out of the
+// staging table afterwards.
+
+pub async fn run(pool: PgPool) -> Result<()> {
+    let imported = import(&pool, io::stdin()).await?;
+    eprintln!("imported {imported} records");
+    Ok(())
+}
+
+/// Replace the geoip data with the records in `input`, returning how many were
+/// imported. The current data is kept if the import fails or is empty.
+async fn import(pool: &PgPool, input: impl Read) -> Result<usize> {
+    let mut reader = reader(input);
     let mut tx = pool.begin().await?;
+    query!("truncate geoip_staging").execute(&mut *tx).await?;
+    let mut imported = 0;
     for (i, result) in reader.deserialize().enumerate() {
-        let RawRecord {
-            start,
-            end,
-            continent,
-            country,
-            state,
-            city,
-            latitude,
-            longitude,
-        } = result?;
-
-        if country == "ZZ" {
+        let Some(x) = checked(result?)? else {
             continue;
-        }
-        // check it fits into the rust enum
-        Country::from_str(&country)?;
+        };
 
         query!(
-            "insert into geoip (cidr, range_start, range_end, country, latitude, longitude) values (inet_merge($1, $2), $1, $2, $3, $4, $5)",
-            start,
-            end,
-            country,
-            latitude,
-            longitude
+            "insert into geoip_staging (cidr, range_start, range_end, country, latitude, longitude, accuracy, continent, state, city) values (inet_merge($1, $2), $1, $2, $3, $4, $5, $6, $7, $8, $9)",
+            IpNetwork::from(x.start),
+            IpNetwork::from(x.end),
+            x.country,
+            x.latitude,
+            x.longitude,
+            accuracy(&x.state, &x.city),
+            x.continent,
+            x.state,
+            x.city
         ).execute(&mut *tx).await?;
+        imported += 1;
 
         if (i > 0 && i % 100_000 == 0) {
             dbg!(i);
         }
     }
+    if imported == 0 {
+        bail!("no records to import, keeping the current data");
+    }
+
+    for statement in [
+        "alter table geoip rename to geoip_previous",
+        "alter table geoip_staging rename to geoip",
+        "alter table geoip_previous rename to geoip_staging",
+    ] {
+        query(statement).execute(&mut *tx).await?;
+    }
     tx.commit().await?;
+    query!("truncate geoip_staging").execute(pool).await?;
 
-    Ok(())
+    Ok(imported)
+}
+
+/// The record, if it should be imported. Records for reserved ranges have no
+/// country, and are skipped.
+fn checked(x: RawRecord) -> Result<Option<RawRecord>> {
+    if x.country == "ZZ" {
+        return Ok(None);
+    }
+    // check it fits into the rust enum
+    Country::from_str(&x.country)?;
+    Ok(Some(x))
+}
+
+/// A rough estimate in metres of how far off a record's position may be,
+/// based on whether it points to a city, a state or only a country.
+fn accuracy(state: &str, city: &str) -> i32 {
+    if !city.is_empty() {
+        10_000
+    } else if !state.is_empty() {
+        50_000
+    } else {
+        200_000
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checked_records() {
+        let record = |country: &str| RawRecord {
+            start: "1.0.0.0".parse().unwrap(),
+            end: "1.0.0.255".parse().unwrap(),
+            continent: "OC".to_owned(),
+            country: country.to_owned(),
+            state: "Queensland".to_owned(),
+            city: "Brisbane".to_owned(),
+            latitude: -27.4698,
+            longitude: 153.0251,
+        };
+
+        assert_eq!(checked(record("AU")).unwrap(), Some(record("AU")));
+        assert_eq!(checked(record("ZZ")).unwrap(), None);
+        assert!(checked(record("XX")).is_err());
+    }
+
+    #[sqlx::test]
+    async fn staging_swap(pool: PgPool) {
+        let cities = |pool: PgPool| async move {
+            query!("select city from geoip order by city")
+                .fetch_all(&pool)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|x| x.city)
+                .collect::<Vec<_>>()
+        };
+        let staged = |pool: PgPool| async move {
+            query!(r#"select count(*) as "count!" from geoip_staging"#)
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+                .count
+        };
+
+        let first = "1.0.0.0,1.0.0.255,OC,AU,Queensland,Brisbane,-27.4698,153.0251\n";
+        assert_eq!(import(&pool, first.as_bytes()).await.unwrap(), 1);
+        assert_eq!(cities(pool.clone()).await, [Some("Brisbane".to_owned())]);
+
+        // failed and empty imports leave the current data in place
+        let invalid = "1.0.1.0,1.0.1.255,OC,AU,Queensland,Cairns,-16.92,145.77\n\
+                       1.0.2.0,1.0.2.255,EU,XX,,,50.0,10.0\n";
+        assert!(import(&pool, invalid.as_bytes()).await.is_err());
+        let reserved = "10.0.0.0,10.255.255.255,ZZ,ZZ,,,0,0\n";
+        for input in ["", reserved] {
+            assert!(import(&pool, input.as_bytes()).await.is_err());
+        }
+        assert_eq!(cities(pool.clone()).await, [Some("Brisbane".to_owned())]);
+
+        // a successful one replaces it
+        let second = "1.0.1.0,1.0.1.255,OC,AU,Queensland,Cairns,-16.92,145.77\n\
+                      1.0.3.0,1.0.3.255,OC,AU,New South Wales,Sydney,-33.87,151.21\n";
+        assert_eq!(import(&pool, second.as_bytes()).await.unwrap(), 2);
+        assert_eq!(
+            cities(pool.clone()).await,
+            [Some("Cairns".to_owned()), Some("Sydney".to_owned())]
+        );
+        assert_eq!(staged(pool).await, 0);
+    }
+
+    #[test]
+    fn granularity() {
+        assert_eq!(accuracy("Queensland", "Brisbane"), 10_000);
+        assert_eq!(accuracy("", "Brisbane"), 10_000);
+        assert_eq!(accuracy("Queensland", ""), 50_000);
+        assert_eq!(accuracy("", ""), 200_000);
+    }
 }
@@ -16,9 +16,13 @@ use serde::Deserialize;
 use serde_json::json;
 use sqlx::{query_file, PgPool};
 
+use crate::{config::Config, license, not_found};
+
 mod country;
 pub use country::Country;
+pub mod export;
 pub mod import;
+pub mod mcc;
 
 pub const LICENSE: &str =
     "IP geolocation data sourced from IP to City Lite by DB-IP, licensed under CC BY 4.0.";
@@ -26,7 +30,20 @@ pub const LICENSE: &str =
 #[post("/v1/country")]
 pub async fn country_service(
     pool: web::Data<PgPool>,
+    config: web::Data<Config>,
     req: HttpRequest,
+) -> actix_web::Result<HttpResponse> {
+    let mut res = country(&pool, &req, config.bare_not_found).await?;
+    if let Some(x) = &config.data_license {
+        license::append(&mut res, x);
+    }
+    Ok(res)
+}
+
+async fn country(
+    pool: &PgPool,
+    req: &HttpRequest,
+    bare_not_found: bool,
 ) -> actix_web::Result<HttpResponse> {
     let ip = req
         .headers()
@@ -37,7 +54,7 @@ pub async fn country_service(
         .map_err(ErrorInternalServerError)?;
 
     if let Some(record) = query_file!("src/geoip/lookup.sql", ip)
-        .fetch_optional(&**pool)
+        .fetch_optional(pool)
         .await
         .context("database error")
         .map_err(ErrorInternalServerError)?
@@ -47,22 +64,15 @@ pub async fn country_service(
             .parse()
             .context("invalid database")
             .map_err(ErrorInternalServerError)?;
-        Ok(HttpResponse::Ok().json(json!({
+        let mut res = HttpResponse::Ok().json(json!({
             "license": LICENSE,
             "country_code": country.as_ref(),
             "country_name": country.name(),
             "fallback": "ipf"
-        })))
+        }));
+        license::append(&mut res, LICENSE);
+        Ok(res)
     } else {
-        Ok(HttpResponse::NotFound().json(json!({
-            "error": {
-                "errors": [{
-                    "domain": "geolocation",
-                    "reason": "notFound",
-                    "message": "No location could be estimated based on the data provided",
-                }],
-                "code": 404,
-                "message": "Not found",
-        }})))
+        Ok(not_found::response(bare_not_found))
     }
 }
@@ -0,0 +1,168 @@
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+
+use super::{LocationQuery, LocationRequest, LocationResponse};
+use crate::config::GeolocateCacheConfig;
+
+// fixes are cached by the transmitters that were asked about, so that many
+// clients in the same place don't each repeat the same lookups. signal
+// strengths aren't part of the key: a fix from a moment ago is close enough.
+// only fixes from local data are cached, never ip based ones.
+
+pub struct Cache {
+    entries: Mutex<LruCache<u64, (Instant, LocationResponse)>>,
+    ttl: Duration,
+}
+
+impl Cache {
+    pub fn new(config: &GeolocateCacheConfig) -> Self {
+        let capacity = NonZeroUsize::new(config.capacity).unwrap_or(NonZeroUsize::MIN);
+        Cache {
+            entries: Mutex::new(LruCache::new(capacity)),
+            ttl: Duration::from_secs(config.ttl_secs),
+        }
+    }
+
+    pub(super) fn get(
+        &self,
+        req: &LocationRequest,
+        query: &LocationQuery,
+    ) -> Option<LocationResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        let key = key(req, query);
+        match entries.get(&key) {
+            Some((at, res)) if at.elapsed() < self.ttl => Some(res.clone()),
+            Some(_) => {
+                entries.pop(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub(super) fn insert(
+        &self,
+        req: &LocationRequest,
+        query: &LocationQuery,
+        res: LocationResponse,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.put(key(req, query), (Instant::now(), res));
+    }
+
+    /// Forget every fix, such as once stored data has been changed by hand.
+    /// Keys are hashes, so the entries a change affects can't be found.
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+}
+
+/// A hash of the transmitters in a request, regardless of the order they
+/// were listed in, and the query options that affect which are used.
+fn key(req: &LocationRequest, query: &LocationQuery) -> u64 {
+    let mut cells: Vec<_> = req
+        .cell_towers
+        .iter()
+        .map(|x| {
+            (
+                x.radio_type.map(|x| x as i16),
+                x.mobile_country_code,
+                x.mobile_network_code,
+                x.location_area_code,
+                x.cell_id,
+                x.psc,
+            )
+        })
+        .collect();
+    cells.sort_unstable();
+    cells.dedup();
+    let mut macs: Vec<_> = req
+        .wifi_access_points
+        .iter()
+        .map(|x| x.mac_address.bytes())
+        .collect();
+    macs.sort_unstable();
+    macs.dedup();
+
+    let mut hasher = DefaultHasher::new();
+    query.mode.hash(&mut hasher);
+    query.max_age.hash(&mut hasher);
+    query.accuracy_model.hash(&mut hasher);
+    query.estimator.hash(&mut hasher);
+    cells.hash(&mut hasher);
+    macs.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::GeolocateConfig;
+
+    fn request(macs: &[&str]) -> LocationRequest {
+        let aps: Vec<_> = macs
+            .iter()
+            .map(|x| json!({ "macAddress": x, "signalStrength": -60 }))
+            .collect();
+        serde_json::from_value(json!({ "wifiAccessPoints": aps })).unwrap()
+    }
+
+    fn query(x: serde_json::Value) -> LocationQuery {
+        serde_json::from_value(x).unwrap()
+    }
+
+    fn response() -> LocationResponse {
+        let config: GeolocateConfig = toml::from_str("output_decimals = 4").unwrap();
+        LocationResponse::new(-27.4698, 153.0251, 30.0, &config)
+    }
+
+    #[test]
+    fn second_request_hits() {
+        let cache = Cache::new(&toml::from_str("capacity = 10\nttl_secs = 60").unwrap());
+        let first = request(&["00:11:22:33:44:01", "00:11:22:33:44:02"]);
+        assert!(cache.get(&first, &query(json!({}))).is_none());
+        cache.insert(&first, &query(json!({})), response());
+
+        // the same transmitters in a different order
+        let second = request(&["00:11:22:33:44:02", "00:11:22:33:44:01"]);
+        let hit = cache.get(&second, &query(json!({}))).unwrap();
+        assert_eq!(hit.location.lat, -27.4698);
+
+        assert!(cache
+            .get(&second, &query(json!({ "mode": "fast" })))
+            .is_none());
+        assert!(cache
+            .get(&second, &query(json!({ "max_age": 3600 })))
+            .is_none());
+        assert!(cache
+            .get(&request(&["00:11:22:33:44:01"]), &query(json!({})))
+            .is_none());
+    }
+
+    #[test]
+    fn expired() {
+        let cache = Cache::new(&toml::from_str("capacity = 10\nttl_secs = 0").unwrap());
+        let req = request(&["00:11:22:33:44:01"]);
+        cache.insert(&req, &query(json!({})), response());
+        assert!(cache.get(&req, &query(json!({}))).is_none());
+    }
+
+    #[test]
+    fn bounded() {
+        let cache = Cache::new(&toml::from_str("capacity = 1\nttl_secs = 60").unwrap());
+        let first = request(&["00:11:22:33:44:01"]);
+        let second = request(&["00:11:22:33:44:02"]);
+        cache.insert(&first, &query(json!({})), response());
+        cache.insert(&second, &query(json!({})), response());
+        assert!(cache.get(&first, &query(json!({}))).is_none());
+        assert!(cache.get(&second, &query(json!({}))).is_some());
+    }
+}
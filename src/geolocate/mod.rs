@@ -0,0 +1,1696 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    future::Future,
+    str::FromStr,
+};
+
+use actix_web::{
+    error::{ErrorBadRequest, ErrorInternalServerError, ErrorServiceUnavailable},
+    post, web, HttpRequest, HttpResponse,
+};
+use anyhow::Context;
+use chrono::{Duration, Utc};
+use geo::{Distance, Haversine, Intersects, Point, Rect};
+use ipnetwork::IpNetwork;
+use mac_address::MacAddress;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{query, query_as, query_file, query_scalar, PgPool};
+
+use self::{cache::Cache, sample::Sampler};
+use crate::{
+    bounds::Bounds,
+    cell_sites::{sites, CellCentre, SITE_RADIUS},
+    config::{Config, GeolocateConfig, WifiStorage},
+    geoip::Country,
+    license,
+    mls::MlsPool,
+    model::{h3_cell, lookup_wifi, CellRadio, SeenBounds, SeenFilter, WifiBounds},
+    not_found,
+    request_id::RequestId,
+};
+
+pub mod cache;
+pub mod sample;
+pub mod upstream;
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct LocationRequest {
+    #[serde(default)]
+    cell_towers: Vec<CellTower>,
+    #[serde(default)]
+    wifi_access_points: Vec<AccessPoint>,
+
+    // some clients give one radio type for every cell here instead
+    radio_type: Option<CellRadio>,
+    consider_ip: Option<bool>,
+    fallbacks: Option<FallbackOptions>,
+}
+
+impl LocationRequest {
+    /// Apply the top level radio type to any cells that don't have their own.
+    fn default_radio_type(&mut self) {
+        for x in &mut self.cell_towers {
+            x.radio_type = x.radio_type.or(self.radio_type);
+        }
+    }
+
+    /// Only consider the first `max_cells` cells, as each one can take two
+    /// queries. Returns false instead when configured to reject such requests.
+    fn limit_cells(&mut self, config: &GeolocateConfig) -> bool {
+        match config.max_cells {
+            Some(max) if self.cell_towers.len() > max => {
+                self.cell_towers.truncate(max);
+                !config.reject_excess_cells
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether the client may be located by its IP address. Either field
+    /// opting out is enough, so an explicit `considerIp: false` can't be
+    /// overridden by `fallbacks.ipf`, and vice versa. Both default to allowing
+    /// it.
+    fn allows_ip(&self) -> bool {
+        let ipf = self.fallbacks.as_ref().and_then(|x| x.ipf);
+        self.consider_ip != Some(false) && ipf != Some(false)
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
+#[serde(default)]
+struct LocationQuery {
+    mode: Mode,
+    confidence: bool,
+    points: bool,
+    /// Metres. Worse fixes are treated as not found
+    max_accuracy: Option<u32>,
+    /// Seconds. Transmitters not seen for longer than this are ignored
+    max_age: Option<u32>,
+    accuracy_model: AccuracyModel,
+    estimator: Estimator,
+    /// Include how many transmitters the fix was estimated from
+    source_count: bool,
+    /// Include how many known access points are centred within the accuracy
+    /// of the fix
+    density: bool,
+    /// The coordinate system of the location. Contributing points stay in
+    /// WGS84 either way
+    crs: Crs,
+    /// The units of the accuracy. `max_accuracy` is always in metres
+    units: Units,
+    shape: Shape,
+}
+
+impl LocationQuery {
+    fn accepts(&self, accuracy: f64) -> bool {
+        self.max_accuracy.is_none_or(|x| accuracy <= x as f64)
+    }
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    /// Weighted average of every known access point
+    #[default]
+    Thorough,
+    /// Only look up the strongest access point, trading accuracy for a single
+    /// database query
+    Fast,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum AccuracyModel {
+    /// Weighted average of the contributing access points' radii
+    #[default]
+    Radius,
+    /// Weighted RMS distance of the contributing access points from the fix,
+    /// which grows when they disagree with each other
+    Variance,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum Estimator {
+    /// Weighted average of the contributing access points' centres
+    #[default]
+    Mean,
+    /// Weighted geometric median of the centres, which a few misplaced access
+    /// points barely move
+    Median,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+enum Crs {
+    /// Latitude and longitude
+    #[default]
+    #[serde(rename = "epsg:4326", alias = "EPSG:4326")]
+    Wgs84,
+    /// Web mercator x and y, in metres
+    #[serde(rename = "epsg:3857", alias = "EPSG:3857")]
+    WebMercator,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Shape {
+    /// A location and an accuracy radius
+    #[default]
+    Point,
+    /// `[min_lng, min_lat, max_lng, max_lat]` instead of a location and
+    /// accuracy
+    Bbox,
+}
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Units {
+    #[default]
+    #[serde(alias = "metres")]
+    Meters,
+    Feet,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct FallbackOptions {
+    ipf: Option<bool>,
+    h3: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CellTower {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    radio_type: Option<CellRadio>,
+    mobile_country_code: i16,
+    mobile_network_code: i16,
+    location_area_code: i32,
+    cell_id: i64,
+    psc: Option<i16>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AccessPoint {
+    mac_address: MacAddress,
+    signal_strength: Option<i8>,
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct LocationResponse {
+    location: Location,
+    accuracy: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    confidence: Option<Confidence>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    points: Option<Vec<ContributingPoint>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    fallback: Option<&'static str>,
+    #[serde(rename = "sourceCount", skip_serializing_if = "Option::is_none")]
+    source_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    density: Option<i64>,
+
+    // the transmitters that the fix was estimated from
+    #[serde(skip)]
+    contributors: Vec<ContributingPoint>,
+    // the stored bounds of the transmitter, for a fix from a single one
+    #[serde(skip)]
+    bounds: Option<Bounds>,
+}
+
+/// The estimated position of a transmitter used for a fix, and how much it
+/// counted towards it.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+struct ContributingPoint {
+    lat: f64,
+    lng: f64,
+    radius: f64,
+    weight: f64,
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum Confidence {
+    High,
+    Medium,
+    Low,
+}
+
+impl Confidence {
+    /// A rough bucket for clients that don't want to interpret the accuracy
+    /// themselves:
+    ///
+    /// - high: accurate to under 100m, using at least 3 transmitters
+    /// - medium: accurate to under 1km
+    /// - low: anything else
+    fn new(accuracy: i64, sources: usize) -> Self {
+        if accuracy < 100 && sources >= 3 {
+            Confidence::High
+        } else if accuracy < 1000 {
+            Confidence::Medium
+        } else {
+            Confidence::Low
+        }
+    }
+}
+
+impl LocationResponse {
+    /// A fix from a single transmitter.
+    fn new(lat: f64, lon: f64, acc: f64, config: &GeolocateConfig) -> Self {
+        let lat = round(lat, config);
+        let lon = round(lon, config);
+
+        LocationResponse {
+            location: Location { lat, lng: lon },
+            accuracy: (acc.round() as i64).max(50),
+            confidence: None,
+            points: None,
+            fallback: None,
+            source_count: None,
+            density: None,
+            bounds: None,
+            contributors: vec![ContributingPoint {
+                lat,
+                lng: lon,
+                radius: acc,
+                weight: 1.0,
+            }],
+        }
+    }
+
+    /// Replace the contributors of a fix estimated from several transmitters.
+    fn contributors(mut self, points: Vec<ContributingPoint>, config: &GeolocateConfig) -> Self {
+        self.contributors = points
+            .into_iter()
+            .map(|x| ContributingPoint {
+                lat: round(x.lat, config),
+                lng: round(x.lng, config),
+                ..x
+            })
+            .collect();
+        self
+    }
+
+    fn respond(mut self, query: &LocationQuery) -> actix_web::Result<HttpResponse> {
+        if query.confidence {
+            self.confidence = Some(Confidence::new(self.accuracy, self.contributors.len()));
+        }
+        if query.points {
+            self.points = Some(self.contributors.clone());
+        }
+        if query.source_count {
+            self.source_count = Some(self.contributors.len());
+        }
+
+        if self.location.lat.is_nan() || self.location.lng.is_nan() {
+            return Ok(HttpResponse::InternalServerError().finish());
+        }
+        let mut body = serde_json::to_value(&self).map_err(ErrorInternalServerError)?;
+        project(&mut body, self.bounds, query);
+        Ok(HttpResponse::Ok().json(body))
+    }
+
+    /// Apply `max_returned_accuracy`, or give nothing if the fix is to be
+    /// rejected. A capped fix no longer has its transmitter's bounds, which
+    /// would be larger than the accuracy.
+    fn capped(mut self, config: &GeolocateConfig) -> Option<Self> {
+        let accuracy = returned_accuracy(self.accuracy as f64, config)?;
+        if accuracy < self.accuracy as f64 {
+            self.accuracy = accuracy as i64;
+            self.bounds = None;
+        }
+        Some(self)
+    }
+
+    /// Count the access points centred within the accuracy of the fix, or
+    /// rather the square around it. Only access points with bounds can be
+    /// counted, so there's no count with h3 storage.
+    async fn with_density(
+        mut self,
+        query: &LocationQuery,
+        pool: &PgPool,
+        storage: WifiStorage,
+    ) -> actix_web::Result<Self> {
+        if !query.density || storage != WifiStorage::Bounds {
+            return Ok(self);
+        }
+
+        let b = Bounds::around(self.location.lat, self.location.lng, self.accuracy as f64);
+        let count = query_scalar!(
+            "select count(*) from wifi
+             where (min_lat + max_lat) / 2 between $1 and $2 and (min_lon + max_lon) / 2 between $3 and $4",
+            b.min_lat,
+            b.max_lat,
+            b.min_lon,
+            b.max_lon
+        )
+        .fetch_one(pool)
+        .await
+        .map_err(ErrorInternalServerError)?;
+        self.density = count;
+        Ok(self)
+    }
+
+    fn from_bounds(value: Bounds, config: &GeolocateConfig) -> Self {
+        Self::from_stored(value, None, config)
+    }
+
+    /// A fix from a transmitter's stored bounds, no more precise than the
+    /// accuracy of the positions it was seen from.
+    fn from_stored(value: Bounds, accuracy: Option<f64>, config: &GeolocateConfig) -> Self {
+        let (min, max) = value.points();
+        let center = (min + max) / 2.0;
+        let acc = match accuracy {
+            Some(x) => Haversine::distance(min, center).max(x),
+            None if min == max => config.single_observation_accuracy,
+            None => Haversine::distance(min, center),
+        };
+        let (lon, lat) = center.x_y();
+        let bounds = Bounds {
+            min_lat: round(value.min_lat, config),
+            min_lon: round(value.min_lon, config),
+            max_lat: round(value.max_lat, config),
+            max_lon: round(value.max_lon, config),
+        };
+        Self {
+            bounds: Some(bounds),
+            ..Self::new(lat, lon, acc, config)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone)]
+struct Location {
+    lat: f64,
+    lng: f64,
+}
+
+#[post("/v1/geolocate")]
+pub async fn service(
+    data: Option<web::Json<LocationRequest>>,
+    query: web::Query<LocationQuery>,
+    pool: web::Data<PgPool>,
+    mls_pool: web::Data<MlsPool>,
+    config: web::Data<Config>,
+    client: web::Data<reqwest::Client>,
+    req: HttpRequest,
+) -> actix_web::Result<HttpResponse> {
+    let data = data.map(|x| x.into_inner()).unwrap_or_default();
+    let sample = req
+        .app_data::<web::Data<Sampler>>()
+        .filter(|x| x.sample())
+        .map(|_| format!("{data:?}"));
+    // the fallbacks, upstream and density lookups count towards the timeout
+    // along with the transmitters themselves
+    let res = with_timeout(
+        geolocate(data, &query, &pool, &mls_pool, &config, &client, &req),
+        config
+            .geolocate
+            .timeout_ms
+            .map(std::time::Duration::from_millis),
+    )
+    .await;
+    if let Some(data) = sample {
+        let id = RequestId::of(&req);
+        match &res {
+            Ok(x) => eprintln!(
+                "[{id}] sampled geolocate request: {} for {data}",
+                x.status()
+            ),
+            Err(e) => eprintln!("[{id}] sampled geolocate request: {e} for {data}"),
+        }
+    }
+
+    let mut res = res?;
+    if let Some(x) = &config.data_license {
+        license::append(&mut res, x);
+    }
+    Ok(res)
+}
+
+async fn geolocate(
+    mut data: LocationRequest,
+    query: &LocationQuery,
+    pool: &PgPool,
+    mls_pool: &MlsPool,
+    config: &Config,
+    client: &reqwest::Client,
+    req: &HttpRequest,
+) -> actix_web::Result<HttpResponse> {
+    data.default_radio_type();
+    let storage = config.wifi_storage;
+    let bare_not_found = config.bare_not_found;
+    let config = &config.geolocate;
+    if !data.limit_cells(config) {
+        return Err(ErrorBadRequest("too many cell towers"));
+    }
+
+    let filter = SeenFilter {
+        min_days: i32::try_from(config.min_days_seen).unwrap_or(i32::MAX),
+        not_before: query
+            .max_age
+            .map(|x| Utc::now() - Duration::seconds(x.into())),
+    };
+
+    let cache = req.app_data::<web::Data<Cache>>();
+    let res = match cache.and_then(|x| x.get(&data, query)) {
+        Some(res) => Some(res),
+        None => {
+            let res = locate(&data, query, filter, pool, &mls_pool.0, storage, config).await?;
+            if let (Some(cache), Some(res)) = (cache, &res) {
+                cache.insert(&data, query, res.clone());
+            }
+            res
+        }
+    };
+    if let Some(res) = res
+        .filter(|x| query.accepts(x.accuracy as f64))
+        .and_then(|x| x.capped(config))
+    {
+        return res.with_density(query, pool, storage).await?.respond(query);
+    }
+
+    let h3_fallback = config.wifi_h3_fallback
+        && storage == WifiStorage::Bounds
+        && data.fallbacks.as_ref().and_then(|x| x.h3).unwrap_or(true);
+    if h3_fallback && !data.wifi_access_points.is_empty() {
+        let macs: Vec<_> = data
+            .wifi_access_points
+            .iter()
+            .map(|x| x.mac_address)
+            .collect();
+        let rows = query!(
+            "select mac, h3 from wifi_h3 where mac = any($1) and not exists (select from wifi where wifi.mac = wifi_h3.mac)",
+            &macs
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(ErrorInternalServerError)?;
+        if let Some(b) = tightest_cells(rows.into_iter().map(|x| (x.mac, x.h3))) {
+            let mut res = LocationResponse::from_bounds(b, config);
+            res.fallback = Some("h3");
+            if let Some(res) = Some(res)
+                .filter(|x| query.accepts(x.accuracy as f64))
+                .and_then(|x| x.capped(config))
+            {
+                return res.with_density(query, pool, storage).await?.respond(query);
+            }
+        }
+    }
+
+    if let Some(url) = &config.upstream_url {
+        match upstream::locate(client, url, &data).await {
+            Ok(Some(mut x)) if x["accuracy"].as_f64().is_some_and(|x| query.accepts(x)) => {
+                if cap_accuracy(&mut x, config) {
+                    project(&mut x, None, query);
+                    return Ok(HttpResponse::Ok().json(x));
+                }
+            }
+            Ok(Some(_)) => (),
+            Ok(None) => (),
+            Err(e) => eprintln!("[{}] Upstream geolocation failed: {e}", RequestId::of(req)),
+        }
+    }
+
+    if data.allows_ip() {
+        let ip = req
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| IpNetwork::from_str(x).ok())
+            .context("failed to get client ip address")
+            .map_err(ErrorInternalServerError)?;
+        if let Some(record) = query_file!("src/geoip/lookup.sql", ip)
+            .fetch_optional(pool)
+            .await
+            .map_err(ErrorInternalServerError)?
+            .filter(|x| query.accepts(x.accuracy.into()))
+        {
+            let mut res = json!({
+                "license": crate::geoip::LICENSE,
+                "location": {
+                    "lat": record.latitude,
+                    "lng": record.longitude,
+                },
+                "accuracy": record.accuracy,
+                "fallback": "ipf"
+            });
+            if query.confidence {
+                res["confidence"] = json!(Confidence::Low);
+            }
+            if cap_accuracy(&mut res, config) {
+                project(&mut res, None, query);
+                let mut res = HttpResponse::Ok().json(res);
+                license::append(&mut res, crate::geoip::LICENSE);
+                return Ok(res);
+            }
+        }
+    }
+
+    Ok(not_found::response(bare_not_found))
+}
+
+/// Estimate a position using only the transmitters in the database.
+async fn locate(
+    data: &LocationRequest,
+    query: &LocationQuery,
+    filter: SeenFilter,
+    pool: &PgPool,
+    mls_pool: &PgPool,
+    storage: WifiStorage,
+    config: &GeolocateConfig,
+) -> actix_web::Result<Option<LocationResponse>> {
+    if query.mode == Mode::Fast {
+        if let Some(x) = strongest(&data.wifi_access_points) {
+            let row = lookup_wifi(pool, &x.mac_address, storage, filter)
+                .await
+                .map_err(ErrorInternalServerError)?;
+            if let Some(row) = row.filter(|x| usable_wifi(x, config)) {
+                let accuracy = row.seen.and_then(|x| x.accuracy);
+                let res = LocationResponse::from_stored(row.bounds, accuracy, config);
+                if res.accuracy <= 500 {
+                    return Ok(Some(res));
+                }
+            }
+        }
+    } else {
+        let mut points = Vec::new();
+        let mut seen = BTreeSet::new();
+        for x in &data.wifi_access_points {
+            if !seen.insert(x.mac_address) {
+                continue;
+            }
+
+            let Some(signal) = normalize_signal(x.signal_strength) else {
+                continue;
+            };
+            let weight = signal_weight(signal, config.max_signal_weight);
+
+            let row = lookup_wifi(pool, &x.mac_address, storage, filter)
+                .await
+                .map_err(ErrorInternalServerError)?;
+            if let Some(row) = row.filter(|x| usable_wifi(x, config)) {
+                let (min, max) = row.bounds.points();
+                let center = (min + max) / 2.0;
+                let r = Haversine::distance(min, center);
+                let r = row.seen.and_then(|x| x.accuracy).map_or(r, |x| r.max(x));
+                let (lon, lat) = center.x_y();
+
+                if (1.0..=500.0).contains(&r) {
+                    points.push(ContributingPoint {
+                        lat,
+                        lng: lon,
+                        radius: r,
+                        weight,
+                    });
+                }
+            }
+        }
+        let mut points = exclude_outliers(points);
+        if points.len() >= 2 {
+            if config.density_weighting {
+                weight_by_density(&mut points);
+            }
+            let (mut lat, mut lon, mut r) = weighted_average(&points);
+            if query.estimator == Estimator::Median {
+                (lat, lon) = weighted_median(&points, lat, lon);
+            }
+            if query.accuracy_model == AccuracyModel::Variance {
+                r = weighted_rms_distance(&points, lat, lon);
+            }
+            if lat.is_nan() || lon.is_nan() {
+                dbg!(&points);
+            } else {
+                return Ok(Some(
+                    LocationResponse::new(lat, lon, r, config).contributors(points, config),
+                ));
+            }
+        }
+    }
+
+    // todo: this is awful
+    for x in &data.cell_towers {
+        let Some(radio) = x.radio_type else {
+            continue;
+        };
+        if let Some(unit) = x.psc {
+            let row = query_as!(SeenBounds, "select min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy from cell where radio = $1 and country = $2 and network = $3 and area = $4 and cell = $5 and unit = $6",
+                radio as i16, x.mobile_country_code, x.mobile_network_code, x.location_area_code, x.cell_id, unit
+            ).fetch_optional(pool).await.map_err(ErrorInternalServerError)?;
+            let row = row.and_then(|x| x.allowed_with_seen(filter));
+            if let Some((b, seen)) = row.filter(|(b, _)| usable_cell(b, config)) {
+                let accuracy = seen.and_then(|x| x.accuracy);
+                return Ok(Some(LocationResponse::from_stored(b, accuracy, config)));
+            }
+            if !uses_mls(radio, config) {
+                continue;
+            }
+
+            let row = query!("select lat, lon, radius from mls_cell where radio = $1 and country = $2 and network = $3 and area = $4 and cell = $5 and unit = $6",
+                radio as i16, x.mobile_country_code, x.mobile_network_code, x.location_area_code, x.cell_id, unit
+            ).fetch_optional(mls_pool).await.map_err(ErrorInternalServerError)?;
+            if let Some(row) = row {
+                return Ok(Some(LocationResponse::new(
+                    row.lat, row.lon, row.radius, config,
+                )));
+            }
+        } else {
+            let row = query_as!(SeenBounds, "select min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy from cell where radio = $1 and country = $2 and network = $3 and area = $4 and cell = $5",
+                radio as i16, x.mobile_country_code, x.mobile_network_code, x.location_area_code, x.cell_id
+            ).fetch_optional(pool).await.map_err(ErrorInternalServerError)?;
+            let row = row.and_then(|x| x.allowed_with_seen(filter));
+            if let Some((b, seen)) = row.filter(|(b, _)| usable_cell(b, config)) {
+                let accuracy = seen.and_then(|x| x.accuracy);
+                return Ok(Some(LocationResponse::from_stored(b, accuracy, config)));
+            }
+            if !uses_mls(radio, config) {
+                continue;
+            }
+
+            let row = query!("select lat, lon, radius from mls_cell where radio = $1 and country = $2 and network = $3 and area = $4 and cell = $5",
+                radio as i16, x.mobile_country_code, x.mobile_network_code, x.location_area_code, x.cell_id
+            ).fetch_optional(mls_pool).await.map_err(ErrorInternalServerError)?;
+            if let Some(row) = row {
+                return Ok(Some(LocationResponse::new(
+                    row.lat, row.lon, row.radius, config,
+                )));
+            }
+        }
+    }
+
+    // an unknown lte cell is often a new sector of a known enodeb, which is
+    // at the same site as its other sectors
+    for x in &data.cell_towers {
+        let Some(radio) = x.radio_type else {
+            continue;
+        };
+        let Some((start, end)) = radio.site_cells(x.cell_id) else {
+            continue;
+        };
+        let rows = query_as!(SeenBounds, "select min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy from cell where radio = $1 and country = $2 and network = $3 and area = $4 and cell between $5 and $6",
+            radio as i16, x.mobile_country_code, x.mobile_network_code, x.location_area_code, start, end
+        ).fetch_all(pool).await.map_err(ErrorInternalServerError)?;
+        let site = rows
+            .into_iter()
+            .filter_map(|x| x.allowed_by(filter))
+            .reduce(|b, x| b + (x.min_lat, x.min_lon) + (x.max_lat, x.max_lon));
+        if let Some(b) = site.filter(|x| usable_cell(x, config)) {
+            return Ok(Some(LocationResponse::from_bounds(b, config)));
+        }
+    }
+
+    // when every known cell of an area is on one mast, an unknown cell of the
+    // area is most likely on it too - often a new radio added to the site
+    if config.cell_area_fallback {
+        for x in &data.cell_towers {
+            let rows = query!(r#"select radio as "radio: CellRadio", min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy from cell where country = $1 and network = $2 and area = $3 limit 1000"#,
+                x.mobile_country_code, x.mobile_network_code, x.location_area_code
+            ).fetch_all(pool).await.map_err(ErrorInternalServerError)?;
+            let cells: Vec<_> = rows
+                .into_iter()
+                .filter_map(|row| {
+                    let b = SeenBounds {
+                        min_lat: row.min_lat,
+                        min_lon: row.min_lon,
+                        max_lat: row.max_lat,
+                        max_lon: row.max_lon,
+                        first_seen: row.first_seen,
+                        last_seen: row.last_seen,
+                        days_seen: row.days_seen,
+                        accuracy: row.accuracy,
+                    }
+                    .allowed_by(filter)
+                    .filter(|b| usable_cell(b, config))?;
+                    Some(CellCentre {
+                        radio: row.radio,
+                        lat: (b.min_lat + b.max_lat) / 2.0,
+                        lon: (b.min_lon + b.max_lon) / 2.0,
+                    })
+                })
+                .collect();
+            if let [site] = &sites(&cells)[..] {
+                if site.radios.len() > 1 {
+                    let acc = site.radius.max(SITE_RADIUS);
+                    let mut res = LocationResponse::new(site.lat, site.lon, acc, config);
+                    res.fallback = Some("lacf");
+                    return Ok(Some(res));
+                }
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Give up on a request that takes longer than `timeout`, so that one with many
+/// transmitters can't hold a worker while the database or upstream is slow.
+async fn with_timeout<T>(
+    lookup: impl Future<Output = actix_web::Result<T>>,
+    timeout: Option<std::time::Duration>,
+) -> actix_web::Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, lookup)
+            .await
+            .map_err(|_| ErrorServiceUnavailable("geolocation timed out"))?,
+        None => lookup.await,
+    }
+}
+
+/// Convert a response to the shape, coordinate system and units asked for.
+/// Responses are built as a point in WGS84 and metres, whether they come from
+/// the database, upstream or the ip fallback. A box is made from the stored
+/// bounds of the transmitter the fix came from when there are any, otherwise
+/// from the accuracy.
+fn project(body: &mut Value, bounds: Option<Bounds>, query: &LocationQuery) {
+    if query.shape == Shape::Bbox {
+        let location = &body["location"];
+        let around = match (location["lat"].as_f64(), location["lng"].as_f64()) {
+            (Some(lat), Some(lng)) => body["accuracy"]
+                .as_f64()
+                .map(|acc| Bounds::around(lat, lng, acc)),
+            _ => None,
+        };
+        if let Some(b) = bounds.or(around) {
+            body["bbox"] = json!([b.min_lon, b.min_lat, b.max_lon, b.max_lat]);
+            if let Some(x) = body.as_object_mut() {
+                x.remove("location");
+                x.remove("accuracy");
+            }
+        }
+    }
+
+    if query.units == Units::Feet {
+        if let Some(x) = body["accuracy"].as_f64() {
+            body["accuracy"] = json!((x / 0.3048).round() as i64);
+        }
+    }
+
+    if query.crs == Crs::WebMercator {
+        let location = &body["location"];
+        if let (Some(lat), Some(lng)) = (location["lat"].as_f64(), location["lng"].as_f64()) {
+            let (x, y) = web_mercator(lat, lng);
+            let x = (x * 100.0).round() / 100.0;
+            let y = (y * 100.0).round() / 100.0;
+            body["location"] = json!({ "x": x, "y": y });
+            body["crs"] = json!("EPSG:3857");
+        }
+        let corners = body["bbox"].as_array().and_then(|x| {
+            let x: Vec<_> = x.iter().filter_map(Value::as_f64).collect();
+            <[f64; 4]>::try_from(x).ok()
+        });
+        if let Some([min_lng, min_lat, max_lng, max_lat]) = corners {
+            let (min_x, min_y) = web_mercator(min_lat, min_lng);
+            let (max_x, max_y) = web_mercator(max_lat, max_lng);
+            let bbox = [min_x, min_y, max_x, max_y].map(|x| (x * 100.0).round() / 100.0);
+            body["bbox"] = json!(bbox);
+            body["crs"] = json!("EPSG:3857");
+        }
+    }
+}
+
+/// The accuracy a fix is returned with under `max_returned_accuracy`, or
+/// nothing if it's to be rejected.
+fn returned_accuracy(accuracy: f64, config: &GeolocateConfig) -> Option<f64> {
+    match config.max_returned_accuracy.map(f64::from) {
+        Some(max) if accuracy > max => (!config.reject_inaccurate_fixes).then_some(max),
+        _ => Some(accuracy),
+    }
+}
+
+/// Apply `max_returned_accuracy` to a response from upstream or IP
+/// geolocation, returning false if it's to be rejected.
+fn cap_accuracy(body: &mut Value, config: &GeolocateConfig) -> bool {
+    let Some(accuracy) = body["accuracy"].as_f64() else {
+        return true;
+    };
+    match returned_accuracy(accuracy, config) {
+        Some(x) if x < accuracy => {
+            body["accuracy"] = json!(x as i64);
+            true
+        }
+        Some(_) => true,
+        None => false,
+    }
+}
+
+/// Project WGS84 coordinates to web mercator. Latitudes past ~85° can't be
+/// represented, so are clamped to the edge of the map.
+fn web_mercator(lat: f64, lng: f64) -> (f64, f64) {
+    const RADIUS: f64 = 6_378_137.0;
+    const MAX_LAT: f64 = 85.051_128_78;
+
+    let lat = lat.clamp(-MAX_LAT, MAX_LAT).to_radians();
+    let x = RADIUS * lng.to_radians();
+    let y = RADIUS * (std::f64::consts::FRAC_PI_4 + lat / 2.0).tan().ln();
+    (x, y)
+}
+
+/// The bounds of whichever access point was seen in the smallest area, given
+/// the h3 cells that each one was seen in.
+fn tightest_cells(rows: impl IntoIterator<Item = (MacAddress, Vec<u8>)>) -> Option<Bounds> {
+    let mut cells: BTreeMap<MacAddress, Vec<_>> = BTreeMap::new();
+    for (mac, h3) in rows {
+        if let Some(cell) = h3_cell(&h3) {
+            cells.entry(mac).or_default().push(cell);
+        }
+    }
+
+    cells
+        .into_values()
+        .filter_map(Bounds::from_cells)
+        .min_by(|a, b| a.span().total_cmp(&b.span()))
+}
+
+/// Whether MLS data is trusted for cells of this radio.
+fn uses_mls(radio: CellRadio, config: &GeolocateConfig) -> bool {
+    config
+        .mls_radios
+        .as_ref()
+        .is_none_or(|x| x.contains(&radio))
+}
+
+/// Cells spread over too large an area are most likely mobile or have
+/// conflicting identifiers, so they're skipped rather than returned as a fix.
+fn usable_cell(b: &Bounds, config: &GeolocateConfig) -> bool {
+    config.max_cell_span.is_none_or(|max| b.span() <= max)
+}
+
+/// Access points only ever seen on the one day may be well placed, or may
+/// just be one stale reading repeated, so can be required to have been seen
+/// on more days before they're trusted. Ones that weren't counted can't show
+/// that, unless they were pinned by hand.
+fn usable_wifi(x: &WifiBounds, config: &GeolocateConfig) -> bool {
+    let min = i32::try_from(config.min_wifi_days).unwrap_or(i32::MAX);
+    min == 0 || x.pinned || x.seen.is_some_and(|x| x.days >= min)
+}
+
+fn round(x: f64, config: &GeolocateConfig) -> f64 {
+    let scale = 10f64.powi(config.output_decimals);
+    (x * scale).round() / scale
+}
+
+/// The latitude, longitude and radius of the points, weighted by their weight.
+fn weighted_average(points: &[ContributingPoint]) -> (f64, f64, f64) {
+    let (mut lat, mut lon, mut r, mut w) = (0.0, 0.0, 0.0, 0.0);
+    for x in points {
+        lat += x.lat * x.weight;
+        lon += x.lng * x.weight;
+        r += x.radius * x.weight;
+        w += x.weight;
+    }
+    (lat / w, lon / w, r / w)
+}
+
+/// At most this many iterations are used to find a median, though it usually
+/// settles within a few dozen.
+const MEDIAN_ITERATIONS: usize = 100;
+
+/// The weighted geometric median of the points, found with Weiszfeld's
+/// algorithm starting from the weighted average. Distances are measured on a
+/// plane with longitude scaled to the latitude, which is close enough over the
+/// few hundred metres that contributing access points span.
+fn weighted_median(points: &[ContributingPoint], lat: f64, lon: f64) -> (f64, f64) {
+    let scale = lat.to_radians().cos();
+    let (mut lat, mut lon) = (lat, lon);
+    for _ in 0..MEDIAN_ITERATIONS {
+        let (mut next_lat, mut next_lon, mut w) = (0.0, 0.0, 0.0);
+        for x in points {
+            // ~1mm, so that an estimate landing on a point doesn't divide by
+            // zero
+            let d = (x.lat - lat).hypot((x.lng - lon) * scale).max(1e-8);
+            next_lat += x.lat * x.weight / d;
+            next_lon += x.lng * x.weight / d;
+            w += x.weight / d;
+        }
+        let (next_lat, next_lon) = (next_lat / w, next_lon / w);
+        let moved = (next_lat - lat).hypot((next_lon - lon) * scale);
+        (lat, lon) = (next_lat, next_lon);
+        if moved < 1e-8 {
+            break;
+        }
+    }
+    (lat, lon)
+}
+
+/// Drop access points that don't overlap the area agreed on by the more
+/// precise half of them. A cloned or recently moved access point can still
+/// have a box small enough to be used, but it won't overlap the others.
+fn exclude_outliers(points: Vec<ContributingPoint>) -> Vec<ContributingPoint> {
+    if points.len() < 3 {
+        return points;
+    }
+
+    let rect = |x: &ContributingPoint| {
+        let (min, max) = Bounds::around(x.lat, x.lng, x.radius).points();
+        Rect::new(min, max)
+    };
+    let mut radii: Vec<_> = points.iter().map(|x| x.radius).collect();
+    radii.sort_by(f64::total_cmp);
+    let median = radii[(radii.len() - 1) / 2];
+
+    let Some(consensus) = points
+        .iter()
+        .filter(|x| x.radius <= median)
+        .map(rect)
+        .reduce(|a, b| {
+            let (a_min, a_max) = (a.min(), a.max());
+            let (b_min, b_max) = (b.min(), b.max());
+            Rect::new(
+                (a_min.x.min(b_min.x), a_min.y.min(b_min.y)),
+                (a_max.x.max(b_max.x), a_max.y.max(b_max.y)),
+            )
+        })
+    else {
+        return points;
+    };
+
+    points
+        .into_iter()
+        .filter(|x| rect(x).intersects(&consensus))
+        .collect()
+}
+
+/// Points within this many metres of each other count as the same cluster when
+/// weighting by density.
+const CLUSTER_DISTANCE: f64 = 100.0;
+
+/// Divide each point's weight by the square root of how many points (itself
+/// included) are clustered around it, so that dozens of access points in one
+/// building don't drown out the few elsewhere that narrow the fix down.
+fn weight_by_density(points: &mut [ContributingPoint]) {
+    let centers: Vec<_> = points.iter().map(|x| Point::new(x.lng, x.lat)).collect();
+    for (point, center) in points.iter_mut().zip(&centers) {
+        let nearby = centers
+            .iter()
+            .filter(|x| Haversine::distance(*center, **x) <= CLUSTER_DISTANCE)
+            .count();
+        point.weight /= (nearby as f64).sqrt();
+    }
+}
+
+/// The weighted root mean square distance in metres of the points from a fix.
+fn weighted_rms_distance(points: &[ContributingPoint], lat: f64, lon: f64) -> f64 {
+    let fix = Point::new(lon, lat);
+    let (mut sum, mut w) = (0.0, 0.0);
+    for x in points {
+        sum += Haversine::distance(fix, Point::new(x.lng, x.lat)).powi(2) * x.weight;
+        w += x.weight;
+    }
+    (sum / w).sqrt()
+}
+
+/// The weight of an access point without a usable signal strength, below
+/// that of the weakest reported signal, as it could have been anywhere in
+/// range.
+const UNKNOWN_SIGNAL_WEIGHT: f64 = 0.5;
+
+/// A usable signal strength. Unknown signals are weaker than any known one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Signal {
+    Unknown,
+    Dbm(i8),
+}
+
+/// How much an access point counts towards a fix by its normalized signal
+/// strength, from 1 at -80 dBm to ~4.2 at -50 dBm, up to `cap`.
+fn signal_weight(signal: Signal, cap: Option<f64>) -> f64 {
+    let weight = match signal {
+        Signal::Unknown => UNKNOWN_SIGNAL_WEIGHT,
+        Signal::Dbm(x) => ((1.0 / (x as f64 - 20.0).powi(2)) * 10000.0).powi(2),
+    };
+    cap.map_or(weight, |cap| weight.min(cap))
+}
+
+fn normalize_signal(signal: Option<i8>) -> Option<Signal> {
+    match signal {
+        // some clients send 0 when they don't know the signal strength, and
+        // it's far stronger than any real signal
+        None | Some(0) => Some(Signal::Unknown),
+        Some(-50..=0) => Some(Signal::Dbm(-50)),
+        Some(x) if (-80..-50).contains(&x) => Some(Signal::Dbm(x)),
+        // ..-80 => -80,
+        _ => None,
+    }
+}
+
+/// The access point with the strongest usable signal, preferring the first one
+/// given when there is a tie.
+fn strongest(aps: &[AccessPoint]) -> Option<&AccessPoint> {
+    aps.iter()
+        .filter_map(|x| Some((normalize_signal(x.signal_strength)?, x)))
+        .rev()
+        .max_by_key(|(signal, _)| *signal)
+        .map(|(_, x)| x)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::body::to_bytes;
+    use chrono::TimeZone;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::model::Seen;
+
+    #[test]
+    fn output_decimals() {
+        let config: GeolocateConfig = toml::from_str("output_decimals = 6").unwrap();
+        let res = LocationResponse::new(-27.123456789, 153.987654321, 100.0, &config);
+        assert_eq!(res.location.lat, -27.123457);
+        assert_eq!(res.location.lng, 153.987654);
+
+        let config: GeolocateConfig = toml::from_str("output_decimals = 4").unwrap();
+        let res = LocationResponse::new(-27.123456789, 153.987654321, 100.0, &config);
+        assert_eq!(res.location.lat, -27.1235);
+        assert_eq!(res.location.lng, 153.9877);
+    }
+
+    #[actix_web::test]
+    async fn contributing_points() {
+        let config: GeolocateConfig = toml::from_str("output_decimals = 4").unwrap();
+        let point = |lat, lng, radius, weight| ContributingPoint {
+            lat,
+            lng,
+            radius,
+            weight,
+        };
+        let points = vec![
+            point(-27.46, 153.02, 100.0, 1.0),
+            point(-27.47, 153.03, 200.0, 3.0),
+        ];
+
+        let (lat, lon, r) = weighted_average(&points);
+        assert!((lat - -27.4675).abs() < 1e-9);
+        assert!((lon - 153.0275).abs() < 1e-9);
+        assert_eq!(r, 175.0);
+
+        let body = |query: Value| {
+            let query: LocationQuery = serde_json::from_value(query).unwrap();
+            let res = LocationResponse::new(lat, lon, r, &config)
+                .contributors(points.clone(), &config)
+                .respond(&query)
+                .unwrap();
+            async move {
+                let body = to_bytes(res.into_body()).await.unwrap();
+                serde_json::from_slice::<Value>(&body).unwrap()
+            }
+        };
+        assert!(body(json!({})).await.get("points").is_none());
+        assert!(body(json!({})).await.get("sourceCount").is_none());
+        assert_eq!(
+            body(json!({ "source_count": true })).await["sourceCount"],
+            2
+        );
+        assert_eq!(
+            body(json!({ "points": true })).await["points"],
+            json!([
+                { "lat": -27.46, "lng": 153.02, "radius": 100.0, "weight": 1.0 },
+                { "lat": -27.47, "lng": 153.03, "radius": 200.0, "weight": 3.0 },
+            ])
+        );
+
+        // a single transmitter is its own contributor
+        let res = LocationResponse::new(-27.123456, 153.987654, 80.0, &config);
+        assert_eq!(res.contributors, [point(-27.1235, 153.9877, 80.0, 1.0)]);
+        let query = serde_json::from_value(json!({ "source_count": true })).unwrap();
+        let body = to_bytes(res.respond(&query).unwrap().into_body())
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["sourceCount"], 1);
+    }
+
+    #[test]
+    fn accuracy_models() {
+        let point = |lat, lng| ContributingPoint {
+            lat,
+            lng,
+            radius: 100.0,
+            weight: 1.0,
+        };
+        // within ~10m of each other, and spread over ~1km
+        let clustered = [point(-27.46, 153.02), point(-27.4601, 153.02)];
+        let scattered = [point(-27.46, 153.02), point(-27.47, 153.02)];
+
+        for (points, expected) in [(&clustered, 5.6), (&scattered, 556.0)] {
+            let (lat, lon, radius) = weighted_average(points);
+            // the stored radii say nothing about how far apart the points are
+            assert_eq!(radius, 100.0);
+            let rms = weighted_rms_distance(points, lat, lon);
+            assert!((rms - expected).abs() < 1.0, "{rms}");
+        }
+    }
+
+    #[test]
+    fn median_estimator() {
+        let point = |lat, lng| ContributingPoint {
+            lat,
+            lng,
+            radius: 100.0,
+            weight: 1.0,
+        };
+        let points = [
+            point(-27.46, 153.02),
+            point(-27.4601, 153.02),
+            point(-27.46, 153.0201),
+            // ~1.1km south
+            point(-27.47, 153.02),
+        ];
+        let distance =
+            |lat, lon| Haversine::distance(Point::new(153.02, -27.46), Point::new(lon, lat));
+
+        let (lat, lon, _) = weighted_average(&points);
+        assert!(distance(lat, lon) > 250.0);
+        let (lat, lon) = weighted_median(&points, lat, lon);
+        assert!(distance(lat, lon) < 15.0, "{}", distance(lat, lon));
+
+        // weights count as they do for the mean
+        let mut points = points;
+        points[3].weight = 10.0;
+        let (lat, lon) = weighted_median(&points, -27.46, 153.02);
+        assert!(distance(lat, lon) > 1000.0);
+    }
+
+    #[test]
+    fn cloned_access_point() {
+        let point = |lat, lng, radius| ContributingPoint {
+            lat,
+            lng,
+            radius,
+            weight: 1.0,
+        };
+        let building = vec![
+            point(-27.4600, 153.0200, 20.0),
+            point(-27.4601, 153.0201, 30.0),
+            point(-27.4599, 153.0199, 50.0),
+        ];
+        // ~1.1km away, with a box that doesn't reach the building
+        let cloned = point(-27.4700, 153.0200, 300.0);
+        // ~200m away, with a box that does
+        let nearby = point(-27.4620, 153.0200, 300.0);
+
+        let mut points = building.clone();
+        points.extend([cloned.clone(), nearby.clone()]);
+        let mut expected = building.clone();
+        expected.push(nearby);
+        assert_eq!(exclude_outliers(points), expected);
+
+        // too few to agree on anything
+        let points = vec![building[0].clone(), cloned];
+        assert_eq!(exclude_outliers(points.clone()), points);
+    }
+
+    #[test]
+    fn density_weighting() {
+        let point = |lat, lng| ContributingPoint {
+            lat,
+            lng,
+            radius: 50.0,
+            weight: 1.0,
+        };
+        // three access points in one building (~10m apart) and one ~1km away
+        let mut points = vec![
+            point(-27.4600, 153.0200),
+            point(-27.4601, 153.0200),
+            point(-27.4600, 153.0201),
+            point(-27.4700, 153.0200),
+        ];
+
+        let (plain, _, _) = weighted_average(&points);
+        weight_by_density(&mut points);
+        let (weighted, _, _) = weighted_average(&points);
+
+        // the cluster pulls the plain fix 3/4 of the way towards it
+        assert!((plain - -27.4625).abs() < 1e-4);
+        // but only sqrt(3)/(1+sqrt(3)) of the way once weighted
+        assert!(weighted < plain);
+        assert!((weighted - -27.4637).abs() < 1e-4, "{weighted}");
+        assert_eq!(points[3].weight, 1.0);
+    }
+
+    #[test]
+    fn signal_weight_cap() {
+        let point = |lat, signal, cap| ContributingPoint {
+            lat,
+            lng: 153.02,
+            radius: 50.0,
+            weight: signal_weight(signal, cap),
+        };
+        // three weak access points agree, one strong one is ~1km away
+        let fix = |cap| {
+            let points = [
+                point(-27.4600, Signal::Dbm(-80), cap),
+                point(-27.4600, Signal::Dbm(-80), cap),
+                point(-27.4600, Signal::Dbm(-80), cap),
+                point(-27.4700, Signal::Dbm(-50), cap),
+            ];
+            weighted_average(&points).0
+        };
+
+        assert_eq!(signal_weight(Signal::Dbm(-80), None), 1.0);
+        assert!((signal_weight(Signal::Dbm(-50), None) - 4.16).abs() < 0.01);
+        assert_eq!(signal_weight(Signal::Dbm(-50), Some(2.0)), 2.0);
+        assert_eq!(signal_weight(Signal::Dbm(-80), Some(2.0)), 1.0);
+
+        // uncapped, the strong one pulls the fix more than half way to it
+        assert!(fix(None) < -27.465, "{}", fix(None));
+        // capped, the fix stays with the weaker ones
+        assert!(
+            (fix(Some(2.0)) - -27.4640).abs() < 1e-4,
+            "{}",
+            fix(Some(2.0))
+        );
+    }
+
+    #[test]
+    fn unknown_signal() {
+        let weight = |x| signal_weight(normalize_signal(x).unwrap(), None);
+
+        // missing and 0 are both treated as unknown
+        assert_eq!(normalize_signal(None), Some(Signal::Unknown));
+        assert_eq!(normalize_signal(Some(0)), Some(Signal::Unknown));
+        assert_eq!(weight(None), weight(Some(0)));
+
+        // and count for less than even the weakest real signal
+        assert_eq!(normalize_signal(Some(-80)), Some(Signal::Dbm(-80)));
+        assert!(weight(None) < weight(Some(-80)));
+        assert!(weight(Some(-80)) < weight(Some(-65)));
+        assert!(weight(Some(-65)) < weight(Some(-50)));
+        assert_eq!(weight(Some(-10)), weight(Some(-50)));
+        assert_eq!(normalize_signal(Some(-90)), None);
+    }
+
+    #[test]
+    fn single_observation() {
+        let once = Bounds::new(-27.46, 153.02);
+        let twice = once + (-27.4601, 153.0201);
+
+        let config: GeolocateConfig = toml::from_str("").unwrap();
+        assert_eq!(LocationResponse::from_bounds(once, &config).accuracy, 50);
+
+        let config: GeolocateConfig = toml::from_str("single_observation_accuracy = 150").unwrap();
+        assert_eq!(LocationResponse::from_bounds(once, &config).accuracy, 150);
+        // bounds from several observations have a size of their own
+        assert_eq!(LocationResponse::from_bounds(twice, &config).accuracy, 50);
+        let wide = once + (-27.47, 153.03);
+        assert_eq!(LocationResponse::from_bounds(wide, &config).accuracy, 743);
+
+        // the accuracy of the positions it was seen from is a floor, rather
+        // than the default
+        let accuracy = |b, x| LocationResponse::from_stored(b, Some(x), &config).accuracy;
+        assert_eq!(accuracy(once, 100.0), 100);
+        assert_eq!(accuracy(once, 20.0), 50);
+        assert_eq!(accuracy(twice, 80.0), 80);
+        assert_eq!(accuracy(wide, 20.0), 743);
+    }
+
+    #[test]
+    fn web_mercator_points() {
+        let close =
+            |a: (f64, f64), b: (f64, f64)| (a.0 - b.0).abs() < 0.01 && (a.1 - b.1).abs() < 0.01;
+        assert!(close(web_mercator(0.0, 0.0), (0.0, 0.0)));
+        assert!(close(web_mercator(45.0, 180.0), (20037508.34, 5621521.49)));
+        assert!(close(
+            web_mercator(-27.4698, 153.0251),
+            (17034676.21, -3182290.67)
+        ));
+        // the edge of the map, which is square
+        assert!(close(
+            web_mercator(90.0, -180.0),
+            (-20037508.34, 20037508.34)
+        ));
+    }
+
+    #[test]
+    fn projected_response() {
+        let query = |x| serde_json::from_value::<LocationQuery>(x).unwrap();
+        let body = || json!({ "location": { "lat": -27.4698, "lng": 153.0251 }, "accuracy": 743 });
+
+        let mut res = body();
+        project(&mut res, None, &query(json!({})));
+        assert_eq!(res, body());
+
+        let mut res = body();
+        project(
+            &mut res,
+            None,
+            &query(json!({ "crs": "epsg:3857", "units": "feet" })),
+        );
+        assert_eq!(
+            res,
+            json!({
+                "location": { "x": 17034676.21, "y": -3182290.67 },
+                "accuracy": 2438,
+                "crs": "EPSG:3857",
+            })
+        );
+
+        assert!(serde_json::from_value::<LocationQuery>(json!({ "crs": "epsg:27700" })).is_err());
+    }
+
+    #[test]
+    fn bbox_shape() {
+        let query: LocationQuery = serde_json::from_value(json!({ "shape": "bbox" })).unwrap();
+        let encloses = |res: &Value, lat: f64, lng: f64| {
+            let bbox: Vec<_> = res["bbox"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|x| x.as_f64().unwrap())
+                .collect();
+            bbox[0] < lng && lng < bbox[2] && bbox[1] < lat && lat < bbox[3]
+        };
+
+        // a fix from several transmitters, so only a point and accuracy
+        let mut res = json!({ "location": { "lat": -27.4698, "lng": 153.0251 }, "accuracy": 743 });
+        project(&mut res, None, &query);
+        assert!(encloses(&res, -27.4698, 153.0251));
+        assert!(res.get("location").is_none() && res.get("accuracy").is_none());
+
+        // a single transmitter keeps its actual bounds, rounded like the point
+        let config: GeolocateConfig = toml::from_str("output_decimals = 4").unwrap();
+        let b = Bounds::new(-27.46001, 153.02001) + (-27.47001, 153.06001);
+        let res = LocationResponse::from_bounds(b, &config);
+        let (lat, lng) = (res.location.lat, res.location.lng);
+        let mut body = serde_json::to_value(&res).unwrap();
+        project(&mut body, res.bounds, &query);
+        assert!(encloses(&body, lat, lng));
+        assert_eq!(body["bbox"], json!([153.02, -27.47, 153.06, -27.46]));
+    }
+
+    #[test]
+    fn cell_span() {
+        let config: GeolocateConfig = toml::from_str("max_cell_span = 50000").unwrap();
+        let small = Bounds::new(-27.46, 153.02) + (-27.5, 153.06);
+        assert!(usable_cell(&small, &config));
+        // most of south east queensland
+        let huge = Bounds::new(-26.0, 152.0) + (-28.5, 154.0);
+        assert!(!usable_cell(&huge, &config));
+
+        let unlimited: GeolocateConfig = toml::from_str("").unwrap();
+        assert!(usable_cell(&huge, &unlimited));
+    }
+
+    #[test]
+    fn wifi_days() {
+        let config: GeolocateConfig = toml::from_str("min_wifi_days = 3").unwrap();
+        let t = |days| Utc.timestamp_opt(1_700_000_000, 0).unwrap() + Duration::days(days);
+        let wifi = |seen, pinned| WifiBounds {
+            bounds: Bounds::new(-27.4698, 153.0251),
+            seen,
+            pinned,
+        };
+        // seen on a handful of days
+        let sampled = Seen::new(t(0)).add(t(1)).add(t(4));
+        assert!(usable_wifi(&wifi(Some(sampled), false), &config));
+        // the same reading submitted over and over
+        let mut single = Seen::new(t(0));
+        for _ in 0..50 {
+            single = single.add(t(0));
+        }
+        assert!(!usable_wifi(&wifi(Some(single), false), &config));
+        // never counted, such as with h3 storage
+        assert!(!usable_wifi(&wifi(None, false), &config));
+        // placed by hand
+        assert!(usable_wifi(&wifi(None, true), &config));
+
+        let unlimited: GeolocateConfig = toml::from_str("").unwrap();
+        assert!(usable_wifi(&wifi(None, false), &unlimited));
+        assert!(usable_wifi(&wifi(Some(single), false), &unlimited));
+    }
+
+    #[test]
+    fn max_cells() {
+        let request = |n: i64| {
+            let cells: Vec<_> = (0..n)
+                .map(|cell_id| {
+                    json!({
+                        "radioType": "lte",
+                        "mobileCountryCode": 505,
+                        "mobileNetworkCode": 1,
+                        "locationAreaCode": 1,
+                        "cellId": cell_id,
+                    })
+                })
+                .collect();
+            serde_json::from_value::<LocationRequest>(json!({ "cellTowers": cells })).unwrap()
+        };
+
+        let config: GeolocateConfig = toml::from_str("max_cells = 3").unwrap();
+        let mut req = request(5);
+        assert!(req.limit_cells(&config));
+        let ids: Vec<_> = req.cell_towers.iter().map(|x| x.cell_id).collect();
+        assert_eq!(ids, [0, 1, 2]);
+
+        let mut req = request(3);
+        assert!(req.limit_cells(&config));
+        assert_eq!(req.cell_towers.len(), 3);
+
+        let config: GeolocateConfig =
+            toml::from_str("max_cells = 3\nreject_excess_cells = true").unwrap();
+        assert!(!request(4).limit_cells(&config));
+        assert!(request(3).limit_cells(&config));
+
+        // unlimited by default
+        let config: GeolocateConfig = toml::from_str("").unwrap();
+        assert!(request(1000).limit_cells(&config));
+    }
+
+    #[test]
+    fn mls_radios() {
+        let config: GeolocateConfig = toml::from_str("").unwrap();
+        assert!(uses_mls(CellRadio::Gsm, &config));
+        assert!(uses_mls(CellRadio::Lte, &config));
+
+        let config: GeolocateConfig = toml::from_str(r#"mls_radios = ["gsm"]"#).unwrap();
+        assert!(uses_mls(CellRadio::Gsm, &config));
+        assert!(!uses_mls(CellRadio::Wcdma, &config));
+        assert!(!uses_mls(CellRadio::Lte, &config));
+        assert!(!uses_mls(CellRadio::Nr, &config));
+
+        let config: GeolocateConfig = toml::from_str("mls_radios = []").unwrap();
+        assert!(!uses_mls(CellRadio::Gsm, &config));
+    }
+
+    #[test]
+    fn max_accuracy() {
+        let query = |x: Value| serde_json::from_value::<LocationQuery>(x).unwrap();
+        assert!(query(json!({})).accepts(25_000.0));
+
+        let strict = query(json!({ "max_accuracy": 200 }));
+        assert!(strict.accepts(50.0));
+        assert!(strict.accepts(200.0));
+        assert!(!strict.accepts(201.0));
+        assert!(!strict.accepts(25_000.0));
+    }
+
+    #[test]
+    fn max_returned_accuracy() {
+        let capped: GeolocateConfig = toml::from_str("max_returned_accuracy = 1000").unwrap();
+        let rejected: GeolocateConfig =
+            toml::from_str("max_returned_accuracy = 1000\nreject_inaccurate_fixes = true").unwrap();
+        let wide = Bounds::around(-27.46, 153.02, 5000.0);
+        let narrow = Bounds::around(-27.46, 153.02, 100.0);
+
+        let res = LocationResponse::from_bounds(wide, &capped)
+            .capped(&capped)
+            .unwrap();
+        assert_eq!(res.accuracy, 1000);
+        assert!(res.bounds.is_none());
+        let res = LocationResponse::from_bounds(narrow, &capped)
+            .capped(&capped)
+            .unwrap();
+        // the corner of the square
+        assert_eq!(res.accuracy, 141);
+        assert!(res.bounds.is_some());
+
+        assert!(LocationResponse::from_bounds(wide, &rejected)
+            .capped(&rejected)
+            .is_none());
+        assert!(LocationResponse::from_bounds(narrow, &rejected)
+            .capped(&rejected)
+            .is_some());
+
+        // responses from ip geolocation and upstream
+        let ipf = || json!({ "location": { "lat": -27.46, "lng": 153.02 }, "accuracy": 25000 });
+        let mut res = ipf();
+        assert!(cap_accuracy(&mut res, &capped));
+        assert_eq!(res["accuracy"], 1000);
+        assert!(!cap_accuracy(&mut ipf(), &rejected));
+        let mut res = ipf();
+        assert!(cap_accuracy(&mut res, &GeolocateConfig::default()));
+        assert_eq!(res, ipf());
+    }
+
+    #[test]
+    fn confidence() {
+        assert_eq!(Confidence::new(50, 3), Confidence::High);
+        assert_eq!(Confidence::new(99, 10), Confidence::High);
+        assert_eq!(Confidence::new(99, 2), Confidence::Medium);
+        assert_eq!(Confidence::new(100, 3), Confidence::Medium);
+        assert_eq!(Confidence::new(999, 1), Confidence::Medium);
+        assert_eq!(Confidence::new(1000, 10), Confidence::Low);
+        assert_eq!(Confidence::new(25_000, 1), Confidence::Low);
+    }
+
+    #[test]
+    fn strongest_ap() {
+        let ap = |mac: &str, signal_strength| AccessPoint {
+            mac_address: mac.parse().unwrap(),
+            signal_strength,
+        };
+
+        let aps = [
+            ap("00:00:00:00:00:01", Some(-75)),
+            ap("00:00:00:00:00:02", Some(-60)),
+            ap("00:00:00:00:00:03", Some(-90)),
+            ap("00:00:00:00:00:04", Some(-60)),
+            ap("00:00:00:00:00:05", None),
+        ];
+        let x = strongest(&aps).unwrap();
+        assert_eq!(x.mac_address, "00:00:00:00:00:02".parse().unwrap());
+
+        // a known weak signal beats an unknown one
+        let aps = [
+            ap("00:00:00:00:00:05", Some(0)),
+            ap("00:00:00:00:00:01", Some(-80)),
+        ];
+        let x = strongest(&aps).unwrap();
+        assert_eq!(x.mac_address, "00:00:00:00:00:01".parse().unwrap());
+
+        // too weak to be used at all
+        assert!(strongest(&[ap("00:00:00:00:00:03", Some(-90))]).is_none());
+        assert!(strongest(&[]).is_none());
+    }
+
+    #[test]
+    fn ip_fallback_precedence() {
+        let allows_ip = |consider_ip: Option<bool>, ipf: Option<bool>| {
+            let mut req = json!({});
+            if let Some(x) = consider_ip {
+                req["considerIp"] = json!(x);
+            }
+            if let Some(x) = ipf {
+                req["fallbacks"] = json!({ "ipf": x });
+            }
+            serde_json::from_value::<LocationRequest>(req)
+                .unwrap()
+                .allows_ip()
+        };
+
+        // (considerIp, fallbacks.ipf, allowed)
+        let cases = [
+            (Some(true), Some(true), true),
+            (Some(true), Some(false), false),
+            (Some(false), Some(true), false),
+            (Some(false), Some(false), false),
+            (None, None, true),
+            (None, Some(true), true),
+            (None, Some(false), false),
+            (Some(true), None, true),
+            (Some(false), None, false),
+        ];
+        for (consider_ip, ipf, allowed) in cases {
+            assert_eq!(
+                allows_ip(consider_ip, ipf),
+                allowed,
+                "{consider_ip:?} {ipf:?}"
+            );
+        }
+
+        // fallbacks without ipf
+        let req: LocationRequest =
+            serde_json::from_value(json!({ "fallbacks": { "h3": false } })).unwrap();
+        assert!(req.allows_ip());
+    }
+
+    #[test]
+    fn top_level_radio_type() {
+        let mut req: LocationRequest = serde_json::from_value(json!({
+            "radioType": "lte",
+            "cellTowers": [
+                {
+                    "mobileCountryCode": 505,
+                    "mobileNetworkCode": 1,
+                    "locationAreaCode": 12345,
+                    "cellId": 1234,
+                },
+                {
+                    "radioType": "gsm",
+                    "mobileCountryCode": 505,
+                    "mobileNetworkCode": 1,
+                    "locationAreaCode": 12345,
+                    "cellId": 1234,
+                },
+            ],
+        }))
+        .unwrap();
+        req.default_radio_type();
+        assert_eq!(req.cell_towers[0].radio_type, Some(CellRadio::Lte));
+        assert_eq!(req.cell_towers[1].radio_type, Some(CellRadio::Gsm));
+
+        // without either, the cell can't be looked up
+        let mut req: LocationRequest = serde_json::from_value(json!({
+            "cellTowers": [{
+                "mobileCountryCode": 505,
+                "mobileNetworkCode": 1,
+                "locationAreaCode": 12345,
+                "cellId": 1234,
+            }],
+        }))
+        .unwrap();
+        req.default_radio_type();
+        assert_eq!(req.cell_towers[0].radio_type, None);
+    }
+
+    #[test]
+    fn h3_fallback() {
+        use h3o::LatLng;
+
+        use crate::model::WIFI_RESOLUTION;
+
+        let config: GeolocateConfig = toml::from_str("output_decimals = 4").unwrap();
+        let cell = |lat, lon| {
+            let h3 = LatLng::new(lat, lon).unwrap().to_cell(WIFI_RESOLUTION);
+            u64::from(h3).to_be_bytes().to_vec()
+        };
+        let mac = |x: &str| x.parse::<MacAddress>().unwrap();
+
+        // only seen in a single cell
+        let rows = vec![
+            (mac("00:11:22:33:44:01"), cell(-27.47, 153.02)),
+            (mac("00:11:22:33:44:02"), cell(-27.47, 153.02)),
+            (mac("00:11:22:33:44:02"), cell(-27.50, 153.05)),
+            (mac("00:11:22:33:44:03"), vec![1, 2, 3]),
+        ];
+        let b = tightest_cells(rows).unwrap();
+        let res = LocationResponse::from_bounds(b, &config);
+        assert!((res.location.lat - -27.47).abs() < 0.001);
+        assert!((res.location.lng - 153.02).abs() < 0.001);
+        // about the size of the cell
+        assert!((50..150).contains(&res.accuracy), "{}", res.accuracy);
+
+        assert!(tightest_cells(vec![(mac("00:11:22:33:44:03"), vec![1, 2, 3])]).is_none());
+    }
+
+    #[actix_web::test]
+    async fn timeout() {
+        let slow = |ms| async move {
+            tokio::time::sleep(std::time::Duration::from_millis(ms)).await;
+            Ok(Some(ms))
+        };
+        let limit = Some(std::time::Duration::from_millis(50));
+
+        assert_eq!(with_timeout(slow(0), limit).await.unwrap(), Some(0));
+        assert_eq!(with_timeout(slow(100), None).await.unwrap(), Some(100));
+
+        let err = with_timeout(slow(1000), limit).await.err().unwrap();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            actix_web::http::StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
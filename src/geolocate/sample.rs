@@ -0,0 +1,44 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// logging every request is too much on a busy instance, so only every nth one
+// is logged, with the transmitters it asked about. counting rather than
+// picking at random keeps the rate exact, and requests arrive in no particular
+// order anyway.
+
+pub struct Sampler {
+    every: u64,
+    count: AtomicU64,
+}
+
+impl Sampler {
+    pub fn new(every: u64) -> Self {
+        Sampler {
+            every: every.max(1),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Whether this request should be logged, starting with the first.
+    pub fn sample(&self) -> bool {
+        self.count
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.every)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_in_n() {
+        let sampler = Sampler::new(10);
+        let sampled: Vec<_> = (0..1000).filter(|_| sampler.sample()).collect();
+        assert_eq!(sampled.len(), 100);
+        assert_eq!(sampled[..3], [0, 10, 20]);
+
+        // every request
+        let sampler = Sampler::new(0);
+        assert!((0..10).all(|_| sampler.sample()));
+    }
+}
@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+
+use super::LocationRequest;
+
+// only the transmitters sent by the client are forwarded upstream: ip based
+// fallbacks are always handled locally so that client addresses aren't shared
+// with another provider. upstream is itself a fallback, so clients that opt out
+// of those with considerIp or fallbacks.ipf are never forwarded
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn client() -> reqwest::Result<Client> {
+    Client::builder()
+        .timeout(TIMEOUT)
+        .user_agent(concat!("beacondb/", env!("CARGO_PKG_VERSION")))
+        .build()
+}
+
+/// Ask an upstream provider to locate the client, returning its response
+/// as-is if it was able to.
+pub(super) async fn locate(
+    client: &Client,
+    url: &str,
+    data: &LocationRequest,
+) -> Result<Option<Value>> {
+    if !data.allows_ip() || (data.cell_towers.is_empty() && data.wifi_access_points.is_empty()) {
+        return Ok(None);
+    }
+
+    let res = client
+        .post(url)
+        .json(&json!({
+            "cellTowers": data.cell_towers,
+            "wifiAccessPoints": data.wifi_access_points,
+            "considerIp": false,
+        }))
+        .send()
+        .await?;
+    if res.status() != StatusCode::OK {
+        return Ok(None);
+    }
+
+    Ok(Some(res.json().await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{web, App, HttpResponse, HttpServer};
+
+    use super::*;
+
+    async fn mock(data: web::Json<Value>) -> HttpResponse {
+        // only knows about a single access point
+        assert_eq!(data["considerIp"], false);
+        if data["wifiAccessPoints"][0]["macAddress"] == "00:11:22:33:44:55" {
+            HttpResponse::Ok().json(json!({
+                "location": { "lat": -27.5, "lng": 153.0 },
+                "accuracy": 30,
+            }))
+        } else {
+            HttpResponse::NotFound().finish()
+        }
+    }
+
+    fn request(mac: &str) -> LocationRequest {
+        serde_json::from_value(json!({
+            "wifiAccessPoints": [{ "macAddress": mac, "signalStrength": -60 }],
+        }))
+        .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn mock_upstream() {
+        let server = HttpServer::new(|| App::new().route("/", web::post().to(mock)))
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let url = format!("http://{}/", server.addrs()[0]);
+        actix_web::rt::spawn(server.run());
+
+        let client = client().unwrap();
+        let res = locate(&client, &url, &request("00:11:22:33:44:55"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(res["location"]["lat"], -27.5);
+        assert_eq!(res["accuracy"], 30);
+
+        let res = locate(&client, &url, &request("00:11:22:33:44:66"))
+            .await
+            .unwrap();
+        assert!(res.is_none());
+
+        let res = locate(&client, &url, &LocationRequest::default())
+            .await
+            .unwrap();
+        assert!(res.is_none());
+
+        // known upstream, but the client opted out of fallbacks
+        let data = serde_json::from_value(json!({
+            "wifiAccessPoints": [{ "macAddress": "00:11:22:33:44:55", "signalStrength": -60 }],
+            "considerIp": false,
+        }))
+        .unwrap();
+        let res = locate(&client, &url, &data).await.unwrap();
+        assert!(res.is_none());
+    }
+}
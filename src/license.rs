@@ -0,0 +1,37 @@
+use actix_web::{
+    http::header::{HeaderName, HeaderValue},
+    HttpResponse,
+};
+
+// the licenses of the data a response was built from are also sent as
+// headers, so that attribution isn't lost whatever the client keeps of the
+// body. each applicable license is sent as its own header.
+
+pub const HEADER: HeaderName = HeaderName::from_static("x-data-license");
+
+/// Add a license to those sent with a response. Licenses that can't be sent
+/// in a header, such as ones containing line breaks, are left out.
+pub fn append(res: &mut HttpResponse, license: &str) {
+    if let Ok(value) = HeaderValue::from_str(license) {
+        res.headers_mut().append(HEADER, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiple_licenses() {
+        let mut res = HttpResponse::Ok().finish();
+        append(&mut res, crate::geoip::LICENSE);
+        append(&mut res, "https://beacondb.net/privacy/");
+        append(&mut res, "multiple\nlines");
+
+        let licenses: Vec<_> = res.headers().get_all(HEADER).collect();
+        assert_eq!(
+            licenses,
+            [crate::geoip::LICENSE, "https://beacondb.net/privacy/"]
+        );
+    }
+}
@@ -1,20 +1,31 @@
 use std::{
+    env, fs,
+    io::ErrorKind,
+    os::unix::{fs::FileTypeExt, net::UnixStream},
     path::{Path, PathBuf},
     sync::Arc,
 };
 
-use actix_web::{web, App, HttpServer};
-use anyhow::Result;
+use actix_web::{web, App, HttpServer, Scope};
+use anyhow::{bail, Result};
 use clap::{Parser, Subcommand};
 use sqlx::PgPool;
 
 mod bounds;
+mod capabilities;
+mod cell_sites;
+mod check_schema;
 mod config;
+mod db_info;
 mod geoip;
 mod geolocate;
+mod license;
 mod map;
 mod mls;
 mod model;
+mod not_found;
+mod repair;
+mod request_id;
 mod submission;
 
 #[derive(Debug, Parser)]
@@ -30,45 +41,277 @@ struct Cli {
 enum Command {
     Serve,
     Process,
-    Map,
+    /// Delete a contributor's reports and rebuild the transmitters they affected
+    Purge {
+        #[arg(long)]
+        user_agent: Option<String>,
+        #[arg(long)]
+        token_id: Option<i32>,
+        /// Purge even though reports archived by retention will no longer
+        /// count towards the affected transmitters
+        #[arg(long)]
+        discard_archived: bool,
+    },
+    Map {
+        /// Print one feature per country, tagged with its country code
+        #[arg(long)]
+        per_country: bool,
+    },
+    /// Remove map cells that no remaining report or transmitter is in, such as
+    /// after a purge. Coverage from reports moved out by retention is lost
+    /// once no transmitter is centred in its cell either
+    PruneMap,
+    /// Insert reports from a file (or stdin) with one geosubmit report per line
+    Ingest {
+        path: Option<PathBuf>,
+        /// Stored with each report, so that an import can be purged later
+        #[arg(long)]
+        user_agent: Option<String>,
+        /// Reports inserted per transaction
+        #[arg(long, default_value_t = 10_000)]
+        batch_size: usize,
+        /// Read a whole geosubmit body (`{"items": [...]}`), such as a
+        /// NeoStumbler export, instead of one report per line
+        #[arg(long)]
+        geosubmit: bool,
+    },
     FormatMls,
     ImportGeoip,
+    /// Write the imported IP geolocation data back out as CSV
+    ExportGeoip,
+    /// List recent reports that look spoofed, for manual review
+    Suspects {
+        /// How far back to look
+        #[arg(long, default_value_t = 24)]
+        hours: i64,
+    },
+    /// Fix transmitters stored with their corners swapped
+    Repair,
+    /// Move old processed reports into an archive file, as configured in
+    /// `[retention]`
+    Retention,
+    /// List groups of co-located cells of different radios
+    CellSites,
+    /// Check that the saved query data in .sqlx matches the schema the
+    /// migrations create. Best run against a throwaway database
+    CheckSchema {
+        #[arg(long, default_value = ".sqlx")]
+        dir: PathBuf,
+    },
+    /// Show the size of each table
+    DbInfo {
+        /// Run `vacuum (analyze)` first
+        #[arg(long)]
+        vacuum: bool,
+    },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    let search_paths = config::search_paths(
+        env::var_os("XDG_CONFIG_HOME").map(PathBuf::from),
+        env::var_os("HOME").map(PathBuf::from),
+    );
     let path = match cli.config.as_deref() {
         Some(x) => x,
-        None => Path::new("config.toml"),
+        None => config::find(&search_paths)?,
     };
-    let config = config::load(path)?;
+    let config = web::Data::new(config::load(path)?);
 
     let pool = PgPool::connect(&config.database_url).await?;
     sqlx::migrate!().run(&pool).await?;
 
     match cli.command {
         Command::Serve => {
-            HttpServer::new(move || {
-                App::new()
-                    .app_data(web::Data::new(pool.clone()))
+            let port = config.http_port;
+            let socket = config.unix_socket.clone();
+            let client = web::Data::new(geolocate::upstream::client()?);
+            let mls_pool = web::Data::new(mls::MlsPool::new(&config, &pool)?);
+            let cache = config
+                .geolocate
+                .cache
+                .as_ref()
+                .map(|x| web::Data::new(geolocate::cache::Cache::new(x)));
+            let sampler = config
+                .geolocate
+                .log_every
+                .map(|x| web::Data::new(geolocate::sample::Sampler::new(x)));
+            let insert_batch_size = config
+                .insert_batch_size
+                .unwrap_or(submission::geosubmit::INSERT_BATCH_SIZE);
+            let buffer = config.submission_buffer.as_ref().map(|x| {
+                web::Data::new(submission::buffer::Buffer::spawn(
+                    pool.clone(),
+                    x,
+                    insert_batch_size,
+                ))
+            });
+            let queued = buffer.clone();
+            let server = HttpServer::new(move || {
+                let mut app = App::new();
+                if let Some(buffer) = &buffer {
+                    app = app.app_data(buffer.clone());
+                }
+                if let Some(cache) = &cache {
+                    app = app.app_data(cache.clone());
+                }
+                if let Some(sampler) = &sampler {
+                    app = app.app_data(sampler.clone());
+                }
+
+                app.app_data(web::Data::new(pool.clone()))
+                    .app_data(config.clone())
+                    .app_data(client.clone())
+                    .app_data(mls_pool.clone())
                     .app_data(web::JsonConfig::default().limit(500 * 1024 * 1024))
-                    .service(geoip::country_service)
-                    .service(geolocate::service)
-                    .service(submission::geosubmit::service)
-            })
-            .bind(("0.0.0.0", config.http_port))?
-            .run()
-            .await?;
+                    .wrap_fn(request_id::middleware)
+                    .service(routes(&config.prefix()))
+            });
+            let server = match &socket {
+                Some(path) => {
+                    remove_stale_socket(path)?;
+                    server.bind_uds(path)?
+                }
+                None => server.bind(("0.0.0.0", port))?,
+            };
+            let res = server.run().await;
+            if let Some(buffer) = queued {
+                buffer.drain().await;
+            }
+            res?;
         }
 
-        Command::Process => submission::process::run(pool, config.stats.as_ref()).await?,
-        Command::Map => map::run(pool).await?,
+        Command::Process => submission::process::run(pool, &config).await?,
+        Command::Purge {
+            user_agent,
+            token_id,
+            discard_archived,
+        } => submission::purge::run(pool, &config, user_agent, token_id, discard_archived).await?,
+        Command::Map { per_country: false } => map::run(pool).await?,
+        Command::Map { per_country: true } => map::per_country(pool).await?,
+        Command::PruneMap => map::prune(pool).await?,
+        Command::Ingest {
+            path,
+            user_agent,
+            batch_size,
+            geosubmit,
+        } => {
+            submission::ingest::run(
+                pool,
+                path.as_deref(),
+                user_agent.as_deref(),
+                batch_size,
+                geosubmit,
+                &config,
+            )
+            .await?
+        }
 
         Command::ImportGeoip => geoip::import::run(pool).await?,
+        Command::ExportGeoip => geoip::export::run(pool).await?,
         Command::FormatMls => mls::format()?,
+        Command::Suspects { hours } => submission::suspect::run(pool, hours).await?,
+        Command::Repair => repair::run(pool).await?,
+        Command::Retention => match &config.retention {
+            Some(x) => submission::retention::run(pool, x).await?,
+            None => bail!("retention is not configured"),
+        },
+        Command::CellSites => cell_sites::run(pool).await?,
+        Command::DbInfo { vacuum } => db_info::run(pool, vacuum).await?,
+        Command::CheckSchema { dir } => check_schema::run(pool, &dir).await?,
     };
 
     Ok(())
 }
+
+/// Remove a socket left behind by a previous run, as binding would fail while
+/// it exists. Anything other than a socket at the path is left alone, as is a
+/// socket that another instance is still listening on.
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(x) if x.file_type().is_socket() => match UnixStream::connect(path) {
+            Ok(_) => bail!("{} is in use by another process", path.display()),
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+            Err(e) => return Err(e.into()),
+        },
+        Ok(_) => bail!("{} exists and is not a socket", path.display()),
+        Err(_) => (),
+    }
+    Ok(())
+}
+
+/// Every route, under the configured path prefix.
+fn routes(prefix: &str) -> Scope {
+    web::scope(prefix)
+        .service(capabilities::service)
+        .service(geoip::country_service)
+        .service(geolocate::service)
+        .service(submission::geosubmit::service)
+        .service(submission::geosubmit::validate)
+        .service(submission::correct::service)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, init_service, read_body_json, TestRequest},
+    };
+    use serde_json::Value;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn path_prefix() {
+        let config: config::Config =
+            toml::from_str("database_url = ''\nhttp_port = 0\npath_prefix = '/beacondb/'").unwrap();
+        assert_eq!(config.prefix(), "/beacondb");
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(config))
+                .service(routes("/beacondb")),
+        )
+        .await;
+
+        let res = call_service(&app, TestRequest::get().uri("/v1").to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        let res = call_service(&app, TestRequest::get().uri("/beacondb/v1").to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: Value = read_body_json(res).await;
+        assert!(body["endpoints"]
+            .as_array()
+            .unwrap()
+            .contains(&"/beacondb/v1/geolocate".into()));
+    }
+
+    #[test]
+    fn stale_socket() {
+        let path = env::temp_dir().join(format!("beacondb-test-{}.sock", std::process::id()));
+
+        // nothing there yet
+        remove_stale_socket(&path).unwrap();
+
+        drop(UnixListener::bind(&path).unwrap());
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+        UnixListener::bind(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // still being listened on
+        let listener = UnixListener::bind(&path).unwrap();
+        assert!(remove_stale_socket(&path).is_err());
+        assert!(path.exists());
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+
+        fs::write(&path, "not a socket").unwrap();
+        assert!(remove_stale_socket(&path).is_err());
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,12 +1,18 @@
-use std::{collections::BTreeSet, fs, io};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+};
 
 use anyhow::Result;
 use futures::TryStreamExt;
 use geo_types::MultiPolygon;
-use geojson::{FeatureCollection, Geometry};
+use geojson::{Feature, FeatureCollection, Geometry, JsonObject};
 use h3o::{geom::dissolve, CellIndex, LatLng, Resolution};
+use serde_json::json;
 use sqlx::{query, query_scalar, PgPool};
 
+use crate::geoip::{mcc, Country};
+
 pub const RESOLUTION: Resolution = Resolution::Eight;
 
 pub async fn run(pool: PgPool) -> Result<()> {
@@ -32,9 +38,173 @@ pub async fn run(pool: PgPool) -> Result<()> {
         features,
         foreign_members: None,
     };
-    println!("{}", coll.to_string());
+    println!("{coll}");
 
     tx.commit().await?;
 
     Ok(())
 }
+
+/// Print the coverage of each country as a single feature tagged with its
+/// country code. Only cell towers carry a country, so this is where they are,
+/// placed by their mobile country code. Codes covering several territories
+/// are counted under the first, which is the main country.
+pub async fn per_country(pool: PgPool) -> Result<()> {
+    let mut cells = Vec::new();
+    let mut q = query!(
+        r#"select country, (min_lat + max_lat) / 2 as "lat!", (min_lon + max_lon) / 2 as "lon!" from cell"#
+    )
+    .fetch(&pool);
+    while let Some(x) = q.try_next().await? {
+        cells.push((x.country, LatLng::new(x.lat, x.lon)?));
+    }
+    drop(q);
+
+    let coll = FeatureCollection {
+        bbox: None,
+        features: country_features(cells)?,
+        foreign_members: None,
+    };
+    println!("{coll}");
+
+    Ok(())
+}
+
+fn country_features(cells: impl IntoIterator<Item = (i16, LatLng)>) -> Result<Vec<Feature>> {
+    let mut countries: BTreeMap<Country, BTreeSet<CellIndex>> = BTreeMap::new();
+    for (mcc, position) in cells {
+        if let Some(country) = mcc::countries(mcc).first() {
+            countries
+                .entry(*country)
+                .or_default()
+                .insert(position.to_cell(RESOLUTION));
+        }
+    }
+
+    let mut features = Vec::new();
+    for (country, cells) in countries {
+        let poly = dissolve(cells)?;
+        let mut properties = JsonObject::new();
+        properties.insert("country".to_owned(), json!(country.as_ref()));
+        features.push(Feature {
+            bbox: None,
+            geometry: Some(Geometry::new((&poly).into())),
+            id: None,
+            properties: Some(properties),
+            foreign_members: None,
+        });
+    }
+    Ok(features)
+}
+
+/// Delete map cells that no remaining report was made in, such as after
+/// reports were purged. Cells that a transmitter is still centred in are kept
+/// too, so coverage from reports moved out by retention stays while their
+/// transmitters do.
+pub async fn prune(pool: PgPool) -> Result<()> {
+    let mut valid = BTreeSet::new();
+    let mut q = query!("select latitude, longitude from report").fetch(&pool);
+    while let Some(x) = q.try_next().await? {
+        valid.insert(LatLng::new(x.latitude, x.longitude)?.to_cell(RESOLUTION));
+    }
+    drop(q);
+    let mut q = query!(
+        r#"select (min_lat + max_lat) / 2 as "lat!", (min_lon + max_lon) / 2 as "lon!" from wifi
+           union all select (min_lat + max_lat) / 2, (min_lon + max_lon) / 2 from cell
+           union all select (min_lat + max_lat) / 2, (min_lon + max_lon) / 2 from bluetooth
+           union all select (min_lat + max_lat) / 2, (min_lon + max_lon) / 2 from beacon"#
+    )
+    .fetch(&pool);
+    while let Some(x) = q.try_next().await? {
+        valid.insert(LatLng::new(x.lat, x.lon)?.to_cell(RESOLUTION));
+    }
+    drop(q);
+    let mut q = query_scalar!("select distinct h3 from wifi_h3").fetch(&pool);
+    while let Some(x) = q.try_next().await? {
+        if let Some(parent) = crate::model::h3_cell(&x).and_then(|x| x.parent(RESOLUTION)) {
+            valid.insert(parent);
+        }
+    }
+    drop(q);
+
+    let existing: Vec<Vec<u8>> = query_scalar!("select h3 from map").fetch_all(&pool).await?;
+    let orphans: Vec<Vec<u8>> = orphans(&existing, &valid)
+        .into_iter()
+        .map(|x| u64::from(x).to_be_bytes().to_vec())
+        .collect();
+
+    query!("delete from map where h3 = any($1)", &orphans)
+        .execute(&pool)
+        .await?;
+    eprintln!("pruned {} of {} map cells", orphans.len(), existing.len());
+
+    Ok(())
+}
+
+/// The stored map cells that aren't in `valid`.
+fn orphans(existing: &[Vec<u8>], valid: &BTreeSet<CellIndex>) -> Vec<CellIndex> {
+    existing
+        .iter()
+        .filter_map(|x| crate::model::h3_cell(x))
+        .filter(|x| !valid.contains(x))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn countries() {
+        use geo::Contains;
+
+        let brisbane = LatLng::new(-27.47, 153.02).unwrap();
+        let sydney = LatLng::new(-33.87, 151.21).unwrap();
+        let auckland = LatLng::new(-36.85, 174.76).unwrap();
+        let cells = [
+            (505, brisbane),
+            (505, sydney),
+            // a second tower in the same h3 cell
+            (505, LatLng::new(-27.4701, 153.0201).unwrap()),
+            (530, auckland),
+            // unassigned
+            (1, LatLng::new(10.0, 20.0).unwrap()),
+        ];
+
+        let features = country_features(cells).unwrap();
+        assert_eq!(features.len(), 2);
+        let tagged: Vec<_> = features
+            .iter()
+            .map(|x| x.property("country").unwrap().as_str().unwrap())
+            .collect();
+        assert_eq!(tagged, ["AU", "NZ"]);
+
+        let area =
+            |x: &Feature| MultiPolygon::<f64>::try_from(x.geometry.clone().unwrap().value).unwrap();
+        let point = |x: LatLng| geo::Point::new(x.lng(), x.lat());
+        let au = area(&features[0]);
+        assert_eq!(au.0.len(), 2);
+        assert!(au.contains(&point(brisbane)));
+        assert!(au.contains(&point(sydney)));
+        assert!(!au.contains(&point(auckland)));
+        assert!(area(&features[1]).contains(&point(auckland)));
+    }
+
+    #[test]
+    fn orphaned_cells() {
+        let cell = |lat, lon| LatLng::new(lat, lon).unwrap().to_cell(RESOLUTION);
+        let kept = cell(-27.47, 153.02);
+        let deleted = cell(-33.87, 151.21);
+        let existing: Vec<_> = [kept, deleted]
+            .iter()
+            .map(|x| u64::from(*x).to_be_bytes().to_vec())
+            .collect();
+
+        // only the first transmitter is left
+        let valid = BTreeSet::from([cell(-27.4701, 153.0201)]);
+        assert_eq!(orphans(&existing, &valid), [deleted]);
+
+        let valid = BTreeSet::from([kept, deleted]);
+        assert_eq!(orphans(&existing, &valid), []);
+    }
+}
@@ -2,9 +2,22 @@ use std::io;
 
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use sqlx::query;
+use sqlx::{query, PgPool};
 
-use crate::model::CellRadio;
+use crate::{config::Config, model::CellRadio};
+
+/// The pool used for MLS fallback lookups, which may be a separate database
+/// from the one beaconDB writes to.
+pub struct MlsPool(pub PgPool);
+
+impl MlsPool {
+    pub fn new(config: &Config, pool: &PgPool) -> Result<Self> {
+        Ok(MlsPool(match &config.mls_database_url {
+            Some(url) => PgPool::connect_lazy(url)?,
+            None => pool.clone(),
+        }))
+    }
+}
 
 #[derive(Debug, Deserialize, Serialize)]
 struct Record {
@@ -58,3 +71,27 @@ pub fn format() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn configured_pool() {
+        let mut config: Config = toml::from_str(
+            r#"
+            database_url = "postgres://localhost/beacondb"
+            http_port = 0
+            "#,
+        )
+        .unwrap();
+        let pool = PgPool::connect_lazy(&config.database_url).unwrap();
+
+        let mls = MlsPool::new(&config, &pool).unwrap();
+        assert_eq!(mls.0.connect_options().get_database(), Some("beacondb"));
+
+        config.mls_database_url = Some("postgres://localhost/mls".to_owned());
+        let mls = MlsPool::new(&config, &pool).unwrap();
+        assert_eq!(mls.0.connect_options().get_database(), Some("mls"));
+    }
+}
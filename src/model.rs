@@ -1,8 +1,14 @@
+use chrono::{DateTime, Duration, Utc};
+use h3o::{CellIndex, Resolution};
 use mac_address::MacAddress;
-use serde::Deserialize;
-use sqlx::{query_as, PgPool};
+use serde::{Deserialize, Serialize};
+use sqlx::{query, query_as, query_scalar, PgConnection, PgPool};
 
-use crate::bounds::Bounds;
+use crate::{bounds::Bounds, config::WifiStorage};
+
+// resolution used when only storing the h3 cells an access point was seen in.
+// cells have an edge length of ~66m
+pub const WIFI_RESOLUTION: Resolution = Resolution::Ten;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Transmitter {
@@ -21,9 +27,46 @@ pub enum Transmitter {
     Bluetooth {
         mac: MacAddress,
     },
+    Beacon {
+        id: BeaconId,
+    },
+}
+
+/// The identity a bluetooth beacon advertises. Unlike its mac address, which
+/// many beacons rotate, it only changes when the beacon is set up again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BeaconId {
+    IBeacon {
+        uuid: [u8; 16],
+        major: u16,
+        minor: u16,
+    },
+    /// An Eddystone-UID beacon
+    Eddystone {
+        namespace: [u8; 10],
+        instance: [u8; 6],
+    },
+}
+
+impl BeaconId {
+    /// The protocol and id columns of the `beacon` table.
+    pub fn columns(&self) -> (i16, Vec<u8>) {
+        match self {
+            BeaconId::IBeacon { uuid, major, minor } => (
+                1,
+                [&uuid[..], &major.to_be_bytes(), &minor.to_be_bytes()].concat(),
+            ),
+            BeaconId::Eddystone {
+                namespace,
+                instance,
+            } => (2, [&namespace[..], &instance[..]].concat()),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, sqlx::Type)]
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize, sqlx::Type,
+)]
 #[serde(rename_all = "lowercase")]
 #[repr(i16)]
 pub enum CellRadio {
@@ -33,9 +76,151 @@ pub enum CellRadio {
     Nr = 5,
 }
 
+impl CellRadio {
+    /// Every cell id sharing a site with the given cell, when the radio's cell
+    /// ids identify one. LTE cell ids are a 20 bit eNodeB id followed by an
+    /// 8 bit sector, so sectors of the same eNodeB share a position. The
+    /// other radios either don't encode a site, or (for NR) don't use a fixed
+    /// split between the gNodeB id and the cell.
+    pub fn site_cells(self, cell: i64) -> Option<(i64, i64)> {
+        match self {
+            CellRadio::Lte if (0..1 << 28).contains(&cell) => {
+                let enodeb = cell >> 8;
+                Some((enodeb << 8, enodeb << 8 | 0xff))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The earliest and latest report timestamps a transmitter was seen in, how
+/// many different days (in UTC) those reports were on, and the accuracy of the
+/// least precise position it was seen from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Seen {
+    pub first: DateTime<Utc>,
+    pub last: DateTime<Utc>,
+    pub days: i32,
+    /// Metres. A transmitter can't be placed more precisely than this
+    pub accuracy: Option<f64>,
+}
+
+impl Seen {
+    pub fn new(timestamp: DateTime<Utc>) -> Self {
+        Self {
+            first: timestamp,
+            last: timestamp,
+            days: 1,
+            accuracy: None,
+        }
+    }
+
+    /// Only a day before the first or after the last is known to be new, so
+    /// one that falls in between isn't counted. The count can come up short
+    /// when reports arrive out of order, but never counts a day twice.
+    pub fn add(self, timestamp: DateTime<Utc>) -> Self {
+        let day = timestamp.date_naive();
+        let new_day = day < self.first.date_naive() || day > self.last.date_naive();
+        Self {
+            first: self.first.min(timestamp),
+            last: self.last.max(timestamp),
+            days: self.days + i32::from(new_day),
+            ..self
+        }
+    }
+
+    /// Fold in the accuracy of a position the transmitter was seen from.
+    pub fn with_accuracy(self, accuracy: Option<f64>) -> Self {
+        let accuracy = accuracy.filter(|x| x.is_finite() && *x > 0.0);
+        Self {
+            accuracy: match (self.accuracy, accuracy) {
+                (Some(a), Some(b)) => Some(a.max(b)),
+                (a, b) => a.or(b),
+            },
+            ..self
+        }
+    }
+
+    /// Transmitters processed before first/last seen were recorded have
+    /// neither.
+    pub fn from_columns(
+        first: Option<DateTime<Utc>>,
+        last: Option<DateTime<Utc>>,
+        days: Option<i32>,
+        accuracy: Option<f64>,
+    ) -> Option<Self> {
+        Some(Self {
+            first: first?,
+            last: last?,
+            days: days?,
+            accuracy,
+        })
+    }
+}
+
+/// Which transmitters may contribute to a fix, based on when they were seen.
+/// Transmitters without first/last seen times are always allowed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SeenFilter {
+    /// The fewest different days a transmitter must have been seen on
+    pub min_days: i32,
+    /// Transmitters not seen since this are considered stale
+    pub not_before: Option<DateTime<Utc>>,
+}
+
+/// A transmitter's bounds along with when it was seen, as stored.
+pub struct SeenBounds {
+    pub min_lat: f64,
+    pub min_lon: f64,
+    pub max_lat: f64,
+    pub max_lon: f64,
+    pub first_seen: Option<DateTime<Utc>>,
+    pub last_seen: Option<DateTime<Utc>>,
+    pub days_seen: Option<i32>,
+    pub accuracy: Option<f64>,
+}
+
+impl SeenBounds {
+    pub fn split(self) -> (Bounds, Option<Seen>) {
+        let seen = Seen::from_columns(
+            self.first_seen,
+            self.last_seen,
+            self.days_seen,
+            self.accuracy,
+        );
+        let b = Bounds {
+            min_lat: self.min_lat,
+            min_lon: self.min_lon,
+            max_lat: self.max_lat,
+            max_lon: self.max_lon,
+        };
+        (b, seen)
+    }
+
+    pub fn allowed_by(self, filter: SeenFilter) -> Option<Bounds> {
+        self.allowed_with_seen(filter).map(|(b, _)| b)
+    }
+
+    /// The same as [`SeenBounds::allowed_by`], keeping when it was seen.
+    pub fn allowed_with_seen(self, filter: SeenFilter) -> Option<(Bounds, Option<Seen>)> {
+        let (b, seen) = self.split();
+        filter.allows(seen).then_some((b, seen))
+    }
+}
+
+impl SeenFilter {
+    pub fn allows(&self, seen: Option<Seen>) -> bool {
+        match seen {
+            Some(x) => x.days >= self.min_days && self.not_before.is_none_or(|t| x.last >= t),
+            None => true,
+        }
+    }
+}
+
 impl Transmitter {
-    pub async fn lookup(&self, pool: &PgPool) -> sqlx::Result<Option<Bounds>> {
-        let bounds = match self {
+    /// The stored bounds of a transmitter, along with when it was seen.
+    pub async fn lookup(&self, pool: &PgPool) -> sqlx::Result<Option<SeenBounds>> {
+        let row = match self {
             Transmitter::Cell {
                 radio,
                 country,
@@ -45,15 +230,15 @@ impl Transmitter {
                 unit,
             } => {
                 query_as!(
-                    Bounds,
-                    "select min_lat, min_lon, max_lat, max_lon from cell where radio = $1 and country = $2 and network = $3 and area = $4 and cell = $5 and unit = $6",
+                    SeenBounds,
+                    "select min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy from cell where radio = $1 and country = $2 and network = $3 and area = $4 and cell = $5 and unit = $6",
                     *radio as i16, country, network, area, cell, unit
                 ).fetch_optional(pool).await?
             }
             Transmitter::Wifi { mac } => {
                 query_as!(
-                    Bounds,
-                    "select min_lat, min_lon, max_lat, max_lon from wifi where mac = $1",
+                    SeenBounds,
+                    "select min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy from wifi where mac = $1",
                     mac
                 )
                 .fetch_optional(pool)
@@ -61,15 +246,537 @@ impl Transmitter {
             }
             Transmitter::Bluetooth { mac } => {
                 query_as!(
-                    Bounds,
-                    "select min_lat, min_lon, max_lat, max_lon from wifi where mac = $1",
+                    SeenBounds,
+                    "select min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy from bluetooth where mac = $1",
                     mac
                 )
                 .fetch_optional(pool)
                 .await?
             }
+            Transmitter::Beacon { id } => {
+                let (protocol, id) = id.columns();
+                query_as!(
+                    SeenBounds,
+                    "select min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy from beacon where protocol = $1 and id = $2",
+                    protocol,
+                    id
+                )
+                .fetch_optional(pool)
+                .await?
+            }
         };
 
-        Ok(bounds)
+        Ok(row)
+    }
+
+    /// Replace what's stored for a transmitter, such as when it's rebuilt from
+    /// scratch. Unlike processing, nothing already stored is kept.
+    pub async fn save(&self, b: &Bounds, seen: Seen, conn: &mut PgConnection) -> sqlx::Result<()> {
+        let Seen {
+            first: first_seen,
+            last: last_seen,
+            days: days_seen,
+            accuracy,
+        } = seen;
+
+        match self {
+            Transmitter::Cell {
+                radio,
+                country,
+                network,
+                area,
+                cell,
+                unit,
+            } => {
+                query!(
+                    "insert into cell (radio, country, network, area, cell, unit, min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14)
+                     on conflict (radio, country, network, area, cell, unit) do update set min_lat = EXCLUDED.min_lat, min_lon = EXCLUDED.min_lon, max_lat = EXCLUDED.max_lat, max_lon = EXCLUDED.max_lon,
+                        first_seen = EXCLUDED.first_seen, last_seen = EXCLUDED.last_seen,
+                        days_seen = EXCLUDED.days_seen, accuracy = EXCLUDED.accuracy
+                    ",
+                    *radio as i16, country, network, area, cell, unit, b.min_lat, b.min_lon, b.max_lat, b.max_lon, first_seen, last_seen, days_seen, accuracy
+                )
+                .execute(conn)
+                .await?;
+            }
+            Transmitter::Wifi { mac } => {
+                query!(
+                    "insert into wifi (mac, min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy) values ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                     on conflict (mac) do update set min_lat = EXCLUDED.min_lat, min_lon = EXCLUDED.min_lon, max_lat = EXCLUDED.max_lat, max_lon = EXCLUDED.max_lon,
+                        first_seen = EXCLUDED.first_seen, last_seen = EXCLUDED.last_seen,
+                        days_seen = EXCLUDED.days_seen, accuracy = EXCLUDED.accuracy
+                     where not wifi.pinned
+                    ",
+                    mac, b.min_lat, b.min_lon, b.max_lat, b.max_lon, first_seen, last_seen, days_seen, accuracy
+                )
+                .execute(conn)
+                .await?;
+            }
+            Transmitter::Bluetooth { mac } => {
+                query!(
+                    "insert into bluetooth (mac, min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy) values ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                     on conflict (mac) do update set min_lat = EXCLUDED.min_lat, min_lon = EXCLUDED.min_lon, max_lat = EXCLUDED.max_lat, max_lon = EXCLUDED.max_lon,
+                        first_seen = EXCLUDED.first_seen, last_seen = EXCLUDED.last_seen,
+                        days_seen = EXCLUDED.days_seen, accuracy = EXCLUDED.accuracy
+                    ",
+                    mac, b.min_lat, b.min_lon, b.max_lat, b.max_lon, first_seen, last_seen, days_seen, accuracy
+                )
+                .execute(conn)
+                .await?;
+            }
+            Transmitter::Beacon { id } => {
+                let (protocol, id) = id.columns();
+                query!(
+                    "insert into beacon (protocol, id, min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+                     on conflict (protocol, id) do update set min_lat = EXCLUDED.min_lat, min_lon = EXCLUDED.min_lon, max_lat = EXCLUDED.max_lat, max_lon = EXCLUDED.max_lon,
+                        first_seen = EXCLUDED.first_seen, last_seen = EXCLUDED.last_seen,
+                        days_seen = EXCLUDED.days_seen, accuracy = EXCLUDED.accuracy
+                    ",
+                    protocol, id, b.min_lat, b.min_lon, b.max_lat, b.max_lon, first_seen, last_seen, days_seen, accuracy
+                )
+                .execute(conn)
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn delete(&self, conn: &mut PgConnection) -> sqlx::Result<()> {
+        match self {
+            Transmitter::Cell {
+                radio,
+                country,
+                network,
+                area,
+                cell,
+                unit,
+            } => {
+                query!(
+                    "delete from cell where radio = $1 and country = $2 and network = $3 and area = $4 and cell = $5 and unit = $6",
+                    *radio as i16, country, network, area, cell, unit
+                )
+                .execute(conn)
+                .await?;
+            }
+            Transmitter::Wifi { mac } => {
+                query!("delete from wifi where mac = $1 and not pinned", mac)
+                    .execute(conn)
+                    .await?;
+            }
+            Transmitter::Bluetooth { mac } => {
+                query!("delete from bluetooth where mac = $1", mac)
+                    .execute(conn)
+                    .await?;
+            }
+            Transmitter::Beacon { id } => {
+                let (protocol, id) = id.columns();
+                query!(
+                    "delete from beacon where protocol = $1 and id = $2",
+                    protocol,
+                    id
+                )
+                .execute(conn)
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// An access point's bounds as looked up for geolocation.
+pub struct WifiBounds {
+    pub bounds: Bounds,
+    /// When it was seen, if that was recorded
+    pub seen: Option<Seen>,
+    /// Set by a correction rather than built from observations
+    pub pinned: bool,
+}
+
+pub async fn lookup_wifi(
+    pool: &PgPool,
+    mac: &MacAddress,
+    storage: WifiStorage,
+    filter: SeenFilter,
+) -> sqlx::Result<Option<WifiBounds>> {
+    match storage {
+        WifiStorage::Bounds => {
+            let row = query!(
+                "select min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy, pinned from wifi where mac = $1",
+                mac
+            )
+            .fetch_optional(pool)
+            .await?;
+            Ok(row.and_then(|x| {
+                let (bounds, seen) = SeenBounds {
+                    min_lat: x.min_lat,
+                    min_lon: x.min_lon,
+                    max_lat: x.max_lat,
+                    max_lon: x.max_lon,
+                    first_seen: x.first_seen,
+                    last_seen: x.last_seen,
+                    days_seen: x.days_seen,
+                    accuracy: x.accuracy,
+                }
+                .split();
+                filter.allows(seen).then_some(WifiBounds {
+                    bounds,
+                    seen,
+                    pinned: x.pinned,
+                })
+            }))
+        }
+        // individual cells aren't timestamped
+        WifiStorage::H3 => {
+            let cells = query_scalar!("select h3 from wifi_h3 where mac = $1", mac)
+                .fetch_all(pool)
+                .await?;
+            let bounds = Bounds::from_cells(cells.into_iter().filter_map(|x| h3_cell(&x)));
+            Ok(bounds.map(|bounds| WifiBounds {
+                bounds,
+                seen: None,
+                pinned: false,
+            }))
+        }
+    }
+}
+
+/// Parse an h3 cell as stored in `wifi_h3` and `map`.
+pub fn h3_cell(bytes: &[u8]) -> Option<CellIndex> {
+    let x: [u8; 8] = bytes.try_into().ok()?;
+    CellIndex::try_from(u64::from_be_bytes(x)).ok()
+}
+
+/// Transmitters to save in one go, split into a column per field so that each
+/// table is written with a single multi-row statement rather than one per
+/// transmitter. A transmitter can only be added once, as postgres rejects a
+/// statement that updates the same row twice.
+#[derive(Debug, Default, PartialEq)]
+pub struct Upserts {
+    cell: CellColumns,
+    wifi: MacColumns,
+    bluetooth: MacColumns,
+    beacon: BeaconColumns,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct CellColumns {
+    radio: Vec<i16>,
+    country: Vec<i16>,
+    network: Vec<i16>,
+    area: Vec<i32>,
+    cell: Vec<i64>,
+    unit: Vec<i16>,
+    bounds: BoundsColumns,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct MacColumns {
+    mac: Vec<MacAddress>,
+    bounds: BoundsColumns,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct BeaconColumns {
+    protocol: Vec<i16>,
+    id: Vec<Vec<u8>>,
+    bounds: BoundsColumns,
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct BoundsColumns {
+    min_lat: Vec<f64>,
+    min_lon: Vec<f64>,
+    max_lat: Vec<f64>,
+    max_lon: Vec<f64>,
+    first_seen: Vec<DateTime<Utc>>,
+    last_seen: Vec<DateTime<Utc>>,
+    days_seen: Vec<i32>,
+    accuracy: Vec<Option<f64>>,
+}
+
+impl BoundsColumns {
+    fn push(&mut self, b: &Bounds, seen: Seen) {
+        self.min_lat.push(b.min_lat);
+        self.min_lon.push(b.min_lon);
+        self.max_lat.push(b.max_lat);
+        self.max_lon.push(b.max_lon);
+        self.first_seen.push(seen.first);
+        self.last_seen.push(seen.last);
+        self.days_seen.push(seen.days);
+        self.accuracy.push(seen.accuracy);
+    }
+}
+
+impl Upserts {
+    pub fn push(&mut self, x: Transmitter, b: &Bounds, seen: Seen) {
+        match x {
+            Transmitter::Cell {
+                radio,
+                country,
+                network,
+                area,
+                cell,
+                unit,
+            } => {
+                let c = &mut self.cell;
+                c.radio.push(radio as i16);
+                c.country.push(country);
+                c.network.push(network);
+                c.area.push(area);
+                c.cell.push(cell);
+                c.unit.push(unit);
+                c.bounds.push(b, seen);
+            }
+            Transmitter::Wifi { mac } => {
+                self.wifi.mac.push(mac);
+                self.wifi.bounds.push(b, seen);
+            }
+            Transmitter::Bluetooth { mac } => {
+                self.bluetooth.mac.push(mac);
+                self.bluetooth.bounds.push(b, seen);
+            }
+            Transmitter::Beacon { id } => {
+                let (protocol, id) = id.columns();
+                self.beacon.protocol.push(protocol);
+                self.beacon.id.push(id);
+                self.beacon.bounds.push(b, seen);
+            }
+        }
+    }
+
+    /// The same as calling [`Transmitter::save`] for each transmitter.
+    pub async fn save(&self, conn: &mut PgConnection) -> sqlx::Result<()> {
+        let (c, b) = (&self.cell, &self.cell.bounds);
+        if !c.radio.is_empty() {
+            query!(
+                "insert into cell (radio, country, network, area, cell, unit, min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy)
+                 select * from unnest($1::smallint[], $2::smallint[], $3::smallint[], $4::integer[], $5::bigint[], $6::smallint[], $7::float8[], $8::float8[], $9::float8[], $10::float8[], $11::timestamptz[], $12::timestamptz[], $13::integer[], $14::float8[])
+                 on conflict (radio, country, network, area, cell, unit) do update set min_lat = EXCLUDED.min_lat, min_lon = EXCLUDED.min_lon, max_lat = EXCLUDED.max_lat, max_lon = EXCLUDED.max_lon,
+                    first_seen = least(cell.first_seen, EXCLUDED.first_seen), last_seen = greatest(cell.last_seen, EXCLUDED.last_seen),
+                        days_seen = coalesce(EXCLUDED.days_seen, cell.days_seen),
+                        accuracy = coalesce(EXCLUDED.accuracy, cell.accuracy)
+                ",
+                &c.radio, &c.country, &c.network, &c.area, &c.cell, &c.unit, &b.min_lat, &b.min_lon, &b.max_lat, &b.max_lon, &b.first_seen, &b.last_seen, &b.days_seen, &b.accuracy as &[Option<f64>]
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        let (w, b) = (&self.wifi, &self.wifi.bounds);
+        if !w.mac.is_empty() {
+            query!(
+                "insert into wifi (mac, min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy)
+                 select * from unnest($1::macaddr[], $2::float8[], $3::float8[], $4::float8[], $5::float8[], $6::timestamptz[], $7::timestamptz[], $8::integer[], $9::float8[])
+                 on conflict (mac) do update set min_lat = EXCLUDED.min_lat, min_lon = EXCLUDED.min_lon, max_lat = EXCLUDED.max_lat, max_lon = EXCLUDED.max_lon,
+                    first_seen = least(wifi.first_seen, EXCLUDED.first_seen), last_seen = greatest(wifi.last_seen, EXCLUDED.last_seen),
+                        days_seen = coalesce(EXCLUDED.days_seen, wifi.days_seen),
+                        accuracy = coalesce(EXCLUDED.accuracy, wifi.accuracy)
+                 where not wifi.pinned
+                ",
+                &w.mac, &b.min_lat, &b.min_lon, &b.max_lat, &b.max_lon, &b.first_seen, &b.last_seen, &b.days_seen, &b.accuracy as &[Option<f64>]
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        let (bt, b) = (&self.bluetooth, &self.bluetooth.bounds);
+        if !bt.mac.is_empty() {
+            query!(
+                "insert into bluetooth (mac, min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy)
+                 select * from unnest($1::macaddr[], $2::float8[], $3::float8[], $4::float8[], $5::float8[], $6::timestamptz[], $7::timestamptz[], $8::integer[], $9::float8[])
+                 on conflict (mac) do update set min_lat = EXCLUDED.min_lat, min_lon = EXCLUDED.min_lon, max_lat = EXCLUDED.max_lat, max_lon = EXCLUDED.max_lon,
+                    first_seen = least(bluetooth.first_seen, EXCLUDED.first_seen), last_seen = greatest(bluetooth.last_seen, EXCLUDED.last_seen),
+                        days_seen = coalesce(EXCLUDED.days_seen, bluetooth.days_seen),
+                        accuracy = coalesce(EXCLUDED.accuracy, bluetooth.accuracy)
+                ",
+                &bt.mac, &b.min_lat, &b.min_lon, &b.max_lat, &b.max_lon, &b.first_seen, &b.last_seen, &b.days_seen, &b.accuracy as &[Option<f64>]
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        let (bc, b) = (&self.beacon, &self.beacon.bounds);
+        if !bc.id.is_empty() {
+            query!(
+                "insert into beacon (protocol, id, min_lat, min_lon, max_lat, max_lon, first_seen, last_seen, days_seen, accuracy)
+                 select * from unnest($1::smallint[], $2::bytea[], $3::float8[], $4::float8[], $5::float8[], $6::float8[], $7::timestamptz[], $8::timestamptz[], $9::integer[], $10::float8[])
+                 on conflict (protocol, id) do update set min_lat = EXCLUDED.min_lat, min_lon = EXCLUDED.min_lon, max_lat = EXCLUDED.max_lat, max_lon = EXCLUDED.max_lon,
+                    first_seen = least(beacon.first_seen, EXCLUDED.first_seen), last_seen = greatest(beacon.last_seen, EXCLUDED.last_seen),
+                        days_seen = coalesce(EXCLUDED.days_seen, beacon.days_seen),
+                        accuracy = coalesce(EXCLUDED.accuracy, beacon.accuracy)
+                ",
+                &bc.protocol, &bc.id, &b.min_lat, &b.min_lon, &b.max_lat, &b.max_lon, &b.first_seen, &b.last_seen, &b.days_seen, &b.accuracy as &[Option<f64>]
+            )
+            .execute(&mut *conn)
+            .await?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    #[test]
+    fn upsert_columns() {
+        let t = |secs| Utc.timestamp_opt(secs, 0).unwrap();
+        let cell = |cell| Transmitter::Cell {
+            radio: CellRadio::Lte,
+            country: 505,
+            network: 1,
+            area: 2,
+            cell,
+            unit: 3,
+        };
+        let mac = MacAddress::new([2, 0, 0, 0, 0, 1]);
+        let rows = [
+            (cell(10), Bounds::new(1.0, 2.0), Seen::new(t(0))),
+            (
+                Transmitter::Wifi { mac },
+                Bounds::new(3.0, 4.0) + (5.0, 6.0),
+                Seen::new(t(1)).add(t(2)),
+            ),
+            (cell(11), Bounds::new(7.0, 8.0), Seen::new(t(3))),
+            (
+                Transmitter::Bluetooth { mac },
+                Bounds::new(9.0, 10.0),
+                Seen::new(t(4)),
+            ),
+        ];
+
+        let mut upserts = Upserts::default();
+        for (x, b, seen) in &rows {
+            upserts.push(*x, b, *seen);
+        }
+
+        // each row lines up across the columns of its table, in order
+        let c = &upserts.cell;
+        assert_eq!(c.radio, [4, 4]);
+        assert_eq!(c.country, [505, 505]);
+        assert_eq!(c.cell, [10, 11]);
+        assert_eq!(c.unit, [3, 3]);
+        assert_eq!(c.bounds.min_lat, [1.0, 7.0]);
+        assert_eq!(c.bounds.max_lon, [2.0, 8.0]);
+        assert_eq!(c.bounds.first_seen, [t(0), t(3)]);
+
+        let w = &upserts.wifi;
+        assert_eq!(w.mac, [mac]);
+        assert_eq!(
+            (
+                w.bounds.min_lat[0],
+                w.bounds.min_lon[0],
+                w.bounds.max_lat[0],
+                w.bounds.max_lon[0]
+            ),
+            (3.0, 4.0, 5.0, 6.0)
+        );
+        assert_eq!(
+            (w.bounds.first_seen[0], w.bounds.last_seen[0]),
+            (t(1), t(2))
+        );
+        assert_eq!(w.bounds.days_seen, [1]);
+
+        assert_eq!(upserts.bluetooth.mac, [mac]);
+        assert_eq!(upserts.bluetooth.bounds.min_lat, [9.0]);
+    }
+
+    #[test]
+    fn site_cells() {
+        // enodeb 123456, sector 2
+        let cell = 123456 << 8 | 2;
+        assert_eq!(
+            CellRadio::Lte.site_cells(cell),
+            Some((123456 << 8, 123456 << 8 | 0xff))
+        );
+        assert_eq!(CellRadio::Lte.site_cells(1 << 28), None);
+        assert_eq!(CellRadio::Lte.site_cells(-1), None);
+
+        assert_eq!(CellRadio::Gsm.site_cells(cell), None);
+        assert_eq!(CellRadio::Wcdma.site_cells(cell), None);
+        assert_eq!(CellRadio::Nr.site_cells(cell), None);
+    }
+
+    #[test]
+    fn seen_filter() {
+        let t = |days: i64| Utc.timestamp_opt(1_700_000_000, 0).unwrap() + Duration::days(days);
+        let filter = SeenFilter {
+            min_days: 3,
+            not_before: None,
+        };
+
+        let one_day = Seen::new(t(0)).add(t(0) + Duration::hours(3));
+        assert!(!filter.allows(Some(one_day)));
+        let multi_day = Seen::new(t(0)).add(t(1)).add(t(5));
+        assert!(filter.allows(Some(multi_day)));
+        // far apart, but still only two days
+        let two_days = Seen::new(t(0)).add(t(30));
+        assert!(!filter.allows(Some(two_days)));
+        // a backdated report only counts once, however far back it is
+        let backdated = Seen::new(t(0)).add(t(-20_000));
+        assert_eq!(backdated.days, 2);
+        assert!(!filter.allows(Some(backdated)));
+
+        assert!(filter.allows(None));
+        assert!(SeenFilter::default().allows(Some(one_day)));
+        assert_eq!(Seen::from_columns(Some(t(0)), None, Some(1), None), None);
+
+        let filter = SeenFilter {
+            min_days: 0,
+            not_before: Some(t(3)),
+        };
+        assert!(!filter.allows(Some(one_day)));
+        assert!(filter.allows(Some(multi_day)));
+        assert!(filter.allows(None));
+    }
+
+    #[test]
+    fn seen_widens() {
+        let t = |secs: i64| Utc.timestamp_opt(1_700_000_000 + secs, 0).unwrap();
+
+        // first processing run
+        let seen = Seen::new(t(100)).add(t(200));
+        assert_eq!(seen.first, t(100));
+        assert_eq!(seen.last, t(200));
+
+        // a later run keeps the original first_seen while last_seen advances,
+        // even when reports arrive out of order
+        let seen = seen.add(t(300)).add(t(150));
+        assert_eq!(seen.first, t(100));
+        assert_eq!(seen.last, t(300));
+
+        let seen = seen.add(t(50));
+        assert_eq!(seen.first, t(50));
+        assert_eq!(seen.last, t(300));
+        assert_eq!(seen.days, 1);
+
+        // days in between the first and last can't be told apart from ones
+        // already counted
+        let day = 24 * 60 * 60;
+        let seen = seen.add(t(2 * day)).add(t(day)).add(t(-day));
+        assert_eq!(seen.days, 3);
+    }
+
+    #[test]
+    fn seen_accuracy() {
+        let seen = Seen::new(Utc.timestamp_opt(1_700_000_000, 0).unwrap());
+        assert_eq!(seen.accuracy, None);
+
+        // the least precise position decides it
+        let seen = seen.with_accuracy(Some(20.0)).with_accuracy(None);
+        assert_eq!(seen.accuracy, Some(20.0));
+        let seen = seen.with_accuracy(Some(150.0)).with_accuracy(Some(5.0));
+        assert_eq!(seen.accuracy, Some(150.0));
+        // and positions that don't say are left out
+        assert_eq!(seen.add(seen.last).accuracy, Some(150.0));
+        assert_eq!(
+            Seen::new(seen.first).with_accuracy(Some(0.0)).accuracy,
+            None
+        );
     }
 }
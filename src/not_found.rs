@@ -0,0 +1,59 @@
+use actix_web::HttpResponse;
+use serde_json::json;
+
+// lookups that find nothing answer the way Ichnaea (and so MLS and Google's
+// api) does, as that's what clients such as Firefox were written against.
+// some clients only look at the status and fail to handle a body, so with
+// `bare_not_found` set the body is left out instead.
+
+/// The response for a geolocate or country lookup that found nothing.
+pub fn response(bare: bool) -> HttpResponse {
+    if bare {
+        return HttpResponse::NotFound().finish();
+    }
+
+    HttpResponse::NotFound().json(json!({
+        "error": {
+            "errors": [{
+                "domain": "geolocation",
+                "reason": "notFound",
+                "message": "No location could be estimated based on the data provided",
+            }],
+            "code": 404,
+            "message": "Not found",
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        body::{to_bytes, MessageBody},
+        http::StatusCode,
+    };
+    use serde_json::Value;
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn envelope() {
+        let res = response(false);
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body: Value =
+            serde_json::from_slice(&to_bytes(res.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body["error"]["code"], 404);
+        assert_eq!(body["error"]["message"], "Not found");
+        assert_eq!(
+            body["error"]["errors"],
+            json!([{
+                "domain": "geolocation",
+                "reason": "notFound",
+                "message": "No location could be estimated based on the data provided",
+            }])
+        );
+
+        let res = response(true);
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(res.into_body().try_into_bytes().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::Result;
+use sqlx::{query, query_as, PgPool};
+
+use crate::bounds::Bounds;
+
+// the tables don't share a key, so rows are addressed by ctid instead. it stays
+// the same for as long as the transaction holds the rows
+
+struct Swapped {
+    id: String,
+    min_lat: f64,
+    min_lon: f64,
+    max_lat: f64,
+    max_lon: f64,
+}
+
+impl Swapped {
+    fn bounds(&self) -> Bounds {
+        Bounds {
+            min_lat: self.min_lat,
+            min_lon: self.min_lon,
+            max_lat: self.max_lat,
+            max_lon: self.max_lon,
+        }
+        .normalized()
+    }
+}
+
+/// Fix transmitters stored with their minimum and maximum corners swapped,
+/// which would otherwise give nonsense centres and accuracies.
+pub async fn run(pool: PgPool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+
+    let rows = query_as!(
+        Swapped,
+        r#"select ctid::text as "id!", min_lat, min_lon, max_lat, max_lon from wifi
+           where min_lat > max_lat or min_lon > max_lon for update"#
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for x in &rows {
+        let b = x.bounds();
+        query!(
+            "update wifi set min_lat = $2, min_lon = $3, max_lat = $4, max_lon = $5 where ctid = $1::text::tid",
+            x.id, b.min_lat, b.min_lon, b.max_lat, b.max_lon
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    eprintln!("wifi: {} rows corrected", rows.len());
+
+    let rows = query_as!(
+        Swapped,
+        r#"select ctid::text as "id!", min_lat, min_lon, max_lat, max_lon from cell
+           where min_lat > max_lat or min_lon > max_lon for update"#
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for x in &rows {
+        let b = x.bounds();
+        query!(
+            "update cell set min_lat = $2, min_lon = $3, max_lat = $4, max_lon = $5 where ctid = $1::text::tid",
+            x.id, b.min_lat, b.min_lon, b.max_lat, b.max_lon
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    eprintln!("cell: {} rows corrected", rows.len());
+
+    let rows = query_as!(
+        Swapped,
+        r#"select ctid::text as "id!", min_lat, min_lon, max_lat, max_lon from bluetooth
+           where min_lat > max_lat or min_lon > max_lon for update"#
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for x in &rows {
+        let b = x.bounds();
+        query!(
+            "update bluetooth set min_lat = $2, min_lon = $3, max_lat = $4, max_lon = $5 where ctid = $1::text::tid",
+            x.id, b.min_lat, b.min_lon, b.max_lat, b.max_lon
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    eprintln!("bluetooth: {} rows corrected", rows.len());
+
+    let rows = query_as!(
+        Swapped,
+        r#"select ctid::text as "id!", min_lat, min_lon, max_lat, max_lon from beacon
+           where min_lat > max_lat or min_lon > max_lon for update"#
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for x in &rows {
+        let b = x.bounds();
+        query!(
+            "update beacon set min_lat = $2, min_lon = $3, max_lat = $4, max_lon = $5 where ctid = $1::text::tid",
+            x.id, b.min_lat, b.min_lon, b.max_lat, b.max_lon
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    eprintln!("beacon: {} rows corrected", rows.len());
+
+    tx.commit().await?;
+
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use std::{
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock,
+    },
+};
+
+use actix_web::{
+    dev::{Service, ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    HttpMessage, HttpRequest,
+};
+use chrono::Utc;
+
+// every request gets an id, sent back in the X-Request-Id header and included
+// in anything logged about it, so that a user's report of a bad response can
+// be matched up with the logs. clients can send their own id to use instead,
+// as long as it's short and printable.
+
+pub const HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+const MAX_LENGTH: usize = 128;
+
+/// The start time and pid of this process, so that ids don't repeat across
+/// restarts or between instances.
+static PREFIX: LazyLock<String> =
+    LazyLock::new(|| format!("{:x}-{:x}", Utc::now().timestamp(), std::process::id()));
+static COUNT: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(String);
+
+impl RequestId {
+    fn new(header: Option<&HeaderValue>) -> Self {
+        let provided = header
+            .and_then(|x| x.to_str().ok())
+            .filter(|x| !x.is_empty() && x.len() <= MAX_LENGTH)
+            .filter(|x| x.bytes().all(|b| b.is_ascii_graphic()));
+        match provided {
+            Some(x) => RequestId(x.to_owned()),
+            None => RequestId(unique()),
+        }
+    }
+
+    /// The id of a request, or `-` outside of the middleware.
+    pub fn of(req: &HttpRequest) -> Self {
+        req.extensions()
+            .get::<RequestId>()
+            .cloned()
+            .unwrap_or_else(|| RequestId("-".to_owned()))
+    }
+}
+
+/// An id that's never been generated before, by this or any other instance.
+/// Unlike a request's id, it can't be chosen by the client.
+pub fn unique() -> String {
+    format!("{}-{:x}", *PREFIX, COUNT.fetch_add(1, Ordering::Relaxed))
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Give the request an id, for use with `App::wrap_fn`.
+pub fn middleware<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = actix_web::Result<ServiceResponse<B>>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let id = RequestId::new(req.headers().get(HEADER));
+    req.extensions_mut().insert(id.clone());
+    let res = srv.call(req);
+    async move {
+        let mut res = res.await?;
+        // only printable ascii is ever used, which is always a valid value
+        if let Ok(x) = HeaderValue::from_str(&id.0) {
+            res.headers_mut().insert(HEADER, x);
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        test::{call_service, init_service, read_body, TestRequest},
+        web, App, HttpResponse,
+    };
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn echoed_or_generated() {
+        let app = init_service(App::new().wrap_fn(middleware).route(
+            "/",
+            web::get().to(|req: HttpRequest| async move {
+                HttpResponse::Ok().body(RequestId::of(&req).to_string())
+            }),
+        ))
+        .await;
+
+        let res = call_service(
+            &app,
+            TestRequest::get()
+                .uri("/")
+                .insert_header((HEADER, "abc-123"))
+                .to_request(),
+        )
+        .await;
+        assert_eq!(res.headers().get(HEADER).unwrap(), "abc-123");
+        assert_eq!(read_body(res).await, "abc-123");
+
+        let mut ids = Vec::new();
+        for header in [None, Some("has spaces"), Some(&*"x".repeat(200))] {
+            let mut req = TestRequest::get().uri("/");
+            if let Some(x) = header {
+                req = req.insert_header((HEADER, x));
+            }
+            let res = call_service(&app, req.to_request()).await;
+            let id = res
+                .headers()
+                .get(HEADER)
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_owned();
+            assert_eq!(read_body(res).await, id);
+            assert!(id.starts_with(&*PREFIX));
+            ids.push(id);
+        }
+        ids.dedup();
+        assert_eq!(ids.len(), 3);
+
+        // a 404 from the router still gets one
+        let res = call_service(&app, TestRequest::get().uri("/missing").to_request()).await;
+        assert!(res.headers().contains_key(HEADER));
+    }
+}
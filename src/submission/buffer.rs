@@ -0,0 +1,186 @@
+use std::{future::Future, mem, sync::Mutex, time::Duration};
+
+use anyhow::{Context, Result};
+use sqlx::PgPool;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time,
+};
+
+use super::geosubmit::{self, NewReport};
+use crate::config::BufferConfig;
+
+// when buffering is enabled, submissions are acknowledged as soon as they are
+// queued in memory and written to the database in larger batches by a
+// background task. anything still queued is written out when the server shuts
+// down, but lost if it stops unexpectedly, so this trades a small durability
+// window for throughput.
+
+pub struct Buffer {
+    sender: mpsc::Sender<NewReport>,
+    // taken on shutdown to stop the background task once it has caught up
+    task: Mutex<Option<(oneshot::Sender<()>, JoinHandle<()>)>>,
+}
+
+impl Buffer {
+    pub fn spawn(pool: PgPool, config: &BufferConfig, insert_batch_size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel(config.capacity);
+        let (shutdown, closed) = oneshot::channel();
+        let interval = Duration::from_millis(config.flush_interval_ms);
+        let task = tokio::spawn(batch(
+            receiver,
+            closed,
+            config.flush_size,
+            interval,
+            move |reports| {
+                let pool = pool.clone();
+                async move {
+                    let count = reports.len();
+                    if let Err(e) = geosubmit::insert(&pool, reports, insert_batch_size).await {
+                        eprintln!("Failed to write {count} buffered reports: {e}");
+                    }
+                }
+            },
+        ));
+
+        Buffer {
+            sender,
+            task: Mutex::new(Some((shutdown, task))),
+        }
+    }
+
+    /// Queue reports to be written, waiting for space in the buffer if needed.
+    pub async fn push(&self, reports: Vec<NewReport>) -> Result<()> {
+        for report in reports {
+            self.sender
+                .send(report)
+                .await
+                .ok()
+                .context("submission buffer is closed")?;
+        }
+
+        Ok(())
+    }
+
+    /// Write out everything still queued. Called once the server has stopped,
+    /// as anything pushed afterwards is rejected.
+    pub async fn drain(&self) {
+        let Some((shutdown, task)) = self.task.lock().unwrap().take() else {
+            return;
+        };
+        let _ = shutdown.send(());
+        if let Err(e) = task.await {
+            eprintln!("Submission buffer failed: {e}");
+        }
+    }
+}
+
+/// Collect items into batches, flushing whenever a batch is full or the
+/// interval elapses with items waiting. Once `closed` fires, no more items
+/// are accepted and the rest are flushed.
+async fn batch<T, F, Fut>(
+    mut receiver: mpsc::Receiver<T>,
+    mut closed: oneshot::Receiver<()>,
+    size: usize,
+    interval: Duration,
+    mut flush: F,
+) where
+    F: FnMut(Vec<T>) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut pending = Vec::new();
+    // the first tick of a plain interval fires immediately
+    let mut ticker = time::interval_at(time::Instant::now() + interval, interval);
+    let mut closing = false;
+    loop {
+        tokio::select! {
+            x = receiver.recv() => match x {
+                Some(x) => {
+                    pending.push(x);
+                    if pending.len() >= size {
+                        flush(mem::take(&mut pending)).await;
+                    }
+                }
+                None => {
+                    if !pending.is_empty() {
+                        flush(pending).await;
+                    }
+                    return;
+                }
+            },
+            _ = ticker.tick() => {
+                if !pending.is_empty() {
+                    flush(mem::take(&mut pending)).await;
+                }
+            }
+            // recv keeps returning what was already queued, then None
+            _ = &mut closed, if !closing => {
+                receiver.close();
+                closing = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    type Flushed = Arc<Mutex<Vec<Vec<u32>>>>;
+
+    type Shutdown = (oneshot::Sender<()>, JoinHandle<()>);
+
+    fn spawn(size: usize, interval: Duration) -> (mpsc::Sender<u32>, Shutdown, Flushed) {
+        let (sender, receiver) = mpsc::channel(100);
+        let (shutdown, closed) = oneshot::channel();
+        let flushed = Arc::new(Mutex::new(Vec::new()));
+        let f = flushed.clone();
+        let task = tokio::spawn(batch(receiver, closed, size, interval, move |x| {
+            f.lock().unwrap().push(x);
+            async {}
+        }));
+        (sender, (shutdown, task), flushed)
+    }
+
+    #[tokio::test]
+    async fn flush_by_size() {
+        let (sender, _shutdown, flushed) = spawn(3, Duration::from_secs(3600));
+        for i in 0..7 {
+            sender.send(i).await.unwrap();
+        }
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(*flushed.lock().unwrap(), [vec![0, 1, 2], vec![3, 4, 5]]);
+
+        // the remainder is flushed once the buffer closes
+        drop(sender);
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(flushed.lock().unwrap()[2], [6]);
+    }
+
+    #[tokio::test]
+    async fn flush_by_time() {
+        let (sender, _shutdown, flushed) = spawn(100, Duration::from_millis(100));
+        sender.send(1).await.unwrap();
+        sender.send(2).await.unwrap();
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(flushed.lock().unwrap().is_empty());
+
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(*flushed.lock().unwrap(), [vec![1, 2]]);
+    }
+
+    #[tokio::test]
+    async fn flush_on_shutdown() {
+        let (sender, (shutdown, task), flushed) = spawn(100, Duration::from_secs(3600));
+        sender.send(1).await.unwrap();
+        sender.send(2).await.unwrap();
+
+        shutdown.send(()).unwrap();
+        task.await.unwrap();
+        assert_eq!(*flushed.lock().unwrap(), [vec![1, 2]]);
+        assert!(sender.send(3).await.is_err());
+    }
+}
@@ -0,0 +1,180 @@
+use actix_web::{
+    error::{ErrorBadRequest, ErrorInternalServerError, ErrorUnauthorized},
+    post, web, HttpRequest, HttpResponse, Responder,
+};
+use anyhow::Context;
+use mac_address::MacAddress;
+use serde::Deserialize;
+use sqlx::{query, PgPool};
+
+use super::token;
+use crate::{
+    bounds::Bounds,
+    config::{Config, WifiStorage},
+    geolocate::cache::Cache,
+};
+
+// corrections let trusted contributors, such as someone who has surveyed an
+// access point, set its position outright. the access point is then pinned:
+// processing and purging leave it alone until it is corrected again. only
+// the separate correction tokens are accepted, never upload tokens.
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Correction {
+    mac_address: MacAddress,
+    latitude: f64,
+    longitude: f64,
+    // metres
+    accuracy: f64,
+}
+
+#[post("/v2/correct")]
+pub async fn service(
+    data: web::Json<Correction>,
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let tokens = config.correction_tokens.as_deref().unwrap_or_default();
+    let Some(token_id) = token::authenticate(tokens, token::bearer(&req)) else {
+        return Err(ErrorUnauthorized("corrections require a correction token"));
+    };
+    if config.wifi_storage != WifiStorage::Bounds {
+        return Err(ErrorBadRequest(
+            "corrections are only supported with wifi_storage = \"bounds\"",
+        ));
+    }
+    let valid = (-90.0..=90.0).contains(&data.latitude)
+        && (-180.0..=180.0).contains(&data.longitude)
+        && (1.0..100_000.0).contains(&data.accuracy);
+    if !valid {
+        return Err(ErrorBadRequest("invalid position"));
+    }
+
+    let b = Bounds::around(data.latitude, data.longitude, data.accuracy);
+    query!(
+        "insert into wifi (mac, min_lat, min_lon, max_lat, max_lon, pinned) values ($1, $2, $3, $4, $5, true)
+         on conflict (mac) do update set min_lat = EXCLUDED.min_lat, min_lon = EXCLUDED.min_lon, max_lat = EXCLUDED.max_lat, max_lon = EXCLUDED.max_lon, pinned = true
+        ",
+        data.mac_address, b.min_lat, b.min_lon, b.max_lat, b.max_lon
+    )
+    .execute(&**pool)
+    .await
+    .context("writing to database failed")
+    .map_err(ErrorInternalServerError)?;
+    eprintln!(
+        "Access point {} pinned by token #{token_id}",
+        data.mac_address
+    );
+    // otherwise fixes from the old position would be served until they expire
+    if let Some(cache) = req.app_data::<web::Data<Cache>>() {
+        cache.clear();
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::{header::AUTHORIZATION, StatusCode},
+        test::{call_service, init_service, TestRequest},
+        App,
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::UploadToken;
+
+    #[actix_web::test]
+    async fn requires_correction_token() {
+        let token = |id, token: &str| UploadToken {
+            id,
+            token: token.to_owned(),
+        };
+        let config = |correction_tokens, storage| {
+            let mut config: Config = toml::from_str("database_url = ''\nhttp_port = 0").unwrap();
+            config.upload_tokens = Some(vec![token(1, "uploader")]);
+            config.correction_tokens = correction_tokens;
+            config.wifi_storage = storage;
+            web::Data::new(config)
+        };
+        let surveyor = || Some(vec![token(2, "surveyor")]);
+        let correction = |accuracy| {
+            json!({
+                "macAddress": "00:11:22:33:44:55",
+                "latitude": -27.4698,
+                "longitude": 153.0251,
+                "accuracy": accuracy,
+            })
+        };
+        // never connects, the requests below are rejected first
+        let pool = web::Data::new(PgPool::connect_lazy("postgres:///beacondb").unwrap());
+
+        let cases = [
+            // disabled
+            (
+                None,
+                WifiStorage::Bounds,
+                Some("Bearer surveyor"),
+                5.0,
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                surveyor(),
+                WifiStorage::Bounds,
+                None,
+                5.0,
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                surveyor(),
+                WifiStorage::Bounds,
+                Some("Bearer wrong"),
+                5.0,
+                StatusCode::UNAUTHORIZED,
+            ),
+            // an upload token isn't enough
+            (
+                surveyor(),
+                WifiStorage::Bounds,
+                Some("Bearer uploader"),
+                5.0,
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                surveyor(),
+                WifiStorage::H3,
+                Some("Bearer surveyor"),
+                5.0,
+                StatusCode::BAD_REQUEST,
+            ),
+            // would be too small to ever be used
+            (
+                surveyor(),
+                WifiStorage::Bounds,
+                Some("Bearer surveyor"),
+                0.0,
+                StatusCode::BAD_REQUEST,
+            ),
+        ];
+        for (correction_tokens, storage, auth, accuracy, status) in cases {
+            let app = init_service(
+                App::new()
+                    .app_data(pool.clone())
+                    .app_data(config(correction_tokens, storage))
+                    .service(service),
+            )
+            .await;
+            let mut req = TestRequest::post()
+                .uri("/v2/correct")
+                .set_json(correction(accuracy));
+            if let Some(auth) = auth {
+                req = req.insert_header((AUTHORIZATION, auth));
+            }
+            let res = call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), status, "{auth:?} {accuracy}");
+        }
+    }
+}
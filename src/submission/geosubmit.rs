@@ -1,31 +1,69 @@
 use actix_web::{
-    error::ErrorInternalServerError,
+    dev::Decompress,
+    error::{
+        ErrorBadRequest, ErrorInternalServerError, ErrorPayloadTooLarge, ErrorServiceUnavailable,
+    },
     http::{header::USER_AGENT, StatusCode},
     post, web, HttpRequest, HttpResponse, Responder,
 };
+use std::collections::BTreeSet;
+
 use anyhow::Context;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Duration, Utc};
+use futures::StreamExt;
+use mac_address::MacAddress;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use sqlx::{query, PgPool};
 
+use super::{
+    buffer::Buffer,
+    items::Items,
+    report::{self, Rejection},
+    token::Uploader,
+};
+use crate::{config::Config, model::Transmitter, request_id};
+
 // only the bare minimum is parsed here: it is assumed that certain data issues
 // may be due to device manufacturer software, making it difficult for
 // developers to handle per device.
 //
 // - https://github.com/mjaakko/NeoStumbler/issues/88
 
+// how many parsed reports are held before they're written, so that memory use
+// doesn't grow with the size of a submission
+const BATCH_SIZE: usize = 1000;
+
+// the most a submission may decompress to, the same limit as when the body was
+// read in whole
+const MAX_BODY_SIZE: usize = 500 * 1024 * 1024;
+
+// how many malformed items are described in the response. the rest are only
+// counted, so a body full of garbage doesn't produce a response just as large
+const MAX_ERRORS: usize = 100;
+
+// reports are written this many to a transaction unless configured otherwise
+pub const INSERT_BATCH_SIZE: usize = 1000;
+
+// how far ahead of the server's clock a report's timestamp may be, unless
+// configured otherwise
+const FUTURE_TOLERANCE_SECS: u32 = 3600;
+
 #[derive(Deserialize)]
 struct Submission {
-    items: Vec<Report>,
+    items: Vec<Value>,
 }
 
 #[derive(Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
-struct Report {
+pub(super) struct Report {
     #[serde(with = "chrono::serde::ts_milliseconds")]
     timestamp: DateTime<Utc>,
     position: Position,
+    /// Synthetic data from testing a live instance, which is stored in
+    /// `test_report` instead
+    #[serde(default, skip_serializing)]
+    test: bool,
     #[serde(flatten)]
     extra: Value,
 }
@@ -38,14 +76,35 @@ struct Position {
     extra: Value,
 }
 
+/// A submitted report that has passed the basic checks and is ready to be
+/// written to the database.
+pub struct NewReport {
+    timestamp: DateTime<Utc>,
+    latitude: f64,
+    longitude: f64,
+    user_agent: Option<String>,
+    token_id: Option<i32>,
+    upload_id: Option<String>,
+    raw: Vec<u8>,
+    test: bool,
+}
+
+// items are parsed one at a time as the body arrives, so that one malformed
+// report doesn't cause the rest of the batch to be thrown away. reports are
+// written in batches along the way, so a body that turns out to be truncated
+// or malformed partway through still has its earlier reports kept - they're
+// ignored if the client retries.
 #[post("/v2/geosubmit")]
 pub async fn service(
-    data: web::Json<Submission>,
+    uploader: Uploader,
+    payload: web::Payload,
     pool: web::Data<PgPool>,
+    buffer: Option<web::Data<Buffer>>,
+    config: web::Data<Config>,
     req: HttpRequest,
 ) -> actix_web::Result<impl Responder> {
-    let data = data.into_inner();
     let pool = pool.into_inner();
+    let buffer = buffer.as_ref().map(|x| x.get_ref());
 
     let ua = match req.headers().get(USER_AGENT).map(|x| x.to_str()) {
         Some(Ok(x)) => Some(x),
@@ -55,34 +114,684 @@ pub async fn service(
         None => None,
     };
 
-    insert(&pool, ua, data)
-        .await
-        .context("writing to database failed")
-        .map_err(ErrorInternalServerError)?;
+    // reports can be stored in several batches, and the buffer can write them
+    // at different times, so they're tagged with the upload they came in
+    let upload_id = request_id::unique();
+    let mut payload = Decompress::from_headers(payload.into_inner(), req.headers());
+    let mut items = Items::default();
+    let mut raw = Vec::new();
+    let mut index = 0;
+    let mut reports = Vec::new();
+    let mut accepted = 0;
+    let mut errors = Vec::new();
+    let mut rejected = 0;
+    let mut size = 0;
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        size += chunk.len();
+        if size > MAX_BODY_SIZE {
+            return Err(ErrorPayloadTooLarge("submission is too large"));
+        }
+
+        items.push(&chunk, &mut raw).map_err(ErrorBadRequest)?;
+        for item in raw.drain(..) {
+            match parse_item(index, &item) {
+                Ok(x) => reports.push(x),
+                Err(e) => {
+                    rejected += 1;
+                    if errors.len() < MAX_ERRORS {
+                        errors.push(e);
+                    }
+                }
+            }
+            index += 1;
+        }
+
+        if reports.len() >= BATCH_SIZE {
+            accepted += reports.len();
+            store(&pool, buffer, &config, ua, uploader.0, &upload_id, &reports).await?;
+            reports.clear();
+        }
+    }
+    items.finish().map_err(ErrorBadRequest)?;
+    accepted += reports.len();
+    store(&pool, buffer, &config, ua, uploader.0, &upload_id, &reports).await?;
 
-    Ok(HttpResponse::new(StatusCode::OK))
+    // the valid reports are kept either way
+    if rejected > 0 {
+        return Ok(HttpResponse::UnprocessableEntity().json(serde_json::json!({
+            "accepted": accepted,
+            "rejected": rejected,
+            "errors": errors,
+        })));
+    }
+
+    let default = match buffer {
+        Some(_) => StatusCode::ACCEPTED,
+        None => StatusCode::OK,
+    };
+    Ok(HttpResponse::new(status(&config, ua, default)))
 }
 
-async fn insert(
+async fn store(
     pool: &PgPool,
+    buffer: Option<&Buffer>,
+    config: &Config,
     user_agent: Option<&str>,
-    submission: Submission,
+    token_id: Option<i32>,
+    upload_id: &str,
+    items: &[Report],
+) -> actix_web::Result<()> {
+    if items.is_empty() {
+        return Ok(());
+    }
+    let reports = prepare(user_agent, token_id, Some(upload_id), items, false, config)
+        .context("failed to encode reports")
+        .map_err(ErrorInternalServerError)?;
+
+    if let Some(buffer) = buffer {
+        buffer
+            .push(reports)
+            .await
+            .map_err(ErrorServiceUnavailable)?;
+    } else {
+        let batch_size = config.insert_batch_size.unwrap_or(INSERT_BATCH_SIZE);
+        insert(pool, reports, batch_size)
+            .await
+            .context("writing to database failed")
+            .map_err(ErrorInternalServerError)?;
+    }
+
+    Ok(())
+}
+
+/// Why a report in a submission couldn't be parsed, by its position in the
+/// batch.
+#[derive(Serialize, Debug, PartialEq)]
+struct ItemError {
+    index: usize,
+    error: String,
+}
+
+fn parse_item(index: usize, item: &[u8]) -> Result<Report, ItemError> {
+    serde_json::from_slice(item).map_err(|e| ItemError {
+        index,
+        error: e.to_string(),
+    })
+}
+
+/// The status for a successful submission, unless the client is configured to
+/// get a different one. Tower Collector, for example, treats 202 as an error.
+fn status(config: &Config, user_agent: Option<&str>, default: StatusCode) -> StatusCode {
+    user_agent
+        .and_then(|x| config.submission_status.get(x))
+        .and_then(|x| StatusCode::from_u16(*x).ok())
+        .unwrap_or(default)
+}
+
+/// Encode reports for storage. Only trusted reports, such as ones loaded back by
+/// the ingest command, keep their `ssidsRemoved` flag.
+pub(super) fn prepare(
+    user_agent: Option<&str>,
+    token_id: Option<i32>,
+    upload_id: Option<&str>,
+    items: &[Report],
+    trusted: bool,
+    config: &Config,
+) -> anyhow::Result<Vec<NewReport>> {
+    let mut reports = Vec::new();
+    let now = Utc::now();
+    for report in items
+        .iter()
+        .filter(|r| !near_null_island(r) && !in_future(r, now, config))
+    {
+        let raw = encode(report, trusted, config)?;
+        reports.push(NewReport {
+            timestamp: report.timestamp,
+            latitude: report.position.latitude,
+            longitude: report.position.longitude,
+            user_agent: user_agent.map(ToOwned::to_owned),
+            token_id,
+            upload_id: upload_id.map(ToOwned::to_owned),
+            raw,
+            test: report.test,
+        });
+    }
+
+    Ok(reports)
+}
+
+/// A report as it's stored.
+fn encode(report: &Report, trusted: bool, config: &Config) -> anyhow::Result<Vec<u8>> {
+    let mut raw = serde_json::to_value(report)?;
+    // processing trusts it to mean the ssids were checked, so submissions can't
+    // set it themselves
+    if !trusted {
+        if let Some(x) = raw.as_object_mut() {
+            x.remove("ssidsRemoved");
+        }
+    }
+    if config.strip_ssids {
+        strip_ssids(&mut raw);
+    }
+
+    Ok(serde_json::to_vec(&raw)?)
+}
+
+/// Remove the ssids of a report's access points. They can't be checked once
+/// they're gone, so hidden and opted out access points are dropped here
+/// instead, and the report is marked so that processing keeps the rest. Access
+/// points without ssids in a report that's already marked were checked when it
+/// was first stripped, so they're kept.
+fn strip_ssids(raw: &mut Value) {
+    let checked = raw["ssidsRemoved"] == Value::Bool(true);
+    if let Some(wifi) = raw
+        .get_mut("wifiAccessPoints")
+        .and_then(Value::as_array_mut)
+    {
+        let mac = |x: &Value| serde_json::from_value::<MacAddress>(x["macAddress"].clone()).ok();
+        let rejection = |x: &Value| match report::ssid_rejection(x["ssid"].as_str()) {
+            Some(Rejection::HiddenNetwork) if checked => None,
+            x => x,
+        };
+
+        // an access point listed more than once is dropped entirely if any of
+        // its ssids opted out, as in processing
+        let opted_out: BTreeSet<_> = wifi
+            .iter()
+            .filter(|x| rejection(x) == Some(Rejection::OptedOut))
+            .filter_map(mac)
+            .collect();
+        wifi.retain(|x| rejection(x).is_none() && !mac(x).is_some_and(|x| opted_out.contains(&x)));
+        for x in wifi.iter_mut().filter_map(Value::as_object_mut) {
+            x.remove("ssid");
+        }
+    }
+    raw["ssidsRemoved"] = Value::Bool(true);
+}
+
+// Ignore reports for (-1,-1) to (1, 1)
+fn near_null_island(report: &Report) -> bool {
+    report.position.latitude.abs() <= 1. && report.position.longitude.abs() <= 1.
+}
+
+/// Whether a report is timestamped further ahead of `now` than clock skew
+/// would explain.
+fn in_future(report: &Report, now: DateTime<Utc>, config: &Config) -> bool {
+    let tolerance = config
+        .future_tolerance_secs
+        .unwrap_or(FUTURE_TOLERANCE_SECS);
+    report.timestamp > now + Duration::seconds(tolerance.into())
+}
+
+#[derive(Serialize, Debug, PartialEq, Default)]
+struct Diagnostic {
+    accepted: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    cells: usize,
+    wifi: usize,
+    bluetooth: usize,
+}
+
+/// Runs a submission through the same checks as geosubmit and processing,
+/// without storing anything, so that developers can see what would be used.
+#[post("/v2/geosubmit/validate")]
+pub async fn validate(data: web::Json<Submission>, config: web::Data<Config>) -> impl Responder {
+    let items: Vec<_> = data
+        .into_inner()
+        .items
+        .into_iter()
+        .map(|x| diagnose(x, &config))
+        .collect();
+    HttpResponse::Ok().json(serde_json::json!({ "items": items }))
+}
+
+fn diagnose(item: Value, config: &Config) -> Diagnostic {
+    let rejected = |reason: String| Diagnostic {
+        reason: Some(reason),
+        ..Default::default()
+    };
+
+    let report: Report = match serde_json::from_value(item) {
+        Ok(x) => x,
+        Err(e) => return rejected(format!("invalid report: {e}")),
+    };
+    if near_null_island(&report) {
+        return rejected("position is too close to 0,0".to_owned());
+    }
+    if in_future(&report, Utc::now(), config) {
+        return rejected("timestamp is too far in the future".to_owned());
+    }
+
+    // this is what would be stored and later read back during processing
+    let raw = match encode(&report, false, config) {
+        Ok(x) => x,
+        Err(e) => return rejected(format!("invalid report: {e}")),
+    };
+    let txs = match report::extract(&raw) {
+        Ok(x) => x.transmitters,
+        Err(e) => return rejected(format!("invalid report: {e}")),
+    };
+
+    let mut diagnostic = Diagnostic::default();
+    for x in &txs {
+        match x {
+            Transmitter::Cell { .. } => diagnostic.cells += 1,
+            Transmitter::Wifi { .. } => diagnostic.wifi += 1,
+            Transmitter::Bluetooth { .. } | Transmitter::Beacon { .. } => diagnostic.bluetooth += 1,
+        }
+    }
+    if txs.is_empty() {
+        diagnostic.reason = Some("no usable cell towers, access points or beacons".to_owned());
+    } else {
+        diagnostic.accepted = true;
+    }
+
+    diagnostic
+}
+
+/// Write reports in transactions of up to `batch_size`. A failure leaves the
+/// batches before it written, which is fine as reports are only ever added.
+pub async fn insert(
+    pool: &PgPool,
+    reports: Vec<NewReport>,
+    batch_size: usize,
 ) -> anyhow::Result<()> {
+    for batch in reports.chunks(batch_size.max(1)) {
+        insert_batch(pool, batch).await?;
+    }
+    Ok(())
+}
+
+async fn insert_batch(pool: &PgPool, reports: &[NewReport]) -> anyhow::Result<()> {
     let mut tx = pool.begin().await?;
 
-    for report in submission.items.iter().filter(|r| {
-        // Ignore reports for (-1,-1) to (1, 1)
-        !(r.position.latitude.abs() <= 1. && r.position.longitude.abs() <= 1.)
-    }) {
-        query!("insert into report (timestamp, latitude, longitude, user_agent, raw) values ($1, $2, $3, $4, $5) on conflict do nothing",
+    for report in reports {
+        if report.test {
+            query!("insert into test_report (timestamp, latitude, longitude, user_agent, token_id, raw) values ($1, $2, $3, $4, $5, $6) on conflict do nothing",
+                report.timestamp,
+                report.latitude,
+                report.longitude,
+                report.user_agent,
+                report.token_id,
+                report.raw,
+            ).execute(&mut *tx).await?;
+            continue;
+        }
+
+        query!("insert into report (timestamp, latitude, longitude, user_agent, token_id, upload_id, raw) values ($1, $2, $3, $4, $5, $6, $7) on conflict do nothing",
             report.timestamp,
-            report.position.latitude,
-            report.position.longitude,
-            user_agent,
-            serde_json::to_vec(&report)?,
+            report.latitude,
+            report.longitude,
+            report.user_agent,
+            report.token_id,
+            report.upload_id,
+            report.raw,
         ).execute(&mut *tx).await?;
     }
 
     tx.commit().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, Cursor};
+
+    use actix_web::{
+        test::{call_and_read_body_json, init_service, TestRequest},
+        App,
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::submission::ingest;
+
+    fn config(extra: &str) -> Config {
+        toml::from_str(&format!("database_url = ''\nhttp_port = 0\n{extra}")).unwrap()
+    }
+
+    #[test]
+    fn user_agent_status() {
+        let config: Config = toml::from_str(
+            r#"
+            database_url = ""
+            http_port = 0
+
+            [submission_status]
+            "okhttp/4.12.0" = 200
+            "#,
+        )
+        .unwrap();
+
+        let accepted = StatusCode::ACCEPTED;
+        assert_eq!(
+            status(&config, Some("okhttp/4.12.0"), accepted),
+            StatusCode::OK
+        );
+        assert_eq!(status(&config, Some("okhttp/4.11.0"), accepted), accepted);
+        assert_eq!(status(&config, Some("NeoStumbler/1.0"), accepted), accepted);
+        assert_eq!(status(&config, None, accepted), accepted);
+    }
+
+    #[test]
+    fn partially_malformed() {
+        let items = [
+            json!({
+                "timestamp": 1700000000000u64,
+                "position": { "latitude": 10.0, "longitude": 20.0 },
+            }),
+            json!({ "position": { "latitude": 10.0, "longitude": 20.0 } }),
+            json!({
+                "timestamp": 1700000000000u64,
+                "position": { "latitude": "north", "longitude": 20.0 },
+            }),
+            json!({
+                "timestamp": 1700000000001u64,
+                "position": { "latitude": 11.0, "longitude": 20.0 },
+            }),
+        ];
+
+        let (reports, errors): (Vec<_>, Vec<_>) = items
+            .iter()
+            .enumerate()
+            .map(|(i, x)| parse_item(i, &serde_json::to_vec(x).unwrap()))
+            .partition(Result::is_ok);
+        let reports: Vec<_> = reports.into_iter().map(Result::unwrap).collect();
+        let errors: Vec<_> = errors.into_iter().map(|x| x.err().unwrap()).collect();
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[1].position.latitude, 11.0);
+
+        let errors: Vec<_> = errors.iter().map(|x| (x.index, x.error.as_str())).collect();
+        assert_eq!(errors[0].0, 1);
+        assert!(errors[0].1.starts_with("missing field `timestamp`"));
+        assert_eq!(errors[1].0, 2);
+        assert!(errors[1].1.contains("invalid type: string \"north\""));
+    }
+
+    #[actix_web::test]
+    async fn errors_truncated() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(config("")))
+                .app_data(web::Data::new(
+                    PgPool::connect_lazy("postgres:///beacondb").unwrap(),
+                ))
+                .service(service),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/v2/geosubmit")
+            .set_json(json!({ "items": vec![json!({}); MAX_ERRORS + 50] }))
+            .to_request();
+        let resp: Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(resp["accepted"], 0);
+        assert_eq!(resp["rejected"], MAX_ERRORS + 50);
+        let errors = resp["errors"].as_array().unwrap();
+        assert_eq!(errors.len(), MAX_ERRORS);
+        assert_eq!(errors[MAX_ERRORS - 1]["index"], MAX_ERRORS - 1);
+    }
+
+    #[test]
+    fn positionless_reports() {
+        // transmitter-only reports, as sent by clients that keep positions in
+        // a separate stream, are rejected without affecting the others
+        let body = json!({
+            "items": [
+                {
+                    "timestamp": 1700000000000u64,
+                    "wifiAccessPoints": [{ "macAddress": "00:11:22:33:44:01" }],
+                },
+                {
+                    "timestamp": 1700000000000u64,
+                    "position": { "latitude": 10.0, "longitude": 20.0 },
+                    "wifiAccessPoints": [{ "macAddress": "00:11:22:33:44:01" }],
+                },
+                {
+                    "timestamp": 1700000001000u64,
+                    "position": null,
+                    "cellTowers": [],
+                },
+            ],
+        });
+
+        let mut items = Items::default();
+        let mut raw = Vec::new();
+        items
+            .push(&serde_json::to_vec(&body).unwrap(), &mut raw)
+            .unwrap();
+        items.finish().unwrap();
+        let results: Vec<_> = raw
+            .iter()
+            .enumerate()
+            .map(|(i, x)| parse_item(i, x))
+            .collect();
+
+        assert_eq!(results.len(), 3);
+        assert!(results[1].is_ok());
+        for i in [0, 2] {
+            let error = results[i].as_ref().err().unwrap();
+            assert_eq!(error.index, i);
+            assert!(error.error.contains("position") || error.error.contains("Position"));
+        }
+    }
+
+    #[test]
+    fn test_reports() {
+        let items: Vec<Report> = serde_json::from_value(json!([
+            {
+                "timestamp": 1700000000000u64,
+                "position": { "latitude": 10.0, "longitude": 20.0 },
+                "test": true,
+            },
+            {
+                "timestamp": 1700000000000u64,
+                "position": { "latitude": 11.0, "longitude": 20.0 },
+            },
+        ]))
+        .unwrap();
+
+        let reports = prepare(None, None, None, &items, false, &config("")).unwrap();
+        assert!(reports[0].test);
+        assert!(!reports[1].test);
+        // the flag only decides the table, so isn't stored
+        for x in &reports {
+            let raw: Value = serde_json::from_slice(&x.raw).unwrap();
+            assert!(raw.get("test").is_none());
+        }
+    }
+
+    #[test]
+    fn future_timestamps() {
+        let now = Utc::now();
+        let report = |ahead: Duration| -> Report {
+            serde_json::from_value(json!({
+                "timestamp": (now + ahead).timestamp_millis(),
+                "position": { "latitude": 10.0, "longitude": 20.0 },
+            }))
+            .unwrap()
+        };
+        let items = [
+            report(Duration::days(-1)),
+            // a clock running a few minutes fast
+            report(Duration::minutes(5)),
+            report(Duration::minutes(59)),
+            report(Duration::hours(2)),
+            report(Duration::days(365)),
+        ];
+
+        let kept = |config: &Config| {
+            let reports = prepare(None, None, None, &items, false, config).unwrap();
+            let kept: Vec<_> = reports.iter().map(|x| x.timestamp).collect();
+            items
+                .iter()
+                .map(|x| kept.contains(&x.timestamp))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(kept(&config("")), [true, true, true, false, false]);
+        assert_eq!(
+            kept(&config("future_tolerance_secs = 0")),
+            [true, false, false, false, false]
+        );
+        assert_eq!(
+            kept(&config("future_tolerance_secs = 86400")),
+            [true, true, true, true, false]
+        );
+    }
+
+    #[test]
+    fn stripped_ssids() {
+        let items: Vec<Report> = serde_json::from_value(json!([{
+            "timestamp": 1700000000000u64,
+            "position": { "latitude": 10.0, "longitude": 20.0 },
+            "wifiAccessPoints": [
+                { "macAddress": "00:11:22:33:44:01", "ssid": "home", "signalStrength": -60 },
+                { "macAddress": "00:11:22:33:44:02", "ssid": "" },
+                { "macAddress": "00:11:22:33:44:03", "ssid": "a" },
+                { "macAddress": "00:11:22:33:44:03", "ssid": "a_nomap" },
+                { "macAddress": "00:11:22:33:44:04", "ssid": "b" },
+            ],
+        }]))
+        .unwrap();
+
+        let stripped = config("strip_ssids = true");
+        let reports = prepare(None, None, None, &items, false, &stripped).unwrap();
+        assert!(!String::from_utf8_lossy(&reports[0].raw).contains("ssid\""));
+        let raw: Value = serde_json::from_slice(&reports[0].raw).unwrap();
+        assert_eq!(
+            raw["wifiAccessPoints"],
+            json!([
+                { "macAddress": "00:11:22:33:44:01", "signalStrength": -60 },
+                { "macAddress": "00:11:22:33:44:04" },
+            ])
+        );
+
+        // processing keeps the same access points as it would have with the
+        // ssids
+        let macs = |raw: &[u8]| report::extract(raw).unwrap().transmitters;
+        let kept = macs(&reports[0].raw);
+        assert_eq!(kept, macs(&serde_json::to_vec(&items[0]).unwrap()));
+        assert_eq!(kept.len(), 2);
+    }
+
+    #[test]
+    fn ssids_removed_not_trusted() {
+        // a client can't claim its hidden access points were already checked
+        let items: Vec<Report> = serde_json::from_value(json!([{
+            "timestamp": 1700000000000u64,
+            "position": { "latitude": 10.0, "longitude": 20.0 },
+            "wifiAccessPoints": [
+                { "macAddress": "00:11:22:33:44:01" },
+                { "macAddress": "00:11:22:33:44:02", "ssid": "a_nomap" },
+            ],
+            "ssidsRemoved": true,
+        }]))
+        .unwrap();
+
+        let reports = prepare(None, None, None, &items, false, &config("")).unwrap();
+        let raw: Value = serde_json::from_slice(&reports[0].raw).unwrap();
+        assert!(raw.get("ssidsRemoved").is_none());
+        assert!(report::extract(&reports[0].raw)
+            .unwrap()
+            .transmitters
+            .is_empty());
+
+        let reports = prepare(
+            None,
+            None,
+            None,
+            &items,
+            false,
+            &config("strip_ssids = true"),
+        )
+        .unwrap();
+        let raw: Value = serde_json::from_slice(&reports[0].raw).unwrap();
+        assert_eq!(raw["ssidsRemoved"], true);
+        assert_eq!(raw["wifiAccessPoints"], json!([]));
+    }
+
+    #[test]
+    fn stripped_ingested_again() {
+        let items: Vec<Report> = serde_json::from_value(json!([{
+            "timestamp": 1700000000000u64,
+            "position": { "latitude": 10.0, "longitude": 20.0 },
+            "wifiAccessPoints": [
+                { "macAddress": "00:11:22:33:44:01", "ssid": "a" },
+                { "macAddress": "00:11:22:33:44:02", "ssid": "" },
+            ],
+        }]))
+        .unwrap();
+        let stripped = config("strip_ssids = true");
+        let stored = prepare(None, None, None, &items, false, &stripped).unwrap();
+
+        // the access point kept when the ssids were stripped is still kept once
+        // the archived report is ingested again
+        let archive = [stored[0].raw.as_slice(), b"\n"].concat();
+        let mut lines = Cursor::new(archive).lines().enumerate();
+        let batch = ingest::read_batch(&mut lines, 10).unwrap();
+        let ingested = prepare(None, None, None, &batch, true, &stripped).unwrap();
+        let macs = |raw: &[u8]| report::extract(raw).unwrap().transmitters;
+        assert_eq!(macs(&ingested[0].raw), macs(&stored[0].raw));
+        assert_eq!(macs(&ingested[0].raw).len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn validate_items() {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(config("")))
+                .service(validate),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/v2/geosubmit/validate")
+            .set_json(json!({
+                "items": [
+                    {
+                        "timestamp": 1700000000000u64,
+                        "position": { "latitude": 10.0, "longitude": 20.0 },
+                        "wifiAccessPoints": [
+                            { "macAddress": "00:11:22:33:44:01", "ssid": "network" },
+                            { "macAddress": "00:11:22:33:44:02", "ssid": "network_nomap" },
+                        ],
+                        "bluetoothBeacons": [{ "macAddress": "00:11:22:33:44:03" }],
+                    },
+                    {
+                        "timestamp": 1700000000000u64,
+                        "position": { "latitude": 0.5, "longitude": -0.5 },
+                        "bluetoothBeacons": [{ "macAddress": "00:11:22:33:44:03" }],
+                    },
+                    {
+                        "timestamp": 1700000000000u64,
+                        "position": { "latitude": 10.0, "longitude": 20.0 },
+                        "wifiAccessPoints": [{ "macAddress": "00:00:00:00:00:00", "ssid": "x" }],
+                    },
+                    {
+                        "position": { "latitude": 10.0, "longitude": 20.0 },
+                    },
+                ],
+            }))
+            .to_request();
+        let resp: Value = call_and_read_body_json(&app, req).await;
+        let items = resp["items"].as_array().unwrap();
+        assert_eq!(items.len(), 4);
+
+        assert_eq!(
+            items[0],
+            json!({ "accepted": true, "cells": 0, "wifi": 1, "bluetooth": 1 })
+        );
+        assert_eq!(items[1]["accepted"], false);
+        assert_eq!(items[1]["reason"], "position is too close to 0,0");
+        assert_eq!(items[2]["accepted"], false);
+        assert_eq!(
+            items[2]["reason"],
+            "no usable cell towers, access points or beacons"
+        );
+        assert_eq!(items[3]["accepted"], false);
+        assert!(items[3]["reason"]
+            .as_str()
+            .unwrap()
+            .starts_with("invalid report: missing field `timestamp`"));
+    }
+}
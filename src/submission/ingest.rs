@@ -0,0 +1,212 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use sqlx::PgPool;
+
+use super::{
+    geosubmit::{self, Report},
+    items::Items,
+};
+use crate::config::Config;
+
+// backfilling from archives or other projects without going through http.
+// reports go through the same checks as geosubmit and are processed as usual
+// afterwards. the input is either one report per line, or a whole geosubmit
+// body as clients like NeoStumbler export it.
+
+pub async fn run(
+    pool: PgPool,
+    path: Option<&Path>,
+    user_agent: Option<&str>,
+    batch_size: usize,
+    geosubmit: bool,
+    config: &Config,
+) -> Result<()> {
+    let reader: Box<dyn BufRead> = match path {
+        Some(path) => Box::new(BufReader::new(
+            File::open(path).context("Failed to open input")?,
+        )),
+        None => Box::new(io::stdin().lock()),
+    };
+
+    let mut input = if geosubmit {
+        Input::Body(Body::new(reader))
+    } else {
+        Input::Lines(Box::new(reader.lines().enumerate()))
+    };
+    let mut total = 0;
+    loop {
+        let batch = match &mut input {
+            Input::Lines(lines) => read_batch(lines, batch_size.max(1))?,
+            Input::Body(body) => body.read_batch(batch_size.max(1))?,
+        };
+        if batch.is_empty() {
+            break;
+        }
+
+        let reports = geosubmit::prepare(user_agent, None, None, &batch, true, config)?;
+        total += reports.len();
+        geosubmit::insert(&pool, reports, batch_size).await?;
+        eprintln!("inserted {total} reports");
+    }
+
+    eprintln!("finished ingesting");
+    Ok(())
+}
+
+/// Read up to `size` reports, skipping blank lines. An empty batch means the
+/// input has ended.
+pub(super) fn read_batch(
+    lines: &mut impl Iterator<Item = (usize, io::Result<String>)>,
+    size: usize,
+) -> Result<Vec<Report>> {
+    let mut batch = Vec::new();
+    for (i, line) in lines {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let report = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid report on line {}", i + 1))?;
+        batch.push(report);
+        if batch.len() >= size {
+            break;
+        }
+    }
+
+    Ok(batch)
+}
+
+enum Input {
+    Lines(Box<dyn Iterator<Item = (usize, io::Result<String>)>>),
+    Body(Body<Box<dyn BufRead>>),
+}
+
+/// The items of a geosubmit body, split as they're read so that the whole
+/// body never has to be held in memory.
+struct Body<R> {
+    reader: R,
+    /// Until the end of the input is reached
+    items: Option<Items>,
+    raw: Vec<Vec<u8>>,
+    index: usize,
+}
+
+impl<R: Read> Body<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            items: Some(Items::default()),
+            raw: Vec::new(),
+            index: 0,
+        }
+    }
+
+    /// Read up to `size` reports. An empty batch means the input has ended.
+    fn read_batch(&mut self, size: usize) -> Result<Vec<Report>> {
+        let mut buf = vec![0; 64 * 1024];
+        while self.raw.len() < size {
+            let Some(items) = &mut self.items else {
+                break;
+            };
+            let n = self.reader.read(&mut buf)?;
+            if n == 0 {
+                self.items
+                    .take()
+                    .unwrap_or_default()
+                    .finish()
+                    .context("Invalid geosubmit body")?;
+                break;
+            }
+            items
+                .push(&buf[..n], &mut self.raw)
+                .context("Invalid geosubmit body")?;
+        }
+
+        let n = self.raw.len().min(size);
+        let mut batch = Vec::new();
+        for item in self.raw.drain(..n) {
+            let report = serde_json::from_slice(&item)
+                .with_context(|| format!("Invalid report at index {}", self.index))?;
+            batch.push(report);
+            self.index += 1;
+        }
+
+        Ok(batch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn ndjson() {
+        let input = r#"{"timestamp":1700000000000,"position":{"latitude":10.0,"longitude":20.0},"wifiAccessPoints":[{"macAddress":"00:11:22:33:44:01","ssid":"a"}]}
+{"timestamp":1700000001000,"position":{"latitude":0.5,"longitude":0.5}}
+
+{"timestamp":1700000002000,"position":{"latitude":10.1,"longitude":20.1}}
+"#;
+        let mut lines = Cursor::new(input).lines().enumerate();
+
+        let first = read_batch(&mut lines, 2).unwrap();
+        assert_eq!(first.len(), 2);
+        // the same filters as geosubmit apply
+        let config: Config = toml::from_str("database_url = ''\nhttp_port = 0").unwrap();
+        let reports =
+            geosubmit::prepare(Some("backfill"), None, None, &first, true, &config).unwrap();
+        assert_eq!(reports.len(), 1);
+
+        let second = read_batch(&mut lines, 2).unwrap();
+        assert_eq!(second.len(), 1);
+        assert!(read_batch(&mut lines, 2).unwrap().is_empty());
+
+        let mut lines = Cursor::new("{}\n").lines().enumerate();
+        let err = read_batch(&mut lines, 2).err().unwrap();
+        assert_eq!(err.to_string(), "Invalid report on line 1");
+    }
+
+    #[test]
+    fn geosubmit_body() {
+        // as exported by NeoStumbler, with cells, access points and beacons
+        let input = r#"{"items": [
+            {
+                "timestamp": 1700000000000,
+                "position": {"latitude": 10.0, "longitude": 20.0, "accuracy": 8.5, "source": "gps"},
+                "cellTowers": [{"radioType": "lte", "mobileCountryCode": 505, "mobileNetworkCode": 1, "locationAreaCode": 2, "cellId": 3, "primaryScramblingCode": 4}],
+                "wifiAccessPoints": [{"macAddress": "00:11:22:33:44:01", "ssid": "a", "signalStrength": -60}],
+                "bluetoothBeacons": [{"macAddress": "00:11:22:33:44:02", "signalStrength": -70}]
+            },
+            {"timestamp": 1700000001000, "position": {"latitude": 10.1, "longitude": 20.1}},
+            {"timestamp": 1700000002000, "position": {"latitude": 10.2, "longitude": 20.2}}
+        ]}"#;
+        // smaller than the body, so that it's read in several chunks
+        let mut body = Body::new(BufReader::with_capacity(16, Cursor::new(input)));
+
+        let first = body.read_batch(2).unwrap();
+        assert_eq!(first.len(), 2);
+        let raw = serde_json::to_vec(&first[0]).unwrap();
+        let extracted = super::super::report::extract(&raw).unwrap();
+        assert_eq!(extracted.transmitters.len(), 3);
+        assert_eq!(extracted.position.accuracy, Some(8.5));
+
+        assert_eq!(body.read_batch(2).unwrap().len(), 1);
+        assert!(body.read_batch(2).unwrap().is_empty());
+        assert!(body.read_batch(2).unwrap().is_empty());
+
+        let mut body = Body::new(Cursor::new(r#"{"items": [{}]}"#));
+        let err = body.read_batch(2).err().unwrap();
+        assert_eq!(err.to_string(), "Invalid report at index 0");
+
+        let mut body = Body::new(Cursor::new(r#"{"items": [{}"#));
+        let err = body.read_batch(2).err().unwrap();
+        assert_eq!(err.to_string(), "Invalid geosubmit body");
+    }
+}
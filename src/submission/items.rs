@@ -0,0 +1,221 @@
+use anyhow::{bail, Result};
+
+// geosubmit batches can be hundreds of megabytes, so rather than parsing the
+// whole body at once, the `items` array is split into the raw json of each
+// item as the body arrives. only enough of the json is understood to find
+// where each item starts and ends - the items themselves are parsed by serde
+// afterwards, so malformed items are still reported one at a time.
+
+/// The largest a single item may be, in bytes. Even a long track with a few
+/// hundred access points at each point stays well under this.
+const MAX_ITEM_SIZE: usize = 4 * 1024 * 1024;
+
+#[derive(Default)]
+pub struct Items {
+    depth: usize,
+    started: bool,
+    in_string: bool,
+    escaped: bool,
+    /// The last string seen directly in the top level object, which is the
+    /// key when an array starts
+    key: Vec<u8>,
+    in_items: bool,
+    seen_items: bool,
+    /// The item currently being read, when inside `items`
+    item: Vec<u8>,
+}
+
+impl Items {
+    /// Feed the next chunk of the body, adding every item completed by it to
+    /// `out`.
+    pub fn push(&mut self, chunk: &[u8], out: &mut Vec<Vec<u8>>) -> Result<()> {
+        for &b in chunk {
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if b == b'\\' {
+                    self.escaped = true;
+                } else if b == b'"' {
+                    self.in_string = false;
+                }
+                self.take(b)?;
+                continue;
+            }
+
+            match b {
+                b' ' | b'\t' | b'\n' | b'\r' => {
+                    if !self.item.is_empty() {
+                        self.take(b)?;
+                    }
+                }
+                b'{' | b'[' => {
+                    if self.depth == 0 {
+                        if b != b'{' || self.started {
+                            bail!("expected an object");
+                        }
+                        self.started = true;
+                    }
+                    if self.depth == 1 && b == b'[' && self.key == b"items" {
+                        if self.seen_items {
+                            bail!("duplicate field `items`");
+                        }
+                        self.in_items = true;
+                        self.seen_items = true;
+                    } else {
+                        self.take(b)?;
+                    }
+                    self.depth += 1;
+                }
+                b'}' | b']' => {
+                    if self.depth == 0 {
+                        bail!("unexpected '{}'", b as char);
+                    }
+                    self.depth -= 1;
+                    if self.in_items && self.depth == 1 {
+                        // the end of the items array, maybe after a scalar item
+                        self.finish_item(out);
+                        self.in_items = false;
+                    } else {
+                        self.take(b)?;
+                        if self.in_items && self.depth == 2 {
+                            self.finish_item(out);
+                        }
+                    }
+                }
+                b',' if self.in_items && self.depth == 2 => self.finish_item(out),
+                _ => {
+                    if self.depth == 0 {
+                        bail!("expected an object");
+                    }
+                    if b == b'"' {
+                        self.in_string = true;
+                        if self.depth == 1 {
+                            self.key.clear();
+                        }
+                    }
+                    self.take(b)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that the whole body was read.
+    pub fn finish(self) -> Result<()> {
+        if !self.started || self.depth != 0 || self.in_string {
+            bail!("unexpected end of body");
+        }
+        if !self.seen_items {
+            bail!("missing field `items`");
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, b: u8) -> Result<()> {
+        if self.in_items && self.depth >= 2 {
+            if self.item.len() >= MAX_ITEM_SIZE {
+                bail!("item is larger than {MAX_ITEM_SIZE} bytes");
+            }
+            self.item.push(b);
+        } else if self.depth == 1 && (self.in_string || b == b'"') && self.key.len() < 16 {
+            // a key is only ever compared against "items", so longer ones
+            // don't need to be kept
+            if b != b'"' {
+                self.key.push(b);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish_item(&mut self, out: &mut Vec<Vec<u8>>) {
+        // scalar items run up to the next comma, so may end with whitespace
+        while self.item.last().is_some_and(u8::is_ascii_whitespace) {
+            self.item.pop();
+        }
+        if !self.item.is_empty() {
+            out.push(std::mem::take(&mut self.item));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(body: &str, chunk_size: usize) -> Result<Vec<String>> {
+        let mut items = Items::default();
+        let mut out = Vec::new();
+        for chunk in body.as_bytes().chunks(chunk_size) {
+            items.push(chunk, &mut out)?;
+        }
+        items.finish()?;
+        Ok(out
+            .into_iter()
+            .map(|x| String::from_utf8(x).unwrap())
+            .collect())
+    }
+
+    #[test]
+    fn split_items() {
+        let body = r#" {
+            "other": {"items": [1, 2]},
+            "items": [
+                {"a": [1, {"b": "]},\"["}]},
+                {},
+                3,
+                "x"
+            ]
+        }"#;
+        for chunk_size in [1, 2, 7, body.len()] {
+            assert_eq!(
+                split(body, chunk_size).unwrap(),
+                [r#"{"a": [1, {"b": "]},\"["}]}"#, "{}", "3", r#""x""#]
+            );
+        }
+
+        assert_eq!(split(r#"{"items": []}"#, 1).unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn malformed_body() {
+        assert!(split(r#"{"items": [{}, {"a": 1}"#, 3).is_err());
+        assert!(split(r#"{"item": []}"#, 3).is_err());
+        assert!(split(r#"[{"items": []}]"#, 3).is_err());
+        assert!(split(r#"{"items": [{}]}}"#, 3).is_err());
+        assert!(split(r#"{"items": [], "items": []}"#, 3).is_err());
+        assert!(split(r#"{"items": []} {}"#, 3).is_err());
+        assert!(split("", 3).is_err());
+    }
+
+    #[test]
+    fn large_batch() {
+        let item = r#"{"timestamp":1700000000000,"position":{"latitude":10.0,"longitude":20.0},"wifiAccessPoints":[{"macAddress":"00:11:22:33:44:55"}]}"#;
+        let count = 200_000;
+
+        let mut items = Items::default();
+        let mut out = Vec::new();
+        let mut total = 0;
+        let mut push = |items: &mut Items, chunk: &[u8]| {
+            items.push(chunk, &mut out).unwrap();
+            total += out.len();
+            assert!(out.iter().all(|x| x == item.as_bytes()));
+            out.clear();
+            // nothing more than the item in progress is held on to
+            assert!(items.item.len() < item.len());
+        };
+
+        // ~25MB, fed in as it's generated rather than built up front
+        push(&mut items, br#"{"items": ["#);
+        for i in 0..count {
+            let separator = if i == 0 { "" } else { ",\n" };
+            for chunk in format!("{separator}{item}").as_bytes().chunks(1000) {
+                push(&mut items, chunk);
+            }
+        }
+        push(&mut items, b"]}");
+        items.finish().unwrap();
+
+        assert_eq!(total, count);
+    }
+}
@@ -1,3 +1,11 @@
+pub mod buffer;
+pub mod correct;
 pub mod geosubmit;
+pub mod ingest;
+mod items;
 pub mod process;
+pub mod purge;
 pub mod report;
+pub mod retention;
+pub mod suspect;
+pub mod token;
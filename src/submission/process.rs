@@ -1,26 +1,41 @@
 use std::{
     collections::{BTreeMap, BTreeSet},
-    fs,
+    fs, mem,
     path::Path,
+    time::{Duration, SystemTime},
 };
 
 use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
 use futures::{StreamExt, TryStreamExt};
 use h3o::LatLng;
 use serde::Serialize;
 use sqlx::{query, query_scalar, PgPool};
 
-use crate::{bounds::Bounds, config::StatsConfig, model::Transmitter};
+use super::report::{Position, Rejection};
+use crate::{
+    bounds::Bounds,
+    config::{CellUnit, Config, WifiStorage},
+    geoip::{mcc, Country},
+    model::{CellRadio, Seen, Transmitter, Upserts, WIFI_RESOLUTION},
+};
+
+// modified transmitters are written this many to a statement unless
+// configured otherwise
+const UPSERT_BATCH_SIZE: usize = 1000;
 
-pub async fn run(pool: PgPool, config: Option<&StatsConfig>) -> Result<()> {
+pub async fn run(pool: PgPool, config: &Config) -> Result<()> {
     loop {
         let mut tx = pool.begin().await?;
         let mut reports =
-            query!("select id, raw, user_agent from report where processed_at is null order by id limit 10000")
+            query!("select id, timestamp, raw, user_agent from report where processed_at is null order by id limit 10000")
                 .fetch_all(&mut *tx)
                 .await?;
-        let mut modified: BTreeMap<Transmitter, Bounds> = BTreeMap::new();
+        let mut modified: BTreeMap<Transmitter, (Bounds, Seen)> = BTreeMap::new();
         let mut h3s = BTreeSet::new();
+        let mut wifi_h3s = BTreeSet::new();
+        let mut capabilities = BTreeMap::new();
+        let mut rejections: BTreeMap<Rejection, usize> = BTreeMap::new();
 
         let last_report_in_batch = if let Some(report) = reports.last() {
             report.id
@@ -37,7 +52,7 @@ pub async fn run(pool: PgPool, config: Option<&StatsConfig>) -> Result<()> {
             .execute(&mut *tx)
             .await?;
 
-            let (pos, txs) = match super::report::extract(&report.raw) {
+            let mut extracted = match super::report::extract(&report.raw) {
                 Ok(x) => x,
                 Err(e) => {
                     eprintln!(
@@ -56,62 +71,98 @@ pub async fn run(pool: PgPool, config: Option<&StatsConfig>) -> Result<()> {
                 }
             };
 
-            for x in txs {
-                if let Some(b) = modified.get_mut(&x) {
-                    *b = *b + (pos.latitude, pos.longitude);
-                } else if let Some(b) = x.lookup(&pool).await? {
-                    modified.insert(x, b + (pos.latitude, pos.longitude));
-                } else {
-                    modified.insert(x, Bounds::new(pos.latitude, pos.longitude));
-                }
+            if config.wifi_storage == WifiStorage::Bounds {
+                capabilities.extend(mem::take(&mut extracted.capabilities));
+            }
+            for (reason, count) in &extracted.rejections {
+                *rejections.entry(*reason).or_default() += count;
             }
 
-            let pos = LatLng::new(pos.latitude, pos.longitude)?;
-            let h3 = pos.to_cell(crate::map::RESOLUTION);
-            h3s.insert(h3);
-        }
+            for &x in &extracted.transmitters {
+                if !allowed_cell(&x, config.cell_countries.as_ref()) {
+                    *rejections.entry(Rejection::CountryNotAllowed).or_default() += 1;
+                    continue;
+                }
+                if !allowed_radio(&x, config.cell_radios.as_ref()) {
+                    *rejections.entry(Rejection::RadioNotAllowed).or_default() += 1;
+                    continue;
+                }
+                let x = match (config.cell_unit, x) {
+                    (CellUnit::Merge, Transmitter::Cell { unit: 0, .. }) => {
+                        let mut units = stored_units(&x, &pool).await?;
+                        units.extend(batch_units(&x, &modified));
+                        resolve_unit(x, config.cell_unit, &units)
+                    }
+                    _ => x,
+                };
 
-        let modified_count = modified.len();
-        for (x, b) in modified {
-            match x {
-                Transmitter::Cell {
-                    radio,
-                    country,
-                    network,
-                    area,
-                    cell,
-                    unit,
-                } => {
-                    query!(
-                        "insert into cell (radio, country, network, area, cell, unit, min_lat, min_lon, max_lat, max_lon) values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
-                         on conflict (radio, country, network, area, cell, unit) do update set min_lat = EXCLUDED.min_lat, min_lon = EXCLUDED.min_lon, max_lat = EXCLUDED.max_lat, max_lon = EXCLUDED.max_lon
-                        ",
-                    radio as i16, country, network, area, cell, unit, b.min_lat, b.min_lon, b.max_lat, b.max_lon
-                )
-                .execute(&mut *tx)
-                .await?;
+                // reports with a track place each transmitter separately
+                let pos = extracted.position_of(&x);
+                if !accurate_enough(&x, &pos, config) {
+                    *rejections.entry(Rejection::InaccuratePosition).or_default() += 1;
+                    continue;
                 }
-                Transmitter::Wifi { mac } => {
-                    query!(
-                        "insert into wifi (mac, min_lat, min_lon, max_lat, max_lon) values ($1, $2, $3, $4, $5)
-                         on conflict (mac) do update set min_lat = EXCLUDED.min_lat, min_lon = EXCLUDED.min_lon, max_lat = EXCLUDED.max_lat, max_lon = EXCLUDED.max_lon
-                        ",
-                    &mac, b.min_lat, b.min_lon, b.max_lat, b.max_lon
-                )
-                .execute(&mut *tx)
-                .await?;
+                let latlng = LatLng::new(pos.latitude, pos.longitude)?;
+                if let (WifiStorage::H3, Transmitter::Wifi { mac }) = (config.wifi_storage, x) {
+                    wifi_h3s.insert((mac, latlng.to_cell(WIFI_RESOLUTION)));
+                    continue;
                 }
-                Transmitter::Bluetooth { mac } => {
-                    query!(
-                        "insert into bluetooth (mac, min_lat, min_lon, max_lat, max_lon) values ($1, $2, $3, $4, $5)
-                         on conflict (mac) do update set min_lat = EXCLUDED.min_lat, min_lon = EXCLUDED.min_lon, max_lat = EXCLUDED.max_lat, max_lon = EXCLUDED.max_lon
-                        ",
-                    &mac, b.min_lat, b.min_lon, b.max_lat, b.max_lon
-                )
-                .execute(&mut *tx)
-                .await?;
+                if let (true, Transmitter::Wifi { mac }) = (config.geolocate.wifi_h3_fallback, x) {
+                    wifi_h3s.insert((mac, latlng.to_cell(WIFI_RESOLUTION)));
                 }
+
+                // days seen are counted from what was stored, so that a day
+                // already counted by an earlier run isn't counted again
+                let stored = match modified.get(&x) {
+                    Some((b, seen)) => Some((*b, Some(*seen))),
+                    None => x.lookup(&pool).await?.map(|row| row.split()),
+                };
+                match observe(&x, stored, &pos, report.timestamp, config) {
+                    Ok(observed) => {
+                        modified.insert(x, observed);
+                    }
+                    Err(reason) => *rejections.entry(reason).or_default() += 1,
+                }
+            }
+
+            let pos = extracted.position;
+            let h3 = LatLng::new(pos.latitude, pos.longitude)?.to_cell(crate::map::RESOLUTION);
+            h3s.insert(h3);
+        }
+
+        let modified_count = match config.wifi_storage {
+            WifiStorage::Bounds => modified.len(),
+            WifiStorage::H3 => modified.len() + wifi_h3s.len(),
+        };
+        let modified: Vec<_> = modified.into_iter().collect();
+        let batch_size = config.upsert_batch_size.unwrap_or(UPSERT_BATCH_SIZE).max(1);
+        for batch in modified.chunks(batch_size) {
+            let mut upserts = Upserts::default();
+            for (x, (b, seen)) in batch {
+                upserts.push(*x, b, *seen);
             }
+            upserts.save(&mut tx).await?;
+        }
+
+        for (mac, capabilities) in capabilities {
+            query!(
+                "update wifi set capabilities = $2 where mac = $1",
+                mac,
+                capabilities
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        for (mac, h3) in wifi_h3s {
+            let h3_binary = u64::from(h3).to_be_bytes();
+            query!(
+                "insert into wifi_h3 (mac, h3) values ($1, $2) on conflict do nothing",
+                mac,
+                &h3_binary
+            )
+            .execute(&mut *tx)
+            .await?;
         }
 
         for h3 in h3s {
@@ -126,27 +177,46 @@ pub async fn run(pool: PgPool, config: Option<&StatsConfig>) -> Result<()> {
 
         tx.commit().await?;
         eprintln!("processed reports up to #{last_report_in_batch} - {modified_count} transmitters modified");
+        if !rejections.is_empty() {
+            let counts: Vec<_> = rejections
+                .iter()
+                .map(|(reason, count)| format!("{reason}: {count}"))
+                .collect();
+            eprintln!("skipped observations - {}", counts.join(", "));
+        }
     }
 
-    if let Some(config) = config {
+    if let Some(stats_config) = &config.stats {
+        let modified = fs::metadata(&stats_config.path).and_then(|x| x.modified());
+        let interval = stats_config.refresh_interval_secs.map(Duration::from_secs);
+        if !stats_due(modified.ok(), SystemTime::now(), interval) {
+            eprintln!("stats were refreshed recently, skipping");
+            return Ok(());
+        }
+
         let stats = Stats {
-            total_wifi: query_scalar!("select count(*) from wifi")
-                .fetch_one(&pool)
-                .await?
-                .unwrap_or_default(),
+            total_wifi: match config.wifi_storage {
+                WifiStorage::Bounds => query_scalar!("select count(*) from wifi"),
+                WifiStorage::H3 => query_scalar!("select count(distinct mac) from wifi_h3"),
+            }
+            .fetch_one(&pool)
+            .await?
+            .unwrap_or_default(),
             total_cell: query_scalar!("select count(*) from cell")
                 .fetch_one(&pool)
                 .await?
                 .unwrap_or_default(),
-            total_bluetooth: query_scalar!("select count(*) from bluetooth")
-                .fetch_one(&pool)
-                .await?
-                .unwrap_or_default(),
+            total_bluetooth: query_scalar!(
+                "select (select count(*) from bluetooth) + (select count(*) from beacon)"
+            )
+            .fetch_one(&pool)
+            .await?
+            .unwrap_or_default(),
             total_countries: query_scalar!("select count(distinct country) from cell")
                 .fetch_one(&pool)
                 .await?
                 .unwrap_or_default(),
-            total_reports: config.archived_reports
+            total_reports: stats_config.archived_reports
                 + query_scalar!("select count(*) from report")
                     .fetch_one(&pool)
                     .await?
@@ -154,12 +224,158 @@ pub async fn run(pool: PgPool, config: Option<&StatsConfig>) -> Result<()> {
         };
 
         let data = serde_json::to_string_pretty(&stats)?;
-        fs::write(&config.path, data)?;
+        fs::write(&stats_config.path, data)?;
     }
 
     Ok(())
 }
 
+/// Add an observation to a transmitter's bounds and when it was seen, or the
+/// reason it was skipped. One that the bounds ignore doesn't count as a
+/// sighting either. The position's own accuracy is kept apart from the bounds,
+/// so that the transmitter is never placed more precisely than the fixes it
+/// came from.
+pub(super) fn observe(
+    x: &Transmitter,
+    stored: Option<(Bounds, Option<Seen>)>,
+    pos: &Position,
+    timestamp: DateTime<Utc>,
+    config: &Config,
+) -> Result<(Bounds, Seen), Rejection> {
+    let observed = (pos.latitude, pos.longitude);
+    let (b, seen) = match stored {
+        Some((b, seen)) => match b.add_capped(observed, config.max_span.get(x)) {
+            Some(b) => (b, seen),
+            None => return Err(Rejection::SpanExceeded),
+        },
+        None => (observed.into(), None),
+    };
+    let seen = match seen {
+        Some(seen) => seen.add(timestamp),
+        None => Seen::new(timestamp),
+    };
+    Ok((b, seen.with_accuracy(pos.accuracy)))
+}
+
+/// Whether a position is accurate enough to place a transmitter from, going
+/// by `max_accuracy`. Positions without an accuracy are taken as they are.
+pub(super) fn accurate_enough(x: &Transmitter, pos: &Position, config: &Config) -> bool {
+    match (pos.accuracy, config.max_accuracy.get(x)) {
+        (Some(accuracy), Some(max)) => accuracy <= max,
+        _ => true,
+    }
+}
+
+/// Whether a transmitter is in scope, going by the configured cell countries.
+/// Other transmitters are always allowed.
+pub(super) fn allowed_cell(x: &Transmitter, countries: Option<&BTreeSet<Country>>) -> bool {
+    match (x, countries) {
+        (Transmitter::Cell { country, .. }, Some(allowed)) => {
+            mcc::countries(*country).iter().any(|x| allowed.contains(x))
+        }
+        _ => true,
+    }
+}
+
+/// The psc/pci specific rows stored for a cell.
+pub(super) async fn stored_units(x: &Transmitter, pool: &PgPool) -> Result<BTreeSet<i16>> {
+    let Transmitter::Cell {
+        radio,
+        country,
+        network,
+        area,
+        cell,
+        ..
+    } = *x
+    else {
+        return Ok(BTreeSet::new());
+    };
+    let units = query_scalar!(
+        "select unit from cell where radio = $1 and country = $2 and network = $3 and area = $4 and cell = $5 and unit != 0",
+        radio as i16, country, network, area, cell
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(units.into_iter().collect())
+}
+
+/// The psc/pci specific variants of a cell modified earlier in the batch, which
+/// aren't stored yet.
+fn batch_units<T>(x: &Transmitter, modified: &BTreeMap<Transmitter, T>) -> BTreeSet<i16> {
+    let Transmitter::Cell {
+        radio,
+        country,
+        network,
+        area,
+        cell,
+        ..
+    } = *x
+    else {
+        return BTreeSet::new();
+    };
+    let with_unit = |unit| Transmitter::Cell {
+        radio,
+        country,
+        network,
+        area,
+        cell,
+        unit,
+    };
+    modified
+        .range(with_unit(i16::MIN)..=with_unit(i16::MAX))
+        .filter_map(|(x, _)| match x {
+            Transmitter::Cell { unit, .. } if *unit != 0 => Some(*unit),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The cell an observation without a psc/pci (stored as unit 0) is recorded
+/// as. When merging, that's the psc-specific cell if there's exactly one,
+/// since with several there's no telling which one was seen.
+pub(super) fn resolve_unit(x: Transmitter, mode: CellUnit, units: &BTreeSet<i16>) -> Transmitter {
+    match (mode, x) {
+        (
+            CellUnit::Merge,
+            Transmitter::Cell {
+                radio,
+                country,
+                network,
+                area,
+                cell,
+                unit: 0,
+            },
+        ) if units.len() == 1 => Transmitter::Cell {
+            radio,
+            country,
+            network,
+            area,
+            cell,
+            unit: *units.first().unwrap(),
+        },
+        _ => x,
+    }
+}
+
+/// Whether a transmitter is in scope, going by the configured cell radio
+/// types. Other transmitters are always allowed.
+pub(super) fn allowed_radio(x: &Transmitter, radios: Option<&BTreeSet<CellRadio>>) -> bool {
+    match (x, radios) {
+        (Transmitter::Cell { radio, .. }, Some(allowed)) => allowed.contains(radio),
+        _ => true,
+    }
+}
+
+/// Whether stats last written at `modified` should be recounted.
+fn stats_due(modified: Option<SystemTime>, now: SystemTime, interval: Option<Duration>) -> bool {
+    match (modified, interval) {
+        (Some(modified), Some(interval)) => now
+            .duration_since(modified)
+            .map_or(true, |age| age >= interval),
+        _ => true,
+    }
+}
+
 #[derive(Serialize)]
 struct Stats {
     total_wifi: i64,
@@ -168,3 +384,223 @@ struct Stats {
     total_countries: i64,
     total_reports: i64,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cell_countries() {
+        let cell = |country| Transmitter::Cell {
+            radio: crate::model::CellRadio::Lte,
+            country,
+            network: 1,
+            area: 1,
+            cell: 1,
+            unit: 0,
+        };
+        let wifi = Transmitter::Wifi {
+            mac: "00:11:22:33:44:01".parse().unwrap(),
+        };
+        let allowed = BTreeSet::from([Country::AU, Country::NZ]);
+
+        assert!(allowed_cell(&cell(505), Some(&allowed)));
+        assert!(allowed_cell(&cell(530), Some(&allowed)));
+        assert!(!allowed_cell(&cell(262), Some(&allowed)));
+        // unassigned codes belong to no country
+        assert!(!allowed_cell(&cell(1), Some(&allowed)));
+        assert!(allowed_cell(&wifi, Some(&allowed)));
+
+        assert!(allowed_cell(&cell(262), None));
+    }
+
+    #[test]
+    fn ignored_positions_not_seen() {
+        let wifi = Transmitter::Wifi {
+            mac: "00:11:22:33:44:01".parse().unwrap(),
+        };
+        let config: Config =
+            toml::from_str("database_url = ''\nhttp_port = 0\n[max_span]\nwifi = 2000").unwrap();
+        let t = |days: i64| DateTime::from_timestamp(1_700_000_000 + days * 86400, 0).unwrap();
+        let pos = |latitude, accuracy| Position {
+            latitude,
+            longitude: 153.02,
+            accuracy,
+        };
+
+        let first = observe(&wifi, None, &pos(-27.47, Some(20.0)), t(0), &config).unwrap();
+        let near = observe(
+            &wifi,
+            Some((first.0, Some(first.1))),
+            &pos(-27.471, None),
+            t(1),
+            &config,
+        )
+        .unwrap();
+        assert_eq!(near.1.days, 2);
+        assert_eq!(near.1.accuracy, Some(20.0));
+
+        // ~50km away, so neither the bounds nor the days seen change
+        let far = observe(
+            &wifi,
+            Some((near.0, Some(near.1))),
+            &pos(-27.0, Some(5.0)),
+            t(5),
+            &config,
+        );
+        assert_eq!(far.err(), Some(Rejection::SpanExceeded));
+
+        // transmitters stored before they were timestamped start counting now
+        let legacy = observe(
+            &wifi,
+            Some((first.0, None)),
+            &pos(-27.47, None),
+            t(3),
+            &config,
+        )
+        .unwrap();
+        assert_eq!((legacy.1.first, legacy.1.days), (t(3), 1));
+    }
+
+    #[test]
+    fn max_accuracy() {
+        let wifi = Transmitter::Wifi {
+            mac: "00:11:22:33:44:01".parse().unwrap(),
+        };
+        let cell = lte(1, 0);
+        let pos = |accuracy| Position {
+            latitude: -27.47,
+            longitude: 153.02,
+            accuracy,
+        };
+        let config = |extra: &str| -> Config {
+            toml::from_str(&format!("database_url = ''\nhttp_port = 0\n{extra}")).unwrap()
+        };
+
+        let defaults = config("");
+        assert!(accurate_enough(&wifi, &pos(Some(20.0)), &defaults));
+        assert!(accurate_enough(&wifi, &pos(None), &defaults));
+        // a poor fix would otherwise widen the access point for good
+        assert!(!accurate_enough(&wifi, &pos(Some(2000.0)), &defaults));
+        assert!(accurate_enough(&cell, &pos(Some(2000.0)), &defaults));
+
+        let strict = config("[max_accuracy]\nwifi = 10\ncell = 1000");
+        assert!(!accurate_enough(&wifi, &pos(Some(20.0)), &strict));
+        assert!(!accurate_enough(&cell, &pos(Some(2000.0)), &strict));
+    }
+
+    #[test]
+    fn cell_radios() {
+        let raw = serde_json::json!({
+            "timestamp": 0,
+            "position": { "latitude": 10.0, "longitude": 20.0 },
+            "cellTowers": [
+                { "radioType": "gsm", "mobileCountryCode": 505, "mobileNetworkCode": 1, "locationAreaCode": 1, "cellId": 1 },
+                { "radioType": "wcdma", "mobileCountryCode": 505, "mobileNetworkCode": 1, "locationAreaCode": 1, "cellId": 2 },
+                { "radioType": "lte", "mobileCountryCode": 505, "mobileNetworkCode": 1, "locationAreaCode": 1, "cellId": 3 },
+            ],
+            "wifiAccessPoints": [{ "macAddress": "00:11:22:33:44:01", "ssid": "a" }],
+        });
+        let extracted = super::super::report::extract(&serde_json::to_vec(&raw).unwrap()).unwrap();
+        let kept = |radios: Option<&BTreeSet<CellRadio>>| {
+            extracted
+                .transmitters
+                .iter()
+                .filter(|x| allowed_radio(x, radios))
+                .count()
+        };
+
+        assert_eq!(kept(None), 4);
+        let lte = BTreeSet::from([CellRadio::Lte]);
+        let kept_lte: Vec<_> = extracted
+            .transmitters
+            .iter()
+            .filter(|x| allowed_radio(x, Some(&lte)))
+            .collect();
+        assert_eq!(kept_lte.len(), 2);
+        assert!(matches!(
+            kept_lte[0],
+            Transmitter::Cell {
+                radio: CellRadio::Lte,
+                ..
+            }
+        ));
+        assert!(matches!(kept_lte[1], Transmitter::Wifi { .. }));
+        assert_eq!(
+            kept(Some(&BTreeSet::from([CellRadio::Gsm, CellRadio::Wcdma]))),
+            3
+        );
+    }
+
+    fn lte(cell: i64, unit: i16) -> Transmitter {
+        Transmitter::Cell {
+            radio: crate::model::CellRadio::Lte,
+            country: 505,
+            network: 1,
+            area: 1,
+            cell,
+            unit,
+        }
+    }
+
+    #[test]
+    fn separate_units() {
+        let units = BTreeSet::from([100]);
+        assert_eq!(
+            resolve_unit(lte(1, 0), CellUnit::Separate, &units),
+            lte(1, 0)
+        );
+        assert_eq!(
+            resolve_unit(lte(1, 101), CellUnit::Separate, &units),
+            lte(1, 101)
+        );
+    }
+
+    #[test]
+    fn merged_units() {
+        let modified = BTreeMap::from([
+            (lte(1, 0), ()),
+            (lte(1, 100), ()),
+            (lte(2, 101), ()),
+            (lte(3, 102), ()),
+            (lte(3, 103), ()),
+        ]);
+
+        let units = batch_units(&lte(1, 0), &modified);
+        assert_eq!(units, BTreeSet::from([100]));
+        assert_eq!(
+            resolve_unit(lte(1, 0), CellUnit::Merge, &units),
+            lte(1, 100)
+        );
+        // psc-specific cells are kept as reported
+        assert_eq!(
+            resolve_unit(lte(1, 101), CellUnit::Merge, &units),
+            lte(1, 101)
+        );
+
+        // ambiguous
+        let units = batch_units(&lte(3, 0), &modified);
+        assert_eq!(units.len(), 2);
+        assert_eq!(resolve_unit(lte(3, 0), CellUnit::Merge, &units), lte(3, 0));
+
+        // nothing to merge into
+        let units = batch_units(&lte(4, 0), &modified);
+        assert_eq!(resolve_unit(lte(4, 0), CellUnit::Merge, &units), lte(4, 0));
+    }
+
+    #[test]
+    fn stats_interval() {
+        let now = SystemTime::now();
+        let hour = Duration::from_secs(3600);
+
+        // always refreshed without an interval, or without existing stats
+        assert!(stats_due(Some(now), now, None));
+        assert!(stats_due(None, now, Some(hour)));
+
+        assert!(!stats_due(Some(now - hour / 2), now, Some(hour)));
+        assert!(stats_due(Some(now - hour), now, Some(hour)));
+        assert!(stats_due(Some(now - hour * 2), now, Some(hour)));
+        // clock went backwards
+        assert!(stats_due(Some(now + hour), now, Some(hour)));
+    }
+}
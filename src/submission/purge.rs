@@ -0,0 +1,417 @@
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet};
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use h3o::LatLng;
+use sqlx::{query, PgPool};
+
+use super::{process, report::Position};
+use crate::{
+    bounds::Bounds,
+    config::{CellUnit, Config, WifiStorage},
+    model::{Seen, Transmitter, WIFI_RESOLUTION},
+};
+
+// bounds only ever grow as reports are processed, so deleting a contributor's
+// reports doesn't undo their effect on the transmitters they submitted. every
+// transmitter that appeared in a purged report is instead rebuilt from scratch
+// using the remaining processed reports, or deleted if none are left.
+//
+// this requires a full scan of the processed reports, as there is no index from
+// transmitters back to the reports they were seen in. unprocessed reports are
+// left alone: they haven't contributed yet and will be processed as usual.
+// when each transmitter was seen is rebuilt along with its bounds, so the
+// purged reports no longer count towards min_days_seen or min_wifi_days. the
+// coverage map is not modified. transmitters that processing would skip are
+// deleted rather than rebuilt.
+//
+// reports moved out of the database by retention can't be replayed, as the
+// archives don't record who submitted them, so rebuilding would drop everything
+// they contributed. purge refuses to run once anything has been archived unless
+// that loss is explicitly accepted.
+
+pub async fn run(
+    pool: PgPool,
+    config: &Config,
+    user_agent: Option<String>,
+    token_id: Option<i32>,
+    discard_archived: bool,
+) -> Result<()> {
+    if user_agent.is_none() && token_id.is_none() {
+        bail!("either a user agent or a token id must be provided");
+    }
+    let archived = query!(r#"select coalesce(sum(reports), 0) as "reports!" from archive"#)
+        .fetch_one(&pool)
+        .await?
+        .reports;
+    if archived > 0 && !discard_archived {
+        bail!(
+            "{archived} reports have been archived by retention and can't be replayed, so \
+             purging would drop what they contributed to the affected transmitters - pass \
+             --discard-archived to purge anyway"
+        );
+    }
+
+    let mut tx = pool.begin().await?;
+
+    let mut affected = BTreeSet::new();
+    let mut purged = 0;
+    let mut q = query!(
+        r#"delete from report where ($1::text is null or user_agent = $1) and ($2::integer is null or token_id = $2)
+           returning raw, (processed_at is not null and processing_error is null) as "contributed!""#,
+        user_agent,
+        token_id
+    )
+    .fetch(&mut *tx);
+    while let Some(report) = q.try_next().await? {
+        purged += 1;
+        if report.contributed {
+            if let Ok(x) = super::report::extract(&report.raw) {
+                affected.extend(x.transmitters);
+            }
+        }
+    }
+    drop(q);
+    let merged = merged_units(&pool, config, &affected).await?;
+    let affected: BTreeSet<_> = affected
+        .into_iter()
+        .map(|x| merged.get(&x).copied().unwrap_or(x))
+        .collect();
+    eprintln!(
+        "purged {purged} reports - {} transmitters affected",
+        affected.len()
+    );
+
+    let mut rebuilt = BTreeMap::new();
+    let mut q = query!(
+        "select raw, timestamp from report where processed_at is not null and processing_error is null order by id"
+    )
+    .fetch(&mut *tx);
+    while let Some(report) = q.try_next().await? {
+        rebuild(
+            &mut rebuilt,
+            &affected,
+            &merged,
+            &report.raw,
+            report.timestamp,
+            config,
+        );
+    }
+    drop(q);
+
+    for x in affected {
+        let positions = rebuilt.get(&x).map(Vec::as_slice).unwrap_or_default();
+        if let (Transmitter::Wifi { mac }, true) = (
+            x,
+            config.wifi_storage == WifiStorage::H3 || config.geolocate.wifi_h3_fallback,
+        ) {
+            query!("delete from wifi_h3 where mac = $1", mac)
+                .execute(&mut *tx)
+                .await?;
+
+            let mut cells = BTreeSet::new();
+            for (pos, _) in positions {
+                cells.insert(LatLng::new(pos.latitude, pos.longitude)?.to_cell(WIFI_RESOLUTION));
+            }
+            for h3 in cells {
+                let h3_binary = u64::from(h3).to_be_bytes();
+                query!(
+                    "insert into wifi_h3 (mac, h3) values ($1, $2) on conflict do nothing",
+                    mac,
+                    &h3_binary
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+
+        match (config.wifi_storage, x) {
+            (WifiStorage::H3, Transmitter::Wifi { .. }) => (),
+            _ => match replay(&x, positions, config) {
+                Some((b, seen)) => x.save(&b, seen, &mut tx).await?,
+                None => x.delete(&mut tx).await?,
+            },
+        }
+    }
+
+    tx.commit().await?;
+    eprintln!("finished purging - {} transmitters kept", rebuilt.len());
+
+    Ok(())
+}
+
+/// The cell that observations of each affected cell without a psc/pci were
+/// stored as, when they are merged into the psc-specific cell.
+async fn merged_units(
+    pool: &PgPool,
+    config: &Config,
+    affected: &BTreeSet<Transmitter>,
+) -> Result<BTreeMap<Transmitter, Transmitter>> {
+    let mut merged = BTreeMap::new();
+    if config.cell_unit != CellUnit::Merge {
+        return Ok(merged);
+    }
+
+    for x in affected {
+        let Transmitter::Cell {
+            radio,
+            country,
+            network,
+            area,
+            cell,
+            ..
+        } = *x
+        else {
+            continue;
+        };
+        let x = Transmitter::Cell {
+            radio,
+            country,
+            network,
+            area,
+            cell,
+            unit: 0,
+        };
+        if let Entry::Vacant(entry) = merged.entry(x) {
+            let units = process::stored_units(&x, pool).await?;
+            entry.insert(process::resolve_unit(x, config.cell_unit, &units));
+        }
+    }
+
+    Ok(merged)
+}
+
+/// A transmitter's bounds and when it was seen, as processing would have built
+/// them from only these observations.
+fn replay(
+    x: &Transmitter,
+    observations: &[(Position, DateTime<Utc>)],
+    config: &Config,
+) -> Option<(Bounds, Seen)> {
+    let mut stored = None;
+    for (pos, timestamp) in observations {
+        let previous = stored.map(|(b, seen)| (b, Some(seen)));
+        if let Ok(x) = process::observe(x, previous, pos, *timestamp, config) {
+            stored = Some(x);
+        }
+    }
+    stored
+}
+
+/// Record a report's position against any affected transmitters it contains.
+/// Reports that fail to parse are skipped, as are transmitters that aren't
+/// stored, as they were during processing. Cells are first resolved through
+/// `merged`, the same as the affected ones were.
+fn rebuild(
+    rebuilt: &mut BTreeMap<Transmitter, Vec<(Position, DateTime<Utc>)>>,
+    affected: &BTreeSet<Transmitter>,
+    merged: &BTreeMap<Transmitter, Transmitter>,
+    raw: &[u8],
+    timestamp: DateTime<Utc>,
+    config: &Config,
+) {
+    let Ok(report) = super::report::extract(raw) else {
+        return;
+    };
+
+    let stored = |x: &Transmitter| {
+        process::allowed_cell(x, config.cell_countries.as_ref())
+            && process::allowed_radio(x, config.cell_radios.as_ref())
+    };
+    for x in report.transmitters.iter().filter(|x| stored(x)) {
+        let x = merged.get(x).copied().unwrap_or(*x);
+        if !affected.contains(&x) {
+            continue;
+        }
+        let pos = report.position_of(&x);
+        if process::accurate_enough(&x, &pos, config) {
+            rebuilt.entry(x).or_default().push((pos, timestamp));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mac_address::MacAddress;
+
+    use super::*;
+    use crate::model::CellRadio;
+
+    fn report(lat: f64, lon: f64, macs: &[&str]) -> Vec<u8> {
+        let wifi: Vec<_> = macs
+            .iter()
+            .map(|x| serde_json::json!({ "macAddress": x, "ssid": "test" }))
+            .collect();
+        serde_json::to_vec(&serde_json::json!({
+            "timestamp": 0,
+            "position": { "latitude": lat, "longitude": lon },
+            "wifiAccessPoints": wifi,
+        }))
+        .unwrap()
+    }
+
+    fn wifi(mac: &str) -> Transmitter {
+        Transmitter::Wifi {
+            mac: mac.parse::<MacAddress>().unwrap(),
+        }
+    }
+
+    fn config(extra: &str) -> Config {
+        toml::from_str(&format!("database_url = ''\nhttp_port = 0\n{extra}")).unwrap()
+    }
+
+    fn t(days: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000 + days * 86400, 0).unwrap()
+    }
+
+    #[test]
+    fn rebuild_affected() {
+        let config = config("");
+        let affected = BTreeSet::from([wifi("00:11:22:33:44:55"), wifi("00:11:22:33:44:66")]);
+
+        // remaining reports after the bad contributor's report at (50, 50)
+        // was purged
+        let mut rebuilt = BTreeMap::new();
+        for (raw, timestamp) in [
+            (
+                report(10.0, 20.0, &["00:11:22:33:44:55", "00:11:22:33:44:77"]),
+                t(0),
+            ),
+            (report(10.1, 20.1, &["00:11:22:33:44:55"]), t(0)),
+        ] {
+            rebuild(
+                &mut rebuilt,
+                &affected,
+                &BTreeMap::new(),
+                &raw,
+                timestamp,
+                &config,
+            );
+        }
+
+        let observations = &rebuilt[&wifi("00:11:22:33:44:55")];
+        let positions: Vec<_> = observations
+            .iter()
+            .map(|(x, _)| (x.latitude, x.longitude))
+            .collect();
+        assert_eq!(positions, [(10.0, 20.0), (10.1, 20.1)]);
+
+        // only seen in the purged report, so it should be deleted
+        assert!(!rebuilt.contains_key(&wifi("00:11:22:33:44:66")));
+        // not affected by the purge, so it is left as is
+        assert!(!rebuilt.contains_key(&wifi("00:11:22:33:44:77")));
+    }
+
+    #[test]
+    fn replay_seen() {
+        let config = config("[max_span]\nwifi = 2000");
+        let x = wifi("00:11:22:33:44:55");
+        let pos = |latitude, accuracy| Position {
+            latitude,
+            longitude: 20.0,
+            accuracy,
+        };
+
+        // the purged contributor's reports were on other days, and less
+        // accurate, so none of that is kept
+        let observations = [
+            (pos(10.0, Some(10.0)), t(3)),
+            (pos(10.001, None), t(3)),
+            // past max_span, so ignored as it was when processed
+            (pos(11.0, Some(50.0)), t(9)),
+        ];
+        let (b, seen) = replay(&x, &observations, &config).unwrap();
+        assert_eq!((b.min_lat, b.max_lat), (10.0, 10.001));
+        assert_eq!((seen.first, seen.last, seen.days), (t(3), t(3), 1));
+        assert_eq!(seen.accuracy, Some(10.0));
+
+        assert!(replay(&x, &[], &config).is_none());
+    }
+
+    #[test]
+    fn rebuild_stored_only() {
+        let cell = |country| Transmitter::Cell {
+            radio: CellRadio::Lte,
+            country,
+            network: 1,
+            area: 1,
+            cell: 1,
+            unit: 0,
+        };
+        let raw = serde_json::to_vec(&serde_json::json!({
+            "timestamp": 0,
+            "position": { "latitude": 10.0, "longitude": 20.0 },
+            "cellTowers": [
+                { "radioType": "lte", "mobileCountryCode": 505, "mobileNetworkCode": 1, "locationAreaCode": 1, "cellId": 1 },
+                { "radioType": "lte", "mobileCountryCode": 262, "mobileNetworkCode": 1, "locationAreaCode": 1, "cellId": 1 },
+                { "radioType": "gsm", "mobileCountryCode": 505, "mobileNetworkCode": 1, "locationAreaCode": 1, "cellId": 1 },
+            ],
+        }))
+        .unwrap();
+        let gsm = Transmitter::Cell {
+            radio: CellRadio::Gsm,
+            country: 505,
+            network: 1,
+            area: 1,
+            cell: 1,
+            unit: 0,
+        };
+        let affected = BTreeSet::from([cell(505), cell(262), gsm]);
+
+        let mut rebuilt = BTreeMap::new();
+        let config = config("cell_countries = ['AU']\ncell_radios = ['lte']");
+        rebuild(
+            &mut rebuilt,
+            &affected,
+            &BTreeMap::new(),
+            &raw,
+            t(0),
+            &config,
+        );
+        // processing never stores the other country's cell or the gsm one, so
+        // they are deleted
+        assert!(rebuilt.contains_key(&cell(505)));
+        assert!(!rebuilt.contains_key(&cell(262)));
+        assert!(!rebuilt.contains_key(&gsm));
+    }
+
+    #[test]
+    fn rebuild_merged_units() {
+        let cell = |unit| Transmitter::Cell {
+            radio: CellRadio::Lte,
+            country: 505,
+            network: 1,
+            area: 1,
+            cell: 1,
+            unit,
+        };
+        let raw = serde_json::to_vec(&serde_json::json!({
+            "timestamp": 0,
+            "position": { "latitude": 10.0, "longitude": 20.0 },
+            "cellTowers": [
+                { "radioType": "lte", "mobileCountryCode": 505, "mobileNetworkCode": 1, "locationAreaCode": 1, "cellId": 1 },
+            ],
+        }))
+        .unwrap();
+        let affected = BTreeSet::from([cell(101)]);
+
+        // without a psc, but stored as the only psc-specific cell known
+        let mut rebuilt = BTreeMap::new();
+        let merged = BTreeMap::from([(cell(0), cell(101))]);
+        rebuild(&mut rebuilt, &affected, &merged, &raw, t(0), &config(""));
+        assert_eq!(rebuilt[&cell(101)].len(), 1);
+        assert!(!rebuilt.contains_key(&cell(0)));
+
+        let mut rebuilt = BTreeMap::new();
+        rebuild(
+            &mut rebuilt,
+            &affected,
+            &BTreeMap::new(),
+            &raw,
+            t(0),
+            &config(""),
+        );
+        assert!(rebuilt.is_empty());
+    }
+}
@@ -1,30 +1,48 @@
+use std::collections::{BTreeMap, BTreeSet};
+
 use anyhow::Result;
 use mac_address::MacAddress;
 use serde::Deserialize;
+use strum::Display;
 
-use crate::model::{CellRadio, Transmitter};
+use crate::model::{BeaconId, CellRadio, Transmitter};
 
-// TODO: use the age value?
-// location interpolation should be client side imo, but that would require a
-// new submission format
+// location interpolation should be client side imo. clients collecting while
+// moving can instead send a short track in `positions`, and each observation
+// is placed at the sample closest to it in time, going by their `age` values.
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct Report {
     timestamp: u64,
     position: Position,
+    positions: Option<Vec<TrackPoint>>,
     cell_towers: Option<Vec<Cell>>,
     wifi_access_points: Option<Vec<Wifi>>,
     bluetooth_beacons: Option<Vec<Bluetooth>>,
+    /// Set when the ssids were checked and removed on submission
+    #[serde(default)]
+    ssids_removed: bool,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
 pub struct Position {
     pub latitude: f64,
     pub longitude: f64,
+    /// Metres
+    pub accuracy: Option<f64>,
 }
 
 #[derive(Deserialize)]
+struct TrackPoint {
+    #[serde(flatten)]
+    position: Position,
+    // milliseconds before the report's timestamp, as with observations
+    #[serde(default)]
+    age: i64,
+}
+
+#[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct Cell {
     radio_type: RadioType,
@@ -36,9 +54,11 @@ struct Cell {
     cell_id: u64,
     #[serde(default)]
     primary_scrambling_code: u16,
+    #[serde(default)]
+    age: i64,
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Deserialize)]
 #[serde(rename_all = "lowercase")]
 enum RadioType {
     Gsm,
@@ -53,18 +73,103 @@ enum RadioType {
 struct Wifi {
     mac_address: MacAddress,
     ssid: Option<String>,
+    // security flags as reported by the device, such as "[WPA2-PSK-CCMP][ESS]"
+    #[serde(alias = "encryption")]
+    capabilities: Option<String>,
+    #[serde(default)]
+    age: i64,
 }
 
 #[derive(Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct Bluetooth {
     mac_address: MacAddress,
+    name: Option<String>,
+    // the identity advertised by an iBeacon, the uuid in hex with or without
+    // dashes
+    uuid: Option<String>,
+    major: Option<u16>,
+    minor: Option<u16>,
+    // the identity advertised by an Eddystone-UID beacon, both in hex
+    namespace: Option<String>,
+    instance: Option<String>,
+    #[serde(default)]
+    age: i64,
 }
 
-pub fn extract(raw: &[u8]) -> Result<(Position, Vec<Transmitter>)> {
+impl Bluetooth {
+    /// The beacon's advertised identity, if it sent a complete and valid one.
+    fn beacon_id(&self) -> Option<BeaconId> {
+        if let (Some(uuid), Some(major), Some(minor)) = (&self.uuid, self.major, self.minor) {
+            return Some(BeaconId::IBeacon {
+                uuid: hex(uuid)?,
+                major,
+                minor,
+            });
+        }
+        let (Some(namespace), Some(instance)) = (&self.namespace, &self.instance) else {
+            return None;
+        };
+        Some(BeaconId::Eddystone {
+            namespace: hex(namespace)?,
+            instance: hex(instance)?,
+        })
+    }
+}
+
+/// Why an observation in a report wasn't used.
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[strum(serialize_all = "snake_case")]
+pub enum Rejection {
+    /// A cell without a country, area or cell id
+    IncompleteCell,
+    /// A cell with identifiers too large to store
+    CellOutOfRange,
+    /// A placeholder or group address
+    InvalidMac,
+    /// An access point without an ssid, whether it was left out, empty or only
+    /// NUL characters, as its opt out can't be checked
+    HiddenNetwork,
+    /// An access point or beacon whose name opts out of being mapped
+    OptedOut,
+    /// A cell from a country that isn't in `cell_countries`
+    CountryNotAllowed,
+    /// A cell of a radio type that isn't in `cell_radios`
+    RadioNotAllowed,
+    /// An observation from a position less accurate than `max_accuracy`
+    InaccuratePosition,
+    /// An observation that would stretch the transmitter's bounds past
+    /// `max_span`
+    SpanExceeded,
+}
+
+pub struct Extracted {
+    pub position: Position,
+    pub transmitters: Vec<Transmitter>,
+    /// The capabilities of access points that reported them
+    pub capabilities: BTreeMap<MacAddress, String>,
+    /// Where transmitters were seen, for reports with a track
+    observed_at: BTreeMap<Transmitter, Position>,
+    /// How many observations were skipped, and why
+    pub rejections: BTreeMap<Rejection, usize>,
+}
+
+impl Extracted {
+    /// Where a transmitter in this report was seen.
+    pub fn position_of(&self, x: &Transmitter) -> Position {
+        self.observed_at.get(x).copied().unwrap_or(self.position)
+    }
+}
+
+pub fn extract(raw: &[u8]) -> Result<Extracted> {
     let parsed: Report = serde_json::from_slice(raw)?;
 
-    let mut txs = Vec::new();
+    // devices that rescan can list the same cell more than once, possibly with
+    // a different signal strength each time
+    let mut cells = BTreeSet::new();
+    let mut ages = BTreeMap::new();
+    let mut rejections = BTreeMap::new();
+    let mut reject = |x| *rejections.entry(x).or_default() += 1;
     for cell in parsed.cell_towers.unwrap_or_default() {
         if cell.mobile_country_code == 0
                 // || cell.mobile_network_code == 0 // this is valid
@@ -72,41 +177,625 @@ pub fn extract(raw: &[u8]) -> Result<(Position, Vec<Transmitter>)> {
                 || cell.cell_id == 0
         {
             // TODO: reuse previous cell tower data
+            reject(Rejection::IncompleteCell);
             continue;
         }
 
-        txs.push(Transmitter::Cell {
+        // postgres uses signed integers, so anything that doesn't fit would
+        // otherwise wrap around into a different cell
+        let (Ok(country), Ok(network), Ok(area), Ok(cell_id), Ok(unit)) = (
+            i16::try_from(cell.mobile_country_code),
+            i16::try_from(cell.mobile_network_code),
+            i32::try_from(cell.location_area_code),
+            i64::try_from(cell.cell_id),
+            i16::try_from(cell.primary_scrambling_code),
+        ) else {
+            eprintln!("Skipping cell with out of range identifiers: {cell:?}");
+            reject(Rejection::CellOutOfRange);
+            continue;
+        };
+
+        let tx = Transmitter::Cell {
             radio: match cell.radio_type {
                 RadioType::Gsm => CellRadio::Gsm,
                 RadioType::Umts => CellRadio::Wcdma,
                 RadioType::Lte => CellRadio::Lte,
                 RadioType::Nr => CellRadio::Nr,
             },
-            // postgres uses signed integers
-            country: cell.mobile_country_code as i16,
-            network: cell.mobile_network_code as i16,
-            area: cell.location_area_code as i32,
-            cell: cell.cell_id as i64,
-            unit: cell.primary_scrambling_code as i16,
-        })
+            country,
+            network,
+            area,
+            cell: cell_id,
+            unit,
+        };
+        cells.insert(tx);
+        ages.entry(tx).or_insert(cell.age);
     }
+    let mut txs: Vec<_> = cells.into_iter().collect();
+
+    // the same access point can be reported more than once in a single
+    // report, possibly with a different ssid each time. it should only
+    // contribute once, and not at all if any of its ssids opted out.
+    let mut accepted = BTreeSet::new();
+    let mut rejected = BTreeSet::new();
+    let mut capabilities = BTreeMap::new();
     for wifi in parsed.wifi_access_points.unwrap_or_default() {
-        // ignore hidden networks
-        let ssid = wifi
-            .ssid
-            .map(|x| x.replace('\0', ""))
-            .filter(|x| !x.is_empty());
-        if ssid.is_some_and(|x| !x.contains("_nomap") && !x.contains("_optout")) {
-            txs.push(Transmitter::Wifi {
-                mac: wifi.mac_address,
-            });
+        if !valid_mac(&wifi.mac_address) {
+            reject(Rejection::InvalidMac);
+            continue;
         }
+        ages.entry(Transmitter::Wifi {
+            mac: wifi.mac_address,
+        })
+        .or_insert(wifi.age);
+        if let Some(x) = wifi
+            .capabilities
+            .map(|x| x.replace('\0', "").trim().to_owned())
+            .filter(|x| !x.is_empty())
+        {
+            capabilities.entry(wifi.mac_address).or_insert(x);
+        }
+
+        match ssid_rejection(wifi.ssid.as_deref()) {
+            None => accepted.insert(wifi.mac_address),
+            Some(Rejection::HiddenNetwork) if parsed.ssids_removed => {
+                accepted.insert(wifi.mac_address)
+            }
+            Some(Rejection::OptedOut) => {
+                reject(Rejection::OptedOut);
+                rejected.insert(wifi.mac_address)
+            }
+            // ignore hidden networks
+            Some(x) => {
+                reject(x);
+                false
+            }
+        };
     }
+    for mac in accepted.difference(&rejected) {
+        txs.push(Transmitter::Wifi { mac: *mac });
+    }
+    capabilities.retain(|mac, _| accepted.contains(mac) && !rejected.contains(mac));
     for bt in parsed.bluetooth_beacons.unwrap_or_default() {
-        txs.push(Transmitter::Bluetooth {
-            mac: bt.mac_address,
-        })
+        // beacons that advertise an identity are stored by it, so their mac
+        // address doesn't matter, and is often random anyway
+        let tx = match bt.beacon_id() {
+            Some(id) => Transmitter::Beacon { id },
+            None if valid_mac(&bt.mac_address) => Transmitter::Bluetooth {
+                mac: bt.mac_address,
+            },
+            None => {
+                reject(Rejection::InvalidMac);
+                continue;
+            }
+        };
+
+        // unlike wifi, most beacons don't advertise a name at all
+        if normalize_bt_name(bt.name).is_some_and(|x| opted_out(&x)) {
+            reject(Rejection::OptedOut);
+            continue;
+        }
+
+        txs.push(tx);
+        ages.entry(tx).or_insert(bt.age);
     }
 
-    Ok((parsed.position, txs))
+    let track = parsed.positions.unwrap_or_default();
+    let mut observed_at = BTreeMap::new();
+    if !track.is_empty() {
+        for x in &txs {
+            let age = ages.get(x).copied().unwrap_or_default();
+            observed_at.insert(*x, closest(&track, parsed.position, age));
+        }
+    }
+
+    Ok(Extracted {
+        position: parsed.position,
+        transmitters: txs,
+        capabilities,
+        observed_at,
+        rejections,
+    })
+}
+
+/// The position in a track (or the report's own position, at age 0) that was
+/// recorded closest in time to an observation.
+fn closest(track: &[TrackPoint], position: Position, age: i64) -> Position {
+    track
+        .iter()
+        .map(|x| (x.age, x.position))
+        .chain([(0, position)])
+        .min_by_key(|(x, _)| x.abs_diff(age))
+        .map_or(position, |(_, x)| x)
+}
+
+/// Some devices fill in a placeholder when the real address isn't available.
+/// Group addresses never belong to a single transmitter either.
+fn valid_mac(mac: &MacAddress) -> bool {
+    let bytes = mac.bytes();
+    // the broadcast address is also a multicast address
+    let multicast = bytes[0] & 0x01 != 0;
+    bytes != [0; 6] && !multicast
+}
+
+/// Why an access point with this ssid can't be used, if it can't.
+pub(super) fn ssid_rejection(ssid: Option<&str>) -> Option<Rejection> {
+    match ssid.map(|x| x.replace('\0', "")).filter(|x| !x.is_empty()) {
+        Some(x) if opted_out(&x) => Some(Rejection::OptedOut),
+        Some(_) => None,
+        None => Some(Rejection::HiddenNetwork),
+    }
+}
+
+/// Names are set by the owner of a device, so they can opt out of being
+/// mapped by adding one of these markers.
+fn opted_out(name: &str) -> bool {
+    name.contains("_nomap") || name.contains("_optout")
+}
+
+/// Parse exactly `N` bytes of hex, ignoring any dashes as in uuids.
+fn hex<const N: usize>(x: &str) -> Option<[u8; N]> {
+    let digits: Vec<u8> = x.bytes().filter(|x| *x != b'-').collect();
+    if digits.len() != N * 2 || !digits.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    let mut bytes = [0; N];
+    for (byte, pair) in bytes.iter_mut().zip(digits.chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Bluetooth device names are often left as the owner's name, so they are
+/// cleaned up the same way regardless of where they were submitted from.
+fn normalize_bt_name(name: Option<String>) -> Option<String> {
+    name.map(|x| x.replace('\0', "").trim().to_owned())
+        .filter(|x| !x.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn cells(cell_towers: serde_json::Value) -> Vec<Transmitter> {
+        let raw = json!({
+            "timestamp": 0,
+            "position": { "latitude": 10.0, "longitude": 20.0 },
+            "cellTowers": cell_towers,
+        });
+        extract(&serde_json::to_vec(&raw).unwrap())
+            .unwrap()
+            .transmitters
+    }
+
+    #[test]
+    fn cell_in_range() {
+        let txs = cells(json!([{
+            "radioType": "lte",
+            "mobileCountryCode": 505,
+            "mobileNetworkCode": 1,
+            "locationAreaCode": 12345,
+            "cellId": 268435455,
+            "primaryScramblingCode": 100,
+        }]));
+        assert_eq!(
+            txs,
+            [Transmitter::Cell {
+                radio: CellRadio::Lte,
+                country: 505,
+                network: 1,
+                area: 12345,
+                cell: 268435455,
+                unit: 100,
+            }]
+        );
+    }
+
+    #[test]
+    fn duplicate_cells() {
+        let cell = |psc, signal| {
+            json!({
+                "radioType": "lte",
+                "mobileCountryCode": 505,
+                "mobileNetworkCode": 1,
+                "locationAreaCode": 12345,
+                "cellId": 1234,
+                "primaryScramblingCode": psc,
+                "signalStrength": signal,
+            })
+        };
+        let txs = cells(json!([cell(100, -80), cell(100, -70), cell(101, -90)]));
+        assert_eq!(txs.len(), 2);
+        assert!(matches!(txs[0], Transmitter::Cell { unit: 100, .. }));
+        assert!(matches!(txs[1], Transmitter::Cell { unit: 101, .. }));
+    }
+
+    #[test]
+    fn cell_out_of_range() {
+        let txs = cells(json!([
+            {
+                "radioType": "nr",
+                "mobileCountryCode": 505,
+                "mobileNetworkCode": 1,
+                "locationAreaCode": 12345,
+                "cellId": 9223372036854775808u64,
+            },
+            {
+                "radioType": "gsm",
+                "mobileCountryCode": 505,
+                "mobileNetworkCode": 1,
+                "locationAreaCode": 4294967295u32,
+                "cellId": 1234,
+            },
+            {
+                "radioType": "wcdma",
+                "mobileCountryCode": 505,
+                "mobileNetworkCode": 1,
+                "locationAreaCode": 12345,
+                "cellId": 1234,
+                "primaryScramblingCode": 65535,
+            },
+        ]));
+        assert!(txs.is_empty());
+    }
+
+    #[test]
+    fn bt_names() {
+        assert_eq!(normalize_bt_name(None), None);
+        assert_eq!(normalize_bt_name(Some(String::new())), None);
+        assert_eq!(normalize_bt_name(Some("\0\0".to_owned())), None);
+        assert_eq!(normalize_bt_name(Some("   ".to_owned())), None);
+        assert_eq!(
+            normalize_bt_name(Some(" Headphones\0 ".to_owned())),
+            Some("Headphones".to_owned())
+        );
+
+        assert!(opted_out("Headphones_nomap"));
+        assert!(opted_out("Tracker_optout"));
+        assert!(!opted_out("Headphones"));
+    }
+
+    #[test]
+    fn bt_opt_out() {
+        let raw = json!({
+            "timestamp": 0,
+            "position": { "latitude": 10.0, "longitude": 20.0 },
+            "bluetoothBeacons": [
+                { "macAddress": "00:11:22:33:44:01" },
+                { "macAddress": "00:11:22:33:44:02", "name": "\0" },
+                { "macAddress": "00:11:22:33:44:03", "name": " Speaker " },
+                { "macAddress": "00:11:22:33:44:04", "name": "Speaker_nomap" },
+                { "macAddress": "00:11:22:33:44:05", "name": "Watch_optout\0" },
+            ],
+        });
+        let txs = extract(&serde_json::to_vec(&raw).unwrap())
+            .unwrap()
+            .transmitters;
+        let macs: Vec<_> = txs
+            .into_iter()
+            .map(|x| match x {
+                Transmitter::Bluetooth { mac } => mac.to_string(),
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            macs,
+            [
+                "00:11:22:33:44:01",
+                "00:11:22:33:44:02",
+                "00:11:22:33:44:03"
+            ]
+        );
+    }
+
+    #[test]
+    fn beacon_identities() {
+        let raw = json!({
+            "timestamp": 0,
+            "position": { "latitude": 10.0, "longitude": 20.0 },
+            "bluetoothBeacons": [
+                // the same iBeacon behind two random addresses
+                {
+                    "macAddress": "d2:11:22:33:44:01",
+                    "uuid": "f7826da6-4fa2-4e98-8024-bc5b71e0893e",
+                    "major": 1,
+                    "minor": 2,
+                },
+                {
+                    "macAddress": "e3:11:22:33:44:02",
+                    "uuid": "F7826DA64FA24E988024BC5B71E0893E",
+                    "major": 1,
+                    "minor": 2,
+                },
+                {
+                    "macAddress": "00:00:00:00:00:00",
+                    "namespace": "edd1ebeac04e5defa017",
+                    "instance": "0123456789ab",
+                },
+                // incomplete or invalid identities fall back to the mac
+                { "macAddress": "00:11:22:33:44:03", "uuid": "f7826da6", "major": 1, "minor": 2 },
+                { "macAddress": "00:11:22:33:44:04", "namespace": "edd1ebeac04e5defa017" },
+                { "macAddress": "00:11:22:33:44:05" },
+                { "macAddress": "00:00:00:00:00:00", "uuid": "not a uuid", "major": 1, "minor": 2 },
+            ],
+        });
+        let txs = extract(&serde_json::to_vec(&raw).unwrap())
+            .unwrap()
+            .transmitters;
+
+        let ibeacon = Transmitter::Beacon {
+            id: BeaconId::IBeacon {
+                uuid: [
+                    0xf7, 0x82, 0x6d, 0xa6, 0x4f, 0xa2, 0x4e, 0x98, 0x80, 0x24, 0xbc, 0x5b, 0x71,
+                    0xe0, 0x89, 0x3e,
+                ],
+                major: 1,
+                minor: 2,
+            },
+        };
+        let eddystone = Transmitter::Beacon {
+            id: BeaconId::Eddystone {
+                namespace: [0xed, 0xd1, 0xeb, 0xea, 0xc0, 0x4e, 0x5d, 0xef, 0xa0, 0x17],
+                instance: [0x01, 0x23, 0x45, 0x67, 0x89, 0xab],
+            },
+        };
+        let mac = |x: &str| Transmitter::Bluetooth {
+            mac: x.parse().unwrap(),
+        };
+        assert_eq!(
+            txs,
+            [
+                ibeacon,
+                ibeacon,
+                eddystone,
+                mac("00:11:22:33:44:03"),
+                mac("00:11:22:33:44:04"),
+                mac("00:11:22:33:44:05"),
+            ]
+        );
+
+        let Transmitter::Beacon { id } = ibeacon else {
+            unreachable!()
+        };
+        let (protocol, id) = id.columns();
+        assert_eq!(protocol, 1);
+        assert_eq!(id.len(), 20);
+        assert_eq!(id[16..], [0, 1, 0, 2]);
+    }
+
+    #[test]
+    fn placeholder_macs() {
+        for mac in [
+            "00:00:00:00:00:00",
+            "ff:ff:ff:ff:ff:ff",
+            "01:00:5e:00:00:01",
+            "33:33:00:00:00:01",
+        ] {
+            assert!(!valid_mac(&mac.parse().unwrap()), "{mac}");
+        }
+        assert!(valid_mac(&"00:11:22:33:44:55".parse().unwrap()));
+
+        let raw = json!({
+            "timestamp": 0,
+            "position": { "latitude": 10.0, "longitude": 20.0 },
+            "wifiAccessPoints": [
+                { "macAddress": "00:00:00:00:00:00", "ssid": "network" },
+                { "macAddress": "ff:ff:ff:ff:ff:ff", "ssid": "network" },
+                { "macAddress": "01:00:5e:00:00:01", "ssid": "network" },
+                { "macAddress": "00:11:22:33:44:55", "ssid": "network" },
+            ],
+            "bluetoothBeacons": [
+                { "macAddress": "00:00:00:00:00:00" },
+                { "macAddress": "ff:ff:ff:ff:ff:ff" },
+                { "macAddress": "33:33:00:00:00:01" },
+                { "macAddress": "00:11:22:33:44:66" },
+            ],
+        });
+        let txs = extract(&serde_json::to_vec(&raw).unwrap())
+            .unwrap()
+            .transmitters;
+        assert_eq!(
+            txs,
+            [
+                Transmitter::Wifi {
+                    mac: "00:11:22:33:44:55".parse().unwrap()
+                },
+                Transmitter::Bluetooth {
+                    mac: "00:11:22:33:44:66".parse().unwrap()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicate_wifi() {
+        let raw = json!({
+            "timestamp": 0,
+            "position": { "latitude": 10.0, "longitude": 20.0 },
+            "wifiAccessPoints": [
+                { "macAddress": "00:11:22:33:44:01", "ssid": "first", "signalStrength": -70 },
+                { "macAddress": "00:11:22:33:44:01", "ssid": "second", "signalStrength": -60 },
+                { "macAddress": "00:11:22:33:44:01" },
+                { "macAddress": "00:11:22:33:44:02", "ssid": "network" },
+                { "macAddress": "00:11:22:33:44:02", "ssid": "network_nomap" },
+            ],
+        });
+        let txs = extract(&serde_json::to_vec(&raw).unwrap())
+            .unwrap()
+            .transmitters;
+        assert_eq!(
+            txs,
+            [Transmitter::Wifi {
+                mac: "00:11:22:33:44:01".parse().unwrap()
+            }]
+        );
+    }
+
+    #[test]
+    fn ssidless_wifi() {
+        let wifi = |ssid: serde_json::Value| {
+            let raw = json!({
+                "timestamp": 0,
+                "position": { "latitude": 10.0, "longitude": 20.0 },
+                "wifiAccessPoints": [{ "macAddress": "00:11:22:33:44:01", "ssid": ssid }],
+            });
+            extract(&serde_json::to_vec(&raw).unwrap()).unwrap()
+        };
+
+        for ssid in [json!(null), json!(""), json!("\0\0")] {
+            let extracted = wifi(ssid);
+            assert!(extracted.transmitters.is_empty());
+            assert_eq!(
+                extracted.rejections.into_iter().collect::<Vec<_>>(),
+                [(Rejection::HiddenNetwork, 1)]
+            );
+        }
+
+        // whatever the ssid, it's the same access point
+        assert_eq!(wifi(json!("a")).transmitters, wifi(json!("b")).transmitters);
+    }
+
+    #[test]
+    fn wifi_capabilities() {
+        let raw = json!({
+            "timestamp": 0,
+            "position": { "latitude": 10.0, "longitude": 20.0 },
+            "wifiAccessPoints": [
+                { "macAddress": "00:11:22:33:44:01", "ssid": "a", "capabilities": "[WPA2-PSK-CCMP][ESS]" },
+                { "macAddress": "00:11:22:33:44:02", "ssid": "b", "encryption": "wep" },
+                { "macAddress": "00:11:22:33:44:03", "ssid": "c", "capabilities": " \0" },
+                { "macAddress": "00:11:22:33:44:04", "ssid": "d" },
+                { "macAddress": "00:11:22:33:44:05", "ssid": "e_nomap", "capabilities": "[ESS]" },
+            ],
+        });
+        let extracted = extract(&serde_json::to_vec(&raw).unwrap()).unwrap();
+        assert_eq!(extracted.transmitters.len(), 4);
+        assert_eq!(
+            extracted.capabilities,
+            BTreeMap::from([
+                (
+                    "00:11:22:33:44:01".parse().unwrap(),
+                    "[WPA2-PSK-CCMP][ESS]".to_owned()
+                ),
+                ("00:11:22:33:44:02".parse().unwrap(), "wep".to_owned()),
+            ])
+        );
+
+        // reports without the field are still accepted
+        let raw = json!({
+            "timestamp": 0,
+            "position": { "latitude": 10.0, "longitude": 20.0 },
+            "wifiAccessPoints": [{ "macAddress": "00:11:22:33:44:01", "ssid": "a" }],
+        });
+        let extracted = extract(&serde_json::to_vec(&raw).unwrap()).unwrap();
+        assert_eq!(extracted.transmitters.len(), 1);
+        assert!(extracted.capabilities.is_empty());
+    }
+
+    #[test]
+    fn track() {
+        let raw = json!({
+            "timestamp": 10_000,
+            "position": { "latitude": 10.0, "longitude": 20.0 },
+            "positions": [
+                { "latitude": 10.001, "longitude": 20.0, "age": 2000 },
+                { "latitude": 10.002, "longitude": 20.0, "age": 4000 },
+            ],
+            "cellTowers": [{
+                "radioType": "gsm",
+                "mobileCountryCode": 505,
+                "mobileNetworkCode": 1,
+                "locationAreaCode": 1,
+                "cellId": 1,
+                "age": 3900,
+            }],
+            "wifiAccessPoints": [
+                { "macAddress": "00:11:22:33:44:01", "ssid": "a", "age": 2200 },
+                { "macAddress": "00:11:22:33:44:02", "ssid": "b" },
+            ],
+        });
+        let extracted = extract(&serde_json::to_vec(&raw).unwrap()).unwrap();
+        let [cell, near, current] = extracted.transmitters[..] else {
+            panic!("{:?}", extracted.transmitters);
+        };
+        let at = |latitude| Position {
+            latitude,
+            longitude: 20.0,
+            accuracy: None,
+        };
+        assert_eq!(extracted.position_of(&cell), at(10.002));
+        assert_eq!(extracted.position_of(&near), at(10.001));
+        // without an age, seen at the report's own position
+        assert_eq!(extracted.position_of(&current), at(10.0));
+
+        // reports without a track place everything at their position
+        let mut raw = raw;
+        raw.as_object_mut().unwrap().remove("positions");
+        let extracted = extract(&serde_json::to_vec(&raw).unwrap()).unwrap();
+        for x in &extracted.transmitters {
+            assert_eq!(extracted.position_of(x), at(10.0));
+        }
+    }
+
+    #[test]
+    fn rejections() {
+        let rejections = |raw: serde_json::Value| {
+            let mut report = json!({
+                "timestamp": 0,
+                "position": { "latitude": 10.0, "longitude": 20.0 },
+            });
+            report
+                .as_object_mut()
+                .unwrap()
+                .extend(raw.as_object().unwrap().clone());
+            let extracted = extract(&serde_json::to_vec(&report).unwrap()).unwrap();
+            extracted.rejections.into_iter().collect::<Vec<_>>()
+        };
+        let cell = |area: u64, cell_id: u64| {
+            json!({
+                "radioType": "lte",
+                "mobileCountryCode": 505,
+                "mobileNetworkCode": 1,
+                "locationAreaCode": area,
+                "cellId": cell_id,
+            })
+        };
+
+        assert_eq!(
+            rejections(json!({ "cellTowers": [cell(1, 1), cell(0, 1), cell(1, 0)] })),
+            [(Rejection::IncompleteCell, 2)]
+        );
+        assert_eq!(
+            rejections(json!({ "cellTowers": [cell(1 << 31, 1)] })),
+            [(Rejection::CellOutOfRange, 1)]
+        );
+        assert_eq!(
+            rejections(json!({
+                "wifiAccessPoints": [
+                    { "macAddress": "00:00:00:00:00:00", "ssid": "a" },
+                    { "macAddress": "00:11:22:33:44:01" },
+                    { "macAddress": "00:11:22:33:44:02", "ssid": "b_nomap" },
+                    { "macAddress": "00:11:22:33:44:03", "ssid": "c" },
+                ],
+            })),
+            [
+                (Rejection::InvalidMac, 1),
+                (Rejection::HiddenNetwork, 1),
+                (Rejection::OptedOut, 1),
+            ]
+        );
+        assert_eq!(
+            rejections(json!({
+                "bluetoothBeacons": [
+                    { "macAddress": "ff:ff:ff:ff:ff:ff" },
+                    { "macAddress": "00:11:22:33:44:01", "name": "x_optout" },
+                    { "macAddress": "00:11:22:33:44:02" },
+                ],
+            })),
+            [(Rejection::InvalidMac, 1), (Rejection::OptedOut, 1)]
+        );
+        assert_eq!(rejections(json!({})), []);
+    }
 }
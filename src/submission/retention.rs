@@ -0,0 +1,132 @@
+use std::{
+    fs::OpenOptions,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use sqlx::{query, PgPool};
+
+use crate::config::RetentionConfig;
+
+// once processed reports are old enough they're moved out of the database into
+// newline delimited files, one per run, which can be loaded back with the ingest
+// command. the file is synced before the reports are deleted, so a failure
+// partway through at worst leaves reports in both places - and ingesting them
+// again skips the ones that are still there.
+//
+// archived reports have still contributed to the transmitters they were in, but
+// purge rebuilds transmitters from the reports left in the database and the
+// archives don't say who submitted each report. each run is recorded in the
+// archive table so purge can refuse to run rather than silently dropping what
+// the archived reports contributed.
+
+pub async fn run(pool: PgPool, config: &RetentionConfig) -> Result<()> {
+    let now = Utc::now();
+    let cutoff = now - Duration::days(config.days);
+
+    let mut tx = pool.begin().await?;
+    let reports = query!(
+        "select id, raw from report where processed_at is not null and submitted_at < $1 order by id for update",
+        cutoff
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    if reports.is_empty() {
+        eprintln!("no processed reports from before {cutoff}");
+        return Ok(());
+    }
+
+    let path = archive_path(&config.path, now);
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut writer = BufWriter::new(file);
+    write(&mut writer, reports.iter().map(|x| x.raw.as_slice()))?;
+    writer.into_inner()?.sync_all()?;
+
+    let ids: Vec<_> = reports.iter().map(|x| x.id).collect();
+    query!("delete from report where id = any($1)", &ids)
+        .execute(&mut *tx)
+        .await?;
+    query!(
+        "insert into archive (archived_at, path, reports) values ($1, $2, $3)",
+        now,
+        &*path.to_string_lossy(),
+        ids.len() as i32
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    eprintln!(
+        "archived {} reports from before {cutoff} to {} - add them to stats.archived_reports to keep counting them",
+        ids.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+fn archive_path(dir: &Path, now: DateTime<Utc>) -> PathBuf {
+    dir.join(format!("reports-{}.jsonl", now.format("%Y%m%dT%H%M%SZ")))
+}
+
+/// Write each report on its own line, as read by the ingest command.
+fn write<'a>(writer: &mut impl Write, reports: impl IntoIterator<Item = &'a [u8]>) -> Result<()> {
+    for raw in reports {
+        writer.write_all(raw)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, Cursor};
+
+    use super::*;
+    use crate::{
+        config::Config,
+        submission::{geosubmit, ingest},
+    };
+
+    #[test]
+    fn archive_round_trip() {
+        let reports = [
+            r#"{"timestamp":1700000000000,"position":{"latitude":10.0,"longitude":20.0},"wifiAccessPoints":[{"macAddress":"00:11:22:33:44:01","ssid":"a"}]}"#,
+            r#"{"timestamp":1700000001000,"position":{"latitude":10.1,"longitude":20.1}}"#,
+        ];
+        let mut out = Vec::new();
+        write(&mut out, reports.iter().map(|x| x.as_bytes())).unwrap();
+
+        // archives can be loaded back as they were
+        let mut lines = Cursor::new(&out).lines().enumerate();
+        let batch = ingest::read_batch(&mut lines, 10).unwrap();
+        assert_eq!(batch.len(), 2);
+        for (report, original) in batch.iter().zip(reports) {
+            let a: serde_json::Value = serde_json::to_value(report).unwrap();
+            let b: serde_json::Value = serde_json::from_str(original).unwrap();
+            assert_eq!(a, b);
+        }
+        let config: Config = toml::from_str("database_url = ''\nhttp_port = 0").unwrap();
+        assert_eq!(
+            geosubmit::prepare(None, None, None, &batch, true, &config)
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn one_file_per_run() {
+        let now = DateTime::from_timestamp(1_735_000_000, 0).unwrap();
+        assert_eq!(
+            archive_path(Path::new("/var/lib/beacondb"), now),
+            Path::new("/var/lib/beacondb/reports-20241224T002640Z.jsonl")
+        );
+    }
+}
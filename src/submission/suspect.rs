@@ -0,0 +1,434 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Duration, Utc};
+use futures::TryStreamExt;
+use geo::{Distance, Haversine, Point};
+use mac_address::MacAddress;
+use sqlx::{query, PgPool};
+
+use crate::model::Transmitter;
+
+// looks through recent reports for patterns that are unlikely to come from a
+// real device, so that an operator can review them and purge the contributor
+// if needed. nothing is modified. the heuristics are:
+//
+// - crowded position: a single coordinate (rounded to ~10m) where more than
+//   MAX_WIFI_AT_POSITION distinct access points were seen. a real survey picks
+//   up a few dozen networks from one spot, while dumps of leaked access point
+//   lists tend to place hundreds at the same made up coordinate.
+// - burst: a contributor making more than MAX_UPLOADS_PER_MINUTE uploads in
+//   any minute. a contributor is a token where one was used, otherwise a user
+//   agent - popular apps share a user agent between many devices, so bursts
+//   from those need to be judged with that in mind.
+// - teleport: consecutive reports in a single upload that are further apart
+//   than TELEPORT_MIN_DISTANCE and imply moving faster than MAX_SPEED. a
+//   single upload comes from one device, so it can't be in two places at once.
+//
+// uploads are told apart by the id each report is tagged with when submitted,
+// as the reports of one upload can be stored at different times. reports
+// without one, from before it was recorded or loaded by the ingest command,
+// aren't counted towards bursts or teleports.
+
+const MAX_WIFI_AT_POSITION: usize = 200;
+const MAX_UPLOADS_PER_MINUTE: usize = 30;
+/// Metres per second, a bit faster than a commercial flight
+const MAX_SPEED: f64 = 300.0;
+/// Metres, so that jitter between reports a few seconds apart isn't counted
+const TELEPORT_MIN_DISTANCE: f64 = 1000.0;
+
+pub async fn run(pool: PgPool, hours: i64) -> Result<()> {
+    let since = Utc::now() - Duration::hours(hours);
+    let mut samples = Vec::new();
+    let mut q = query!(
+        "select id, submitted_at, timestamp, latitude, longitude, user_agent, token_id, upload_id, raw
+         from report where submitted_at >= $1",
+        since
+    )
+    .fetch(&pool);
+    while let Some(report) = q.try_next().await? {
+        let wifi = match super::report::extract(&report.raw) {
+            Ok(x) => x
+                .transmitters
+                .into_iter()
+                .filter_map(|x| match x {
+                    Transmitter::Wifi { mac } => Some(mac),
+                    _ => None,
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        samples.push(Sample {
+            id: report.id,
+            submitted_at: report.submitted_at,
+            timestamp: report.timestamp,
+            latitude: report.latitude,
+            longitude: report.longitude,
+            user_agent: report.user_agent,
+            token_id: report.token_id,
+            upload_id: report.upload_id,
+            wifi,
+        });
+    }
+    drop(q);
+
+    let suspects = analyze(&samples);
+    eprintln!(
+        "checked {} reports since {since} - {} suspects",
+        samples.len(),
+        suspects.len()
+    );
+    for suspect in suspects {
+        println!("{suspect}");
+    }
+
+    Ok(())
+}
+
+struct Sample {
+    id: i32,
+    submitted_at: DateTime<Utc>,
+    timestamp: DateTime<Utc>,
+    latitude: f64,
+    longitude: f64,
+    user_agent: Option<String>,
+    token_id: Option<i32>,
+    upload_id: Option<String>,
+    wifi: Vec<MacAddress>,
+}
+
+impl Sample {
+    fn contributor(&self) -> Contributor {
+        match self.token_id {
+            Some(id) => Contributor::Token(id),
+            None => Contributor::UserAgent(self.user_agent.clone().unwrap_or_default()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum Contributor {
+    Token(i32),
+    UserAgent(String),
+}
+
+impl fmt::Display for Contributor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Contributor::Token(id) => write!(f, "token #{id}"),
+            Contributor::UserAgent(ua) => write!(f, "user agent '{ua}'"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Suspect {
+    Crowded {
+        latitude: f64,
+        longitude: f64,
+        wifi: usize,
+        reports: Vec<i32>,
+    },
+    Burst {
+        contributor: Contributor,
+        start: DateTime<Utc>,
+        uploads: usize,
+    },
+    Teleport {
+        contributor: Contributor,
+        upload_id: String,
+        jumps: usize,
+        /// Metres per second
+        max_speed: f64,
+    },
+}
+
+impl fmt::Display for Suspect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Suspect::Crowded {
+                latitude,
+                longitude,
+                wifi,
+                reports,
+            } => write!(
+                f,
+                "crowded: {wifi} access points at {latitude:.4},{longitude:.4} in reports {reports:?}"
+            ),
+            Suspect::Burst {
+                contributor,
+                start,
+                uploads,
+            } => write!(f, "burst: {uploads} uploads from {contributor} in the minute after {start}"),
+            Suspect::Teleport {
+                contributor,
+                upload_id,
+                jumps,
+                max_speed,
+            } => write!(
+                f,
+                "teleport: {jumps} impossible jumps (up to {:.0} km/h) in upload {upload_id} from {contributor}",
+                max_speed * 3.6
+            ),
+        }
+    }
+}
+
+fn analyze(samples: &[Sample]) -> Vec<Suspect> {
+    let mut suspects = crowded(samples);
+    suspects.extend(bursts(samples));
+    suspects.extend(teleports(samples));
+    suspects
+}
+
+fn crowded(samples: &[Sample]) -> Vec<Suspect> {
+    let mut positions: BTreeMap<(i64, i64), (BTreeSet<MacAddress>, Vec<i32>)> = BTreeMap::new();
+    for sample in samples.iter().filter(|x| !x.wifi.is_empty()) {
+        let key = (
+            (sample.latitude * 1e4).round() as i64,
+            (sample.longitude * 1e4).round() as i64,
+        );
+        let (wifi, reports) = positions.entry(key).or_default();
+        wifi.extend(&sample.wifi);
+        reports.push(sample.id);
+    }
+
+    positions
+        .into_iter()
+        .filter(|(_, (wifi, _))| wifi.len() > MAX_WIFI_AT_POSITION)
+        .map(|((lat, lon), (wifi, reports))| Suspect::Crowded {
+            latitude: lat as f64 / 1e4,
+            longitude: lon as f64 / 1e4,
+            wifi: wifi.len(),
+            reports,
+        })
+        .collect()
+}
+
+fn bursts(samples: &[Sample]) -> Vec<Suspect> {
+    // when each upload's first report was stored
+    let mut uploads: BTreeMap<Contributor, BTreeMap<&str, DateTime<Utc>>> = BTreeMap::new();
+    for sample in samples {
+        let Some(upload_id) = &sample.upload_id else {
+            continue;
+        };
+        uploads
+            .entry(sample.contributor())
+            .or_default()
+            .entry(upload_id)
+            .and_modify(|x| *x = (*x).min(sample.submitted_at))
+            .or_insert(sample.submitted_at);
+    }
+
+    let mut suspects = Vec::new();
+    for (contributor, uploads) in uploads {
+        let mut times: Vec<_> = uploads.into_values().collect();
+        times.sort();
+        // the busiest minute starting at any upload
+        let busiest = times
+            .iter()
+            .enumerate()
+            .map(|(i, start)| {
+                let count = times[i..]
+                    .iter()
+                    .take_while(|x| **x - *start < Duration::minutes(1))
+                    .count();
+                (count, *start)
+            })
+            .max_by_key(|(count, start)| (*count, std::cmp::Reverse(*start)));
+        if let Some((count, start)) = busiest.filter(|(count, _)| *count > MAX_UPLOADS_PER_MINUTE) {
+            suspects.push(Suspect::Burst {
+                contributor,
+                start,
+                uploads: count,
+            });
+        }
+    }
+
+    suspects
+}
+
+fn teleports(samples: &[Sample]) -> Vec<Suspect> {
+    let mut uploads: BTreeMap<(Contributor, &str), Vec<&Sample>> = BTreeMap::new();
+    for sample in samples {
+        let Some(upload_id) = &sample.upload_id else {
+            continue;
+        };
+        uploads
+            .entry((sample.contributor(), upload_id))
+            .or_default()
+            .push(sample);
+    }
+
+    let mut suspects = Vec::new();
+    for ((contributor, upload_id), mut reports) in uploads {
+        reports.sort_by_key(|x| x.timestamp);
+
+        let mut jumps = 0;
+        let mut max_speed: f64 = 0.0;
+        for pair in reports.windows(2) {
+            let (a, b) = (pair[0], pair[1]);
+            let distance = Haversine::distance(
+                Point::new(a.longitude, a.latitude),
+                Point::new(b.longitude, b.latitude),
+            );
+            // reports in the same second are treated as a second apart
+            let secs = (b.timestamp - a.timestamp).num_seconds().max(1) as f64;
+            let speed = distance / secs;
+            if distance > TELEPORT_MIN_DISTANCE && speed > MAX_SPEED {
+                jumps += 1;
+                max_speed = max_speed.max(speed);
+            }
+        }
+
+        if jumps > 0 {
+            suspects.push(Suspect::Teleport {
+                contributor,
+                upload_id: upload_id.to_owned(),
+                jumps,
+                max_speed,
+            });
+        }
+    }
+
+    suspects
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A report in an upload of its own for each second it was submitted in.
+    fn sample(id: i32, submitted_at: i64, timestamp: i64, position: (f64, f64)) -> Sample {
+        Sample {
+            id,
+            submitted_at: DateTime::from_timestamp(submitted_at, 0).unwrap(),
+            timestamp: DateTime::from_timestamp(timestamp, 0).unwrap(),
+            latitude: position.0,
+            longitude: position.1,
+            user_agent: Some("test".to_owned()),
+            token_id: None,
+            upload_id: Some(submitted_at.to_string()),
+            wifi: Vec::new(),
+        }
+    }
+
+    fn macs(range: std::ops::Range<u32>) -> Vec<MacAddress> {
+        range
+            .map(|i| {
+                let [_, a, b, c] = i.to_be_bytes();
+                MacAddress::new([0x02, 0, 0, a, b, c])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn legitimate_survey() {
+        // a walk with a report every ten seconds, uploaded once
+        let samples: Vec<_> = (0..100)
+            .map(|i| {
+                let mut x = sample(i, 0, i as i64 * 10, (10.0 + i as f64 * 1e-4, 20.0));
+                x.wifi = macs(i as u32 * 10..i as u32 * 10 + 30);
+                x
+            })
+            .collect();
+        assert_eq!(analyze(&samples), []);
+    }
+
+    #[test]
+    fn crowded_position() {
+        let mut a = sample(1, 0, 0, (10.0, 20.0));
+        a.wifi = macs(0..150);
+        // about a metre away, so rounded to the same position
+        let mut b = sample(2, 100, 100, (10.00001, 20.0));
+        b.wifi = macs(100..250);
+        let mut elsewhere = sample(3, 100, 100, (11.0, 20.0));
+        elsewhere.wifi = macs(0..150);
+
+        assert_eq!(
+            crowded(&[a, b, elsewhere]),
+            [Suspect::Crowded {
+                latitude: 10.0,
+                longitude: 20.0,
+                wifi: 250,
+                reports: vec![1, 2],
+            }]
+        );
+    }
+
+    #[test]
+    fn submission_burst() {
+        // two uploads a second from one user agent
+        let mut samples: Vec<_> = (0..80)
+            .map(|i| sample(i, i as i64 / 2, 0, (10.0, 20.0)))
+            .collect();
+        // the same rate spread over several tokens is fine
+        samples.extend((0..60).map(|i| {
+            let mut x = sample(100 + i, i as i64 / 2, 0, (10.0, 20.0));
+            x.token_id = Some(i % 3);
+            x
+        }));
+
+        assert_eq!(
+            bursts(&samples),
+            [Suspect::Burst {
+                contributor: Contributor::UserAgent("test".to_owned()),
+                start: DateTime::from_timestamp(0, 0).unwrap(),
+                uploads: 40,
+            }]
+        );
+    }
+
+    #[test]
+    fn teleport() {
+        let samples = [
+            sample(1, 0, 0, (10.0, 20.0)),
+            // ~111km a minute later
+            sample(2, 0, 60, (11.0, 20.0)),
+            // and back
+            sample(3, 0, 120, (10.0, 20.0)),
+            // a plausible drive afterwards
+            sample(4, 0, 3720, (10.5, 20.0)),
+            // other uploads can be anywhere
+            sample(5, 1, 60, (-30.0, 150.0)),
+        ];
+
+        let suspects = teleports(&samples);
+        assert_eq!(suspects.len(), 1);
+        let Suspect::Teleport {
+            jumps, max_speed, ..
+        } = &suspects[0]
+        else {
+            panic!("{suspects:?}");
+        };
+        assert_eq!(*jumps, 2);
+        assert!((1800.0..1900.0).contains(max_speed));
+    }
+
+    #[test]
+    fn stored_in_batches() {
+        // one upload, written a batch at a time over a few seconds
+        let mut samples: Vec<_> = (0..40)
+            .map(|i| {
+                let mut x = sample(i, i as i64 / 10, i as i64 * 60, (10.0 + i as f64, 20.0));
+                x.upload_id = Some("a".to_owned());
+                x
+            })
+            .collect();
+        assert_eq!(bursts(&samples), []);
+        let suspects = teleports(&samples);
+        assert!(
+            matches!(&suspects[..], [Suspect::Teleport { upload_id, jumps: 39, .. }] if upload_id == "a"),
+            "{suspects:?}"
+        );
+
+        // ingested reports don't say which upload they came in
+        for x in &mut samples {
+            x.upload_id = None;
+        }
+        assert_eq!(analyze(&samples), []);
+    }
+}
@@ -0,0 +1,130 @@
+use std::future::{ready, Ready};
+
+use actix_web::{
+    dev::Payload, error::ErrorUnauthorized, http::header::AUTHORIZATION, web, FromRequest,
+    HttpRequest,
+};
+
+use subtle::ConstantTimeEq;
+
+use crate::config::{Config, UploadToken};
+
+/// The uploader that made a submission, identified by the id of the token
+/// they presented. This is always `None` when upload tokens are disabled.
+pub struct Uploader(pub Option<i32>);
+
+impl FromRequest for Uploader {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let tokens = req
+            .app_data::<web::Data<Config>>()
+            .and_then(|x| x.upload_tokens.as_deref());
+        let Some(tokens) = tokens else {
+            return ready(Ok(Uploader(None)));
+        };
+
+        ready(match authenticate(tokens, bearer(req)) {
+            Some(id) => Ok(Uploader(Some(id))),
+            None => Err(ErrorUnauthorized("a valid upload token is required")),
+        })
+    }
+}
+
+/// The token presented in the Authorization header, if any.
+pub(super) fn bearer(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(AUTHORIZATION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.strip_prefix("Bearer "))
+}
+
+/// The id of the token presented. Every token is compared in constant time, so
+/// how long this takes doesn't give away how much of a token was guessed.
+pub(super) fn authenticate(tokens: &[UploadToken], presented: Option<&str>) -> Option<i32> {
+    let presented = presented?.trim().as_bytes();
+    let mut found = None;
+    for x in tokens.iter().filter(|x| !x.token.is_empty()) {
+        if bool::from(x.token.as_bytes().ct_eq(presented)) {
+            found = found.or(Some(x.id));
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::StatusCode,
+        test::{call_and_read_body_json, call_service, init_service, TestRequest},
+        App, HttpResponse,
+    };
+
+    use super::*;
+
+    fn tokens() -> Vec<UploadToken> {
+        vec![
+            UploadToken {
+                id: 1,
+                token: "first".to_owned(),
+            },
+            UploadToken {
+                id: 2,
+                token: "second".to_owned(),
+            },
+        ]
+    }
+
+    #[test]
+    fn authenticate_tokens() {
+        let tokens = tokens();
+        assert_eq!(authenticate(&tokens, Some("first")), Some(1));
+        assert_eq!(authenticate(&tokens, Some("second")), Some(2));
+        assert_eq!(authenticate(&tokens, Some("third")), None);
+        assert_eq!(authenticate(&tokens, Some("firs")), None);
+        assert_eq!(authenticate(&tokens, Some("firstt")), None);
+        assert_eq!(authenticate(&tokens, Some("")), None);
+        assert_eq!(authenticate(&tokens, None), None);
+    }
+
+    #[actix_web::test]
+    async fn extractor() {
+        async fn handler(uploader: Uploader) -> HttpResponse {
+            HttpResponse::Ok().json(uploader.0)
+        }
+
+        let config = |upload_tokens| {
+            let mut config: Config = toml::from_str("database_url = ''\nhttp_port = 0").unwrap();
+            config.upload_tokens = upload_tokens;
+            web::Data::new(config)
+        };
+
+        let open = init_service(
+            App::new()
+                .app_data(config(None))
+                .route("/", web::post().to(handler)),
+        )
+        .await;
+        let req = TestRequest::post().uri("/").to_request();
+        let res: Option<i32> = call_and_read_body_json(&open, req).await;
+        assert_eq!(res, None);
+
+        let closed = init_service(
+            App::new()
+                .app_data(config(Some(tokens())))
+                .route("/", web::post().to(handler)),
+        )
+        .await;
+        let req = TestRequest::post().uri("/").to_request();
+        let res = call_service(&closed, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let req = TestRequest::post()
+            .uri("/")
+            .insert_header((AUTHORIZATION, "Bearer second"))
+            .to_request();
+        let res: Option<i32> = call_and_read_body_json(&closed, req).await;
+        assert_eq!(res, Some(2));
+    }
+}